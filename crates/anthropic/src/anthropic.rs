@@ -124,6 +124,26 @@ impl Model {
         }
     }
 
+    /// The list price of a million prompt tokens in US dollars, if known.
+    pub fn input_price_per_million(&self) -> Option<f64> {
+        match self {
+            Self::Claude3_5Sonnet | Self::Claude3Sonnet => Some(3.0),
+            Self::Claude3Opus => Some(15.0),
+            Self::Claude3Haiku => Some(0.25),
+            Self::Custom { .. } => None,
+        }
+    }
+
+    /// The list price of a million completion tokens in US dollars, if known.
+    pub fn output_price_per_million(&self) -> Option<f64> {
+        match self {
+            Self::Claude3_5Sonnet | Self::Claude3Sonnet => Some(15.0),
+            Self::Claude3Opus => Some(75.0),
+            Self::Claude3Haiku => Some(1.25),
+            Self::Custom { .. } => None,
+        }
+    }
+
     pub fn default_temperature(&self) -> f32 {
         match self {
             Self::Claude3_5Sonnet
@@ -480,6 +500,15 @@ pub enum ToolChoice {
     Tool { name: String },
 }
 
+/// A system prompt, given either as plain text or as text blocks, which can be marked
+/// for caching individually.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SystemPrompt {
+    Text(String),
+    Content(Vec<RequestContent>),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub model: String,
@@ -490,7 +519,7 @@ pub struct Request {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub system: Option<String>,
+    pub system: Option<SystemPrompt>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Metadata>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
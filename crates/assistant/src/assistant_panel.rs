@@ -49,8 +49,8 @@ use language::{
     ToOffset,
 };
 use language_model::{
-    provider::cloud::PROVIDER_ID, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelRegistry, Role,
+    provider::{cloud::PROVIDER_ID, copilot_chat},
+    LanguageModelProvider, LanguageModelProviderId, LanguageModelRegistry, Role,
 };
 use language_model::{LanguageModelImage, LanguageModelToolUse};
 use multi_buffer::MultiBufferRow;
@@ -77,7 +77,7 @@ use ui::TintColor;
 use ui::{
     prelude::*,
     utils::{format_distance_from_now, DateTimeType},
-    Avatar, ButtonLike, ContextMenu, Disclosure, ElevationIndex, KeyBinding, ListItem,
+    Avatar, ButtonLike, ContextMenu, Disclosure, ElevationIndex, Indicator, KeyBinding, ListItem,
     ListItemSpacing, PopoverMenu, PopoverMenuHandle, Tooltip,
 };
 use util::{maybe, ResultExt};
@@ -1125,7 +1125,10 @@ impl AssistantPanel {
                             .active_provider()
                             .map_or(true, |p| p.id() != provider.id())
                         {
-                            if let Some(model) = provider.provided_models(cx).first().cloned() {
+                            if let Some(model) = provider
+                                .default_model(cx)
+                                .or_else(|| provider.provided_models(cx).first().cloned())
+                            {
                                 update_settings_file::<AssistantSettings>(
                                     this.fs.clone(),
                                     cx,
@@ -3569,7 +3572,11 @@ impl ContextEditor {
     fn paste(&mut self, action: &editor::actions::Paste, cx: &mut ViewContext<Self>) {
         cx.stop_propagation();
 
-        let images = if let Some(item) = cx.read_from_clipboard() {
+        // Pasted images are only attached when the active model can make use of them.
+        let supports_images = LanguageModelRegistry::read_global(cx)
+            .active_model()
+            .map_or(false, |model| model.supports_images());
+        let images = if let Some(item) = cx.read_from_clipboard().filter(|_| supports_images) {
             item.into_entries()
                 .filter_map(|entry| {
                     if let ClipboardEntry::Image(image) = entry {
@@ -5158,6 +5165,9 @@ impl Item for ContextHistory {
 pub struct ConfigurationView {
     focus_handle: FocusHandle,
     configuration_views: HashMap<LanguageModelProviderId, AnyView>,
+    auth_subscriptions: HashMap<LanguageModelProviderId, Subscription>,
+    /// The outcome of each provider's health check, once it has finished.
+    provider_health: HashMap<LanguageModelProviderId, Result<(), SharedString>>,
     _registry_subscription: Subscription,
 }
 
@@ -5184,6 +5194,8 @@ impl ConfigurationView {
         let mut this = Self {
             focus_handle,
             configuration_views: HashMap::default(),
+            auth_subscriptions: HashMap::default(),
+            provider_health: HashMap::default(),
             _registry_subscription: registry_subscription,
         };
         this.build_configuration_views(cx);
@@ -5199,6 +5211,8 @@ impl ConfigurationView {
 
     fn remove_configuration_view(&mut self, provider_id: &LanguageModelProviderId) {
         self.configuration_views.remove(provider_id);
+        self.auth_subscriptions.remove(provider_id);
+        self.provider_health.remove(provider_id);
     }
 
     fn add_configuration_view(
@@ -5207,8 +5221,48 @@ impl ConfigurationView {
         cx: &mut ViewContext<Self>,
     ) {
         let configuration_view = provider.configuration_view(cx);
+
+        // Once signing in to Copilot completes, open a context right away
+        // instead of making the user click through again.
+        if let Ok(copilot_view) = configuration_view
+            .clone()
+            .downcast::<copilot_chat::ConfigurationView>()
+        {
+            let provider = provider.clone();
+            let subscription = cx.subscribe(
+                &copilot_view,
+                move |_, _, event: &copilot_chat::AuthEvent, cx| match event {
+                    copilot_chat::AuthEvent::Authenticated => cx.emit(
+                        ConfigurationViewEvent::NewProviderContextEditor(provider.clone()),
+                    ),
+                },
+            );
+            self.auth_subscriptions.insert(provider.id(), subscription);
+        }
+
         self.configuration_views
             .insert(provider.id(), configuration_view);
+        self.check_provider_health(provider, cx);
+    }
+
+    fn check_provider_health(
+        &mut self,
+        provider: &Arc<dyn LanguageModelProvider>,
+        cx: &mut ViewContext<Self>,
+    ) {
+        let provider_id = provider.id();
+        self.provider_health.remove(&provider_id);
+        let check_health = provider.check_health(cx);
+        cx.spawn(|this, mut cx| async move {
+            let health = check_health
+                .await
+                .map_err(|error| SharedString::from(error.to_string()));
+            this.update(&mut cx, |this, cx| {
+                this.provider_health.insert(provider_id, health);
+                cx.notify();
+            })
+        })
+        .detach_and_log_err(cx);
     }
 
     fn render_provider_view(
@@ -5219,6 +5273,26 @@ impl ConfigurationView {
         let provider_id = provider.id().0.clone();
         let provider_name = provider.name().0.clone();
         let configuration_view = self.configuration_views.get(&provider.id()).cloned();
+        let is_authenticated = provider.is_authenticated(cx);
+        let external_dependency = provider
+            .requires_external_dependency()
+            .filter(|_| !is_authenticated)
+            .map(|dependency| {
+                Label::new(format!(
+                    "{provider_name} requires {dependency}, which is installed separately."
+                ))
+                .color(Color::Muted)
+            });
+        let health_indicator = self.provider_health.get(&provider.id()).map(|health| {
+            let (color, tooltip) = match health {
+                Ok(()) => (Color::Success, SharedString::from("Available")),
+                Err(error) => (Color::Error, error.clone()),
+            };
+            div()
+                .id(SharedString::from(format!("provider-health-{provider_id}")))
+                .child(Indicator::dot().color(color))
+                .tooltip(move |cx| Tooltip::text(tooltip.clone(), cx))
+        });
 
         let open_new_context = cx.listener({
             let provider = provider.clone();
@@ -5234,8 +5308,13 @@ impl ConfigurationView {
             .child(
                 h_flex()
                     .justify_between()
-                    .child(Headline::new(provider_name.clone()).size(HeadlineSize::Small))
-                    .when(provider.is_authenticated(cx), move |this| {
+                    .child(
+                        h_flex()
+                            .gap_2()
+                            .child(Headline::new(provider_name.clone()).size(HeadlineSize::Small))
+                            .children(health_indicator),
+                    )
+                    .when(is_authenticated, move |this| {
                         this.child(
                             h_flex().justify_end().child(
                                 Button::new(
@@ -5251,6 +5330,7 @@ impl ConfigurationView {
                         )
                     }),
             )
+            .children(external_dependency)
             .child(
                 div()
                     .p(Spacing::Large.rems(cx))
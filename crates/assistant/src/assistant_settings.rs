@@ -419,6 +419,7 @@ fn providers_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema:
             "google".into(),
             "ollama".into(),
             "openai".into(),
+            "openai_compatible".into(),
             "zed.dev".into(),
             "copilot_chat".into(),
         ]),
@@ -1984,7 +1984,7 @@ impl Context {
 
         let mut request = self.to_completion_request(cx);
 
-        if cx.has_flag::<ToolUseFeatureFlag>() {
+        if cx.has_flag::<ToolUseFeatureFlag>() && model.supports_tools() {
             let tool_registry = ToolRegistry::global(cx);
             request.tools = tool_registry
                 .tools()
@@ -2041,6 +2041,21 @@ impl Context {
                                     LanguageModelCompletionEvent::Stop(reason) => {
                                         stop_reason = reason;
                                     }
+                                    LanguageModelCompletionEvent::Usage {
+                                        prompt_tokens,
+                                        completion_tokens,
+                                    } => {
+                                        this.token_count = Some(prompt_tokens + completion_tokens);
+                                    }
+                                    // Only a completed tool use is run.
+                                    LanguageModelCompletionEvent::ToolUseDelta { .. }
+                                    | LanguageModelCompletionEvent::PartialUsage { .. }
+                                    | LanguageModelCompletionEvent::Connected { .. }
+                                    | LanguageModelCompletionEvent::FirstToken { .. }
+                                    | LanguageModelCompletionEvent::ResponseModel { .. }
+                                    | LanguageModelCompletionEvent::SystemFingerprint { .. }
+                                    | LanguageModelCompletionEvent::Fallback { .. }
+                                    | LanguageModelCompletionEvent::Candidate { .. } => {}
                                     LanguageModelCompletionEvent::Text(chunk) => {
                                         buffer.edit(
                                             [(
@@ -2155,8 +2170,15 @@ impl Context {
                             StopReason::ToolUse => {
                                 cx.emit(ContextEvent::UsePendingTools);
                             }
+                            StopReason::ContentFilter => {
+                                cx.emit(ContextEvent::ShowAssistError(
+                                    "The response was stopped by the content filter.".into(),
+                                ));
+                            }
                             StopReason::EndTurn => {}
                             StopReason::MaxTokens => {}
+                            StopReason::StopSequence => {}
+                            StopReason::Unknown => {}
                         }
                     }
                 })
@@ -2192,6 +2214,18 @@ impl Context {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            seed: None,
+            n: None,
+            max_tokens: None,
+            reasoning_effort: None,
+            session_id: None,
+            response_format: None,
+            locale: None,
+            metadata: Default::default(),
         };
         for message in self.messages(cx) {
             if message.status != MessageStatus::Done {
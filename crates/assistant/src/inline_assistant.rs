@@ -2726,7 +2726,10 @@ impl CodegenAlternative {
             if user_prompt.trim().to_lowercase() == "delete" {
                 async { Ok(stream::empty().boxed()) }.boxed_local()
             } else {
-                let request = self.build_request(user_prompt, assistant_panel_context, cx)?;
+                let mut request = self.build_request(user_prompt, assistant_panel_context, cx)?;
+                // Restarting the assist supersedes its previous completion, which providers
+                // can then stop streaming.
+                request.session_id = Some(format!("inline-assist-{}", cx.entity_id()));
 
                 let chunks = cx
                     .spawn(|_, cx| async move { model.stream_completion_text(request, &cx).await });
@@ -2790,6 +2793,18 @@ impl CodegenAlternative {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            seed: None,
+            n: None,
+            max_tokens: None,
+            reasoning_effort: None,
+            session_id: None,
+            response_format: None,
+            locale: None,
+            metadata: Default::default(),
         })
     }
 
@@ -265,7 +265,7 @@ impl<T: PopoverTrigger> RenderOnce for ModelSelector<T> {
 
         let all_models = LanguageModelRegistry::global(cx)
             .read(cx)
-            .providers()
+            .enabled_providers()
             .iter()
             .flat_map(|provider| {
                 let provider_id = provider.id();
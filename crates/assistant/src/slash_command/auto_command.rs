@@ -217,6 +217,18 @@ async fn commands_for_summaries(
         tools: Vec::new(),
         stop: Vec::new(),
         temperature: None,
+        top_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        logit_bias: None,
+        seed: None,
+        n: None,
+        max_tokens: None,
+        reasoning_effort: None,
+        session_id: None,
+        response_format: None,
+        locale: None,
+        metadata: Default::default(),
     };
 
     while let Some(current_summaries) = stack.pop() {
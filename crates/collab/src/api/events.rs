@@ -17,9 +17,9 @@ use serde::{Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use std::sync::{Arc, OnceLock};
 use telemetry_events::{
-    ActionEvent, AppEvent, AssistantEvent, CallEvent, CpuEvent, EditEvent, EditorEvent, Event,
-    EventRequestBody, EventWrapper, ExtensionEvent, InlineCompletionEvent, MemoryEvent, Panic,
-    ReplEvent, SettingEvent,
+    ActionEvent, AppEvent, AssistantEvent, CallEvent, CompletionEvent, CpuEvent, EditEvent,
+    EditorEvent, Event, EventRequestBody, EventWrapper, ExtensionEvent, InlineCompletionEvent,
+    MemoryEvent, Panic, ReplEvent, SettingEvent,
 };
 use uuid::Uuid;
 
@@ -524,6 +524,17 @@ pub async fn post_events(
                 first_event_at,
                 checksum_matched,
             )),
+            Event::Completion(event) => {
+                to_upload
+                    .completion_events
+                    .push(CompletionEventRow::from_event(
+                        event.clone(),
+                        wrapper,
+                        &request_body,
+                        first_event_at,
+                        checksum_matched,
+                    ))
+            }
         }
     }
 
@@ -549,6 +560,7 @@ struct ToUpload {
     edit_events: Vec<EditEventRow>,
     action_events: Vec<ActionEventRow>,
     repl_events: Vec<ReplEventRow>,
+    completion_events: Vec<CompletionEventRow>,
 }
 
 impl ToUpload {
@@ -629,6 +641,15 @@ impl ToUpload {
             .await
             .with_context(|| format!("failed to upload to table '{REPL_EVENTS_TABLE}'"))?;
 
+        const COMPLETION_EVENTS_TABLE: &str = "completion_events";
+        write_to_table(
+            COMPLETION_EVENTS_TABLE,
+            &self.completion_events,
+            clickhouse_client,
+        )
+        .await
+        .with_context(|| format!("failed to upload to table '{COMPLETION_EVENTS_TABLE}'"))?;
+
         Ok(())
     }
 }
@@ -1237,6 +1258,72 @@ impl ReplEventRow {
     }
 }
 
+#[derive(Serialize, Debug, clickhouse::Row)]
+pub struct CompletionEventRow {
+    // AppInfoBase
+    app_version: String,
+    major: Option<i32>,
+    minor: Option<i32>,
+    patch: Option<i32>,
+    checksum_matched: bool,
+    release_channel: String,
+    os_name: String,
+    os_version: String,
+
+    // ClientEventBase
+    installation_id: Option<String>,
+    session_id: Option<String>,
+    is_staff: Option<bool>,
+    time: i64,
+
+    // CompletionEventRow
+    model: String,
+    model_provider: String,
+    time_to_first_token_in_ms: Option<i64>,
+    duration_in_ms: i64,
+    completion_tokens: Option<i64>,
+    failure_reason: Option<String>,
+    metadata: Vec<(String, String)>,
+}
+
+impl CompletionEventRow {
+    fn from_event(
+        event: CompletionEvent,
+        wrapper: &EventWrapper,
+        body: &EventRequestBody,
+        first_event_at: chrono::DateTime<chrono::Utc>,
+        checksum_matched: bool,
+    ) -> Self {
+        let semver = body.semver();
+        let time =
+            first_event_at + chrono::Duration::milliseconds(wrapper.milliseconds_since_first_event);
+
+        Self {
+            app_version: body.app_version.clone(),
+            major: semver.map(|v| v.major() as i32),
+            minor: semver.map(|v| v.minor() as i32),
+            patch: semver.map(|v| v.patch() as i32),
+            checksum_matched,
+            release_channel: body.release_channel.clone().unwrap_or_default(),
+            os_name: body.os_name.clone(),
+            os_version: body.os_version.clone().unwrap_or_default(),
+            installation_id: body.installation_id.clone(),
+            session_id: body.session_id.clone(),
+            is_staff: body.is_staff,
+            time: time.timestamp_millis(),
+            model: event.model,
+            model_provider: event.model_provider,
+            time_to_first_token_in_ms: event
+                .time_to_first_token
+                .map(|latency| latency.as_millis() as i64),
+            duration_in_ms: event.duration.as_millis() as i64,
+            completion_tokens: event.completion_tokens.map(|tokens| tokens as i64),
+            failure_reason: event.failure_reason.map(|reason| reason.to_string()),
+            metadata: event.metadata.into_iter().collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Debug, clickhouse::Row)]
 pub struct EditEventRow {
     // AppInfoBase
@@ -605,13 +605,28 @@ impl Copilot {
     }
 
     pub fn reinstall(&mut self, cx: &mut ModelContext<Self>) -> Task<()> {
+        self.restart_language_server(true, cx)
+    }
+
+    /// Starts the language server again without reinstalling it, e.g. after it failed to start.
+    pub fn restart(&mut self, cx: &mut ModelContext<Self>) -> Task<()> {
+        self.restart_language_server(false, cx)
+    }
+
+    fn restart_language_server(
+        &mut self,
+        clear_installation: bool,
+        cx: &mut ModelContext<Self>,
+    ) -> Task<()> {
         let start_task = cx
             .spawn({
                 let http = self.http.clone();
                 let node_runtime = self.node_runtime.clone();
                 let server_id = self.server_id;
                 move |this, cx| async move {
-                    clear_copilot_dir().await;
+                    if clear_installation {
+                        clear_copilot_dir().await;
+                    }
                     Self::start_language_server(server_id, http, node_runtime, this, cx).await
                 }
             })
@@ -1,28 +1,48 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::sync::OnceLock;
 use std::{sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
+use collections::{FxHasher, HashMap};
 use fs::Fs;
-use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, StreamExt};
-use gpui::{AppContext, AsyncAppContext, Global};
-use http_client::{AsyncBody, HttpClient, HttpRequestExt, Method, Request as HttpRequest};
+use futures::future::{self, Either, Shared, WeakShared};
+use futures::{
+    io::BufReader,
+    stream::{self, BoxStream},
+    AsyncBufReadExt, AsyncReadExt, Future, FutureExt, StreamExt,
+};
+use gpui::{
+    AppContext, AsyncAppContext, BackgroundExecutor, EventEmitter, Global, ModelContext, Task,
+};
+use http_client::{
+    AsyncBody, HttpClient, HttpRequestExt, Method, Request as HttpRequest, Response, StatusCode,
+};
 use paths::home_dir;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use settings::watch_config_file;
 use strum::EnumIter;
 use ui::Context;
 
-pub const COPILOT_CHAT_COMPLETION_URL: &str = "https://api.githubcopilot.com/chat/completions";
+pub const COPILOT_CHAT_API_URL: &str = "https://api.githubcopilot.com";
 pub const COPILOT_CHAT_AUTH_URL: &str = "https://api.github.com/copilot_internal/v2/token";
 
+/// How long before the API token expires to request a new one.
+const API_TOKEN_REFRESH_MARGIN_SECONDS: i64 = 5 * 60;
+/// The keychain entry the API token is cached in, so that it survives restarts.
+const API_TOKEN_CREDENTIALS_URL: &str = COPILOT_CHAT_AUTH_URL;
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     Assistant,
     System,
+    /// The result of a tool call requested by the assistant.
+    Tool,
 }
 
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -35,8 +55,19 @@ pub enum Model {
     Gpt4,
     #[serde(alias = "gpt-3.5-turbo", rename = "gpt-3.5-turbo")]
     Gpt3_5Turbo,
+    /// A model served by the Copilot Chat API that we don't know about, identified by its ID.
+    #[serde(untagged)]
+    #[strum(disabled)]
+    Other(String),
 }
 
+/// The context window size assumed for models we know nothing about.
+const DEFAULT_MAX_TOKEN_COUNT: usize = 8192;
+
+/// The families of reasoning models, whose IDs are the family's name or start with it
+/// followed by a `-`, e.g. `o1-mini`.
+const REASONING_MODEL_FAMILIES: &[&str] = &["o1", "o3"];
+
 impl Model {
     pub fn from_id(id: &str) -> Result<Self> {
         match id {
@@ -47,19 +78,27 @@ impl Model {
         }
     }
 
-    pub fn id(&self) -> &'static str {
+    /// Returns the model with the given ID as reported by the Copilot Chat API, falling
+    /// back to [`Model::Other`] for models we don't know about.
+    pub fn from_api_id(id: &str) -> Self {
+        Self::from_id(id).unwrap_or_else(|_| Self::Other(id.to_string()))
+    }
+
+    pub fn id(&self) -> &str {
         match self {
             Self::Gpt3_5Turbo => "gpt-3.5-turbo",
             Self::Gpt4 => "gpt-4",
             Self::Gpt4o => "gpt-4o",
+            Self::Other(id) => id,
         }
     }
 
-    pub fn display_name(&self) -> &'static str {
+    pub fn display_name(&self) -> &str {
         match self {
             Self::Gpt3_5Turbo => "GPT-3.5",
             Self::Gpt4 => "GPT-4",
             Self::Gpt4o => "GPT-4o",
+            Self::Other(id) => id,
         }
     }
 
@@ -68,18 +107,97 @@ impl Model {
             Self::Gpt4o => 128000,
             Self::Gpt4 => 8192,
             Self::Gpt3_5Turbo => 16385,
+            Self::Other(_) => DEFAULT_MAX_TOKEN_COUNT,
+        }
+    }
+
+    /// Whether the model is a reasoning model, which accepts a [`ReasoningEffort`].
+    pub fn is_reasoning_model(&self) -> bool {
+        match self {
+            Self::Gpt4o | Self::Gpt4 | Self::Gpt3_5Turbo => false,
+            Self::Other(id) => REASONING_MODEL_FAMILIES.iter().any(|family| {
+                id.strip_prefix(family)
+                    .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('-'))
+            }),
+        }
+    }
+
+    /// Whether the model can accept images alongside text.
+    pub fn supports_images(&self) -> bool {
+        match self {
+            Self::Gpt4o | Self::Gpt4 => true,
+            Self::Gpt3_5Turbo | Self::Other(_) => false,
+        }
+    }
+
+    /// Whether the model accepts [`ResponseFormat::JsonObject`].
+    pub fn supports_json_object_response(&self) -> bool {
+        match self {
+            Self::Gpt4o | Self::Gpt3_5Turbo | Self::Other(_) => true,
+            Self::Gpt4 => false,
+        }
+    }
+
+    /// Whether the model accepts [`ResponseFormat::JsonSchema`]. The GPT-4o snapshot we
+    /// pin predates structured outputs, so only models reported by the API are assumed to.
+    pub fn supports_json_schema_response(&self) -> bool {
+        match self {
+            Self::Gpt4o | Self::Gpt4 | Self::Gpt3_5Turbo => false,
+            Self::Other(_) => true,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Request {
     pub intent: bool,
     pub n: usize,
     pub stream: bool,
     pub temperature: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
     pub model: Model,
     pub messages: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
 }
 
 impl Request {
@@ -89,16 +207,166 @@ impl Request {
             n: 1,
             stream: true,
             temperature: 0.1,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            seed: None,
+            max_tokens: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            response_format: None,
             model,
             messages,
+            tools: Vec::new(),
         }
     }
+
+    /// Returns whether any of the request's messages contain an image.
+    pub fn has_images(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| message.content.has_images())
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ChatMessage {
     pub role: Role,
-    pub content: String,
+    pub content: ChatMessageContent,
+    /// The tools the assistant asked to call, for assistant messages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The ID of the tool call this message is the result of, for tool messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<ChatMessageContent>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+/// The content of a message. Text-only content is sent as a plain string, and content
+/// with images as a list of parts.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum ChatMessageContent {
+    Plain(String),
+    Multipart(Vec<ChatMessagePart>),
+}
+
+impl ChatMessageContent {
+    /// Returns whether the content has no images and only whitespace text.
+    pub fn is_blank(&self) -> bool {
+        match self {
+            Self::Plain(text) => text.trim().is_empty(),
+            Self::Multipart(parts) => parts.iter().all(|part| match part {
+                ChatMessagePart::Text { text } => text.trim().is_empty(),
+                ChatMessagePart::ImageUrl { .. } => false,
+            }),
+        }
+    }
+
+    pub fn has_images(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            Self::Multipart(parts) => parts
+                .iter()
+                .any(|part| matches!(part, ChatMessagePart::ImageUrl { .. })),
+        }
+    }
+
+    /// Appends `other` to this content, separated by a newline.
+    pub fn push(&mut self, other: ChatMessageContent) {
+        match (&mut *self, other) {
+            (Self::Plain(text), Self::Plain(other)) => {
+                text.push('\n');
+                text.push_str(&other);
+            }
+            (this, other) => {
+                let mut parts = this.take_parts();
+                parts.push(ChatMessagePart::Text { text: "\n".into() });
+                parts.extend(other.into_parts());
+                *this = Self::Multipart(parts);
+            }
+        }
+    }
+
+    fn take_parts(&mut self) -> Vec<ChatMessagePart> {
+        std::mem::replace(self, Self::Plain(String::new())).into_parts()
+    }
+
+    fn into_parts(self) -> Vec<ChatMessagePart> {
+        match self {
+            Self::Plain(text) => vec![ChatMessagePart::Text { text }],
+            Self::Multipart(parts) => parts,
+        }
+    }
+}
+
+impl From<String> for ChatMessageContent {
+    fn from(text: String) -> Self {
+        Self::Plain(text)
+    }
+}
+
+impl From<&str> for ChatMessageContent {
+    fn from(text: &str) -> Self {
+        Self::Plain(text.to_string())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatMessagePart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ImageUrl {
+    /// The URL of the image, or its contents as a `data:` URL.
+    pub url: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tool {
+    Function { function: Function },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Function {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(flatten)]
+    pub content: ToolCallContent,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToolCallContent {
+    Function { function: FunctionContent },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FunctionContent {
+    pub name: String,
+    /// The arguments of the call, as a JSON-encoded object.
+    pub arguments: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -107,6 +375,22 @@ pub struct ResponseEvent {
     pub choices: Vec<ResponseChoice>,
     pub created: u64,
     pub id: String,
+    /// The model that served the request, which may differ from the one requested.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Identifies the backend configuration that served the request, which affects
+    /// whether a `seed` reproduces earlier responses.
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -120,6 +404,189 @@ pub struct ResponseChoice {
 pub struct ResponseDelta {
     pub content: Option<String>,
     pub role: Option<Role>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCallChunk>,
+}
+
+/// A fragment of a tool call. The first fragment of each call carries its ID and
+/// function name, and later ones append to its arguments.
+#[derive(Debug, Deserialize)]
+pub struct ToolCallChunk {
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionChunk>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FunctionChunk {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelMetadata>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    /// The position of the embedded text in the request's input.
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// The metadata the Copilot Chat API reports for a model it serves.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelMetadata {
+    pub id: String,
+    #[serde(default)]
+    pub capabilities: ModelCapabilities,
+    /// Whether the user's subscription grants access to the model, for models that not
+    /// every subscription does.
+    #[serde(default)]
+    pub policy: Option<ModelPolicy>,
+}
+
+impl ModelMetadata {
+    /// Whether the model can be used for chat completions, as opposed to e.g. embeddings.
+    pub fn is_chat_model(&self) -> bool {
+        self.capabilities
+            .r#type
+            .as_deref()
+            .map_or(true, |model_type| model_type == "chat")
+    }
+
+    /// Returns the size of the model's context window, if the API reported one.
+    pub fn max_token_count(&self) -> Option<usize> {
+        self.capabilities.limits.max_context_window_tokens
+    }
+
+    /// Whether the user can use the model. Requests to models that have been disabled for
+    /// their subscription, e.g. by an organization's policy, are rejected.
+    pub fn is_enabled(&self) -> bool {
+        self.policy
+            .as_ref()
+            .map_or(true, |policy| policy.state != "disabled")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelPolicy {
+    /// `enabled`, `disabled`, or `unconfigured` for models the user has yet to opt into.
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ModelCapabilities {
+    /// The kind of model, such as `chat` or `embeddings`.
+    pub r#type: Option<String>,
+    #[serde(default)]
+    pub limits: ModelLimits,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ModelLimits {
+    pub max_context_window_tokens: Option<usize>,
+}
+
+/// An error response returned by the Copilot Chat API.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub body: String,
+    /// The human-readable message GitHub included in the response body, if any.
+    pub message: Option<String>,
+    /// The machine-readable code GitHub included in the response body, if any.
+    pub code: Option<String>,
+    /// How long the server asked us to wait before retrying, from its `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, body: String) -> Self {
+        let json = serde_json::from_str::<serde_json::Value>(&body).ok();
+        let message = json.as_ref().and_then(|body| {
+            body.pointer("/error/message")
+                .or_else(|| body.get("message"))
+                .and_then(|message| message.as_str())
+                .map(ToString::to_string)
+        });
+        let code = json.as_ref().and_then(|body| {
+            body.pointer("/error/code")
+                .and_then(|code| code.as_str())
+                .map(ToString::to_string)
+        });
+        Self {
+            status,
+            body,
+            message,
+            code,
+            retry_after: None,
+        }
+    }
+
+    fn from_response(response: &Response<AsyncBody>, body: String) -> Self {
+        let mut error = Self::new(response.status(), body);
+        error.retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        error
+    }
+
+    /// Returns whether the request was rejected by the API's content filter.
+    pub fn is_content_filtered(&self) -> bool {
+        self.code.as_deref() == Some("content_filter")
+    }
+
+    /// Returns whether the failed request may succeed if it is sent again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.status.as_u16(), 429 | 500 | 502 | 503 | 504)
+    }
+
+    /// Returns whether the request was rejected because the user lacks access to Copilot Chat.
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(
+            self.status,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.message.as_deref().unwrap_or(&self.body);
+        write!(f, "Copilot Chat API error ({}): {}", self.status, message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Parses the value of a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date. Dates in the past yield a zero delay.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (retry_at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
 #[derive(Deserialize)]
@@ -138,10 +605,38 @@ impl ApiToken {
     pub fn remaining_seconds(&self) -> i64 {
         self.expires_at
             .timestamp()
-            .saturating_sub(chrono::Utc::now().timestamp())
+            .saturating_sub(Utc::now().timestamp())
+    }
+
+    /// Encodes the token as keychain credentials. The username records the OAuth token the
+    /// API token was issued for, so that it isn't restored for a different account.
+    fn to_credentials(&self, oauth_token: &str) -> (String, Vec<u8>) {
+        let username = format!(
+            "{}:{}",
+            oauth_token_fingerprint(oauth_token),
+            self.expires_at.timestamp()
+        );
+        (username, self.api_key.clone().into_bytes())
+    }
+
+    fn from_credentials(oauth_token: &str, username: &str, password: Vec<u8>) -> Option<Self> {
+        let (fingerprint, expires_at) = username.split_once(':')?;
+        if fingerprint != oauth_token_fingerprint(oauth_token) {
+            return None;
+        }
+        Some(Self {
+            api_key: String::from_utf8(password).ok()?,
+            expires_at: DateTime::from_timestamp(expires_at.parse().ok()?, 0)?,
+        })
     }
 }
 
+fn oauth_token_fingerprint(oauth_token: &str) -> String {
+    let mut hasher = FxHasher::default();
+    oauth_token.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
 impl TryFrom<ApiTokenResponse> for ApiToken {
     type Error = anyhow::Error;
 
@@ -156,16 +651,106 @@ impl TryFrom<ApiTokenResponse> for ApiToken {
     }
 }
 
+/// Identifies the editor to the Copilot Chat API, which expects the same client headers
+/// as the official Copilot plugins send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EditorInfo {
+    /// Sent in the `Editor-Version` header, e.g. `Zed/0.150.0`.
+    pub editor_version: String,
+    /// Sent in the `User-Agent` header.
+    pub user_agent: String,
+}
+
+impl EditorInfo {
+    pub fn new(app_version: impl fmt::Display) -> Self {
+        Self {
+            editor_version: format!("Zed/{app_version}"),
+            user_agent: format!(
+                "Zed/{app_version} ({}; {})",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            ),
+        }
+    }
+}
+
+impl Default for EditorInfo {
+    fn default() -> Self {
+        Self::new(option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"))
+    }
+}
+
+/// How API token requests are retried after transient failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times a failed request is retried. Zero disables retries.
+    pub max_retries: usize,
+    /// The delay before the first retry, which doubles after each further attempt.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before retrying after `attempt` failed attempts, plus up to
+    /// 50% random jitter so that clients don't retry in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        let jitter = delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+        delay.saturating_add(jitter)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Returns whether an API token request that failed with `error` may succeed if it is
+/// sent again. Errors without a response from the API are network failures or timeouts.
+fn is_transient_api_token_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .map_or(true, |error| error.is_retryable())
+}
+
 struct GlobalCopilotChat(gpui::Model<CopilotChat>);
 
 impl Global for GlobalCopilotChat {}
 
 pub struct CopilotChat {
     oauth_token: Option<String>,
+    /// The OAuth token GitHub last rejected, which is tried again when the user authenticates,
+    /// since renewing a Copilot subscription doesn't change the token Copilot stores.
+    rejected_oauth_token: Option<String>,
     api_token: Option<ApiToken>,
+    /// The in-flight API token request, shared so that concurrent callers don't each request a token.
+    /// It's only kept alive by its callers, so that it's aborted once none of them are waiting for it.
+    pending_api_token: Option<WeakShared<Task<Result<ApiToken, Arc<anyhow::Error>>>>>,
+    /// Refreshes the API token shortly before it expires, so that completions don't have to wait for it.
+    refresh_api_token_task: Option<Task<()>>,
+    /// Overrides [`COPILOT_CHAT_API_URL`], e.g. for Copilot Enterprise.
+    api_url: Option<String>,
+    /// Whether to log each line of completion responses, for diagnostics.
+    log_responses: bool,
+    editor_info: EditorInfo,
+    /// How API token requests are retried after transient failures.
+    api_token_retry_policy: RetryPolicy,
     client: Arc<dyn HttpClient>,
+    /// Sends requests through the proxy configured for Copilot Chat, in place of `client`.
+    proxy_client: Option<Arc<dyn HttpClient>>,
 }
 
+pub enum Event {
+    /// The user signed in, signed out or switched to a different GitHub account. Anything
+    /// derived from the previous OAuth token, such as the API token, has been discarded.
+    OAuthTokenChanged,
+}
+
+impl EventEmitter<Event> for CopilotChat {}
+
 pub fn init(fs: Arc<dyn Fs>, client: Arc<dyn HttpClient>, cx: &mut AppContext) {
     let copilot_chat = cx.new_model(|cx| CopilotChat::new(fs, client, cx));
     cx.set_global(GlobalCopilotChat(copilot_chat));
@@ -204,10 +789,7 @@ impl CopilotChat {
 
                 cx.update(|cx| {
                     if let Some(this) = Self::global(cx).as_ref() {
-                        this.update(cx, |this, cx| {
-                            this.oauth_token = oauth_token;
-                            cx.notify();
-                        });
+                        this.update(cx, |this, cx| this.set_oauth_token(oauth_token, cx));
                     }
                 })?;
             }
@@ -217,75 +799,589 @@ impl CopilotChat {
 
         Self {
             oauth_token: None,
+            rejected_oauth_token: None,
+            api_token: None,
+            pending_api_token: None,
+            refresh_api_token_task: None,
+            api_url: None,
+            log_responses: false,
+            editor_info: EditorInfo::default(),
+            api_token_retry_policy: RetryPolicy::default(),
+            client,
+            proxy_client: None,
+        }
+    }
+
+    /// Returns a signed-in instance that sends its requests through `client`, so that code
+    /// talking to the Copilot Chat API can be tested against canned responses.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn fake(client: Arc<dyn HttpClient>) -> Self {
+        Self {
+            oauth_token: Some("fake-oauth-token".into()),
+            rejected_oauth_token: None,
             api_token: None,
+            pending_api_token: None,
+            refresh_api_token_task: None,
+            api_url: None,
+            log_responses: false,
+            editor_info: EditorInfo::default(),
+            api_token_retry_policy: RetryPolicy::default(),
             client,
+            proxy_client: None,
         }
     }
 
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn set_oauth_token_for_test(
+        &mut self,
+        oauth_token: Option<String>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        self.set_oauth_token(oauth_token, cx);
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn has_api_token(&self) -> bool {
+        self.api_token.is_some()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn set_global(copilot_chat: gpui::Model<Self>, cx: &mut AppContext) {
+        cx.set_global(GlobalCopilotChat(copilot_chat));
+    }
+
     pub fn is_authenticated(&self) -> bool {
         self.oauth_token.is_some()
     }
 
-    pub async fn stream_completion(
-        request: Request,
-        low_speed_timeout: Option<Duration>,
-        mut cx: AsyncAppContext,
-    ) -> Result<BoxStream<'static, Result<ResponseEvent>>> {
-        let Some(this) = cx.update(|cx| Self::global(cx)).ok().flatten() else {
-            return Err(anyhow!("Copilot chat is not enabled"));
+    fn set_oauth_token(&mut self, oauth_token: Option<String>, cx: &mut ModelContext<Self>) {
+        if self.oauth_token != oauth_token {
+            // The API token belongs to the previous account, so discard it and stop refreshing it.
+            if self.oauth_token.is_some() {
+                cx.delete_credentials(API_TOKEN_CREDENTIALS_URL)
+                    .detach_and_log_err(cx);
+            }
+            self.api_token = None;
+            self.pending_api_token = None;
+            self.refresh_api_token_task = None;
+            self.oauth_token = oauth_token;
+            self.rejected_oauth_token = None;
+            self.restore_api_token(cx);
+            cx.emit(Event::OAuthTokenChanged);
+            cx.notify();
+        }
+    }
+
+    /// Loads the API token cached in the keychain by a previous session, if it's still valid.
+    fn restore_api_token(&mut self, cx: &mut ModelContext<Self>) {
+        let Some(oauth_token) = self.oauth_token.clone() else {
+            return;
         };
+        let read_credentials = cx.read_credentials(API_TOKEN_CREDENTIALS_URL);
+        cx.spawn(|this, mut cx| async move {
+            let Some((username, password)) = read_credentials.await? else {
+                return Ok(());
+            };
+            let Some(token) = ApiToken::from_credentials(&oauth_token, &username, password) else {
+                return Ok(());
+            };
+            // Expired tokens are left for the next request to replace.
+            if token.remaining_seconds() <= API_TOKEN_REFRESH_MARGIN_SECONDS {
+                return Ok(());
+            }
+            this.update(&mut cx, |this, cx| {
+                if this.oauth_token.as_ref() == Some(&oauth_token)
+                    && this.api_token.is_none()
+                    && this.pending_api_token().is_none()
+                {
+                    this.set_api_token(token, None, cx);
+                }
+            })
+        })
+        .detach_and_log_err(cx);
+    }
 
-        let (oauth_token, api_token, client) = this.read_with(&cx, |this, _| {
-            (
-                this.oauth_token.clone(),
-                this.api_token.clone(),
-                this.client.clone(),
-            )
-        })?;
+    /// Forgets the OAuth token along with the API token, so that requests fail until the
+    /// OAuth token is read again after the user signs back in to Copilot.
+    pub fn sign_out(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let delete_credentials = self.reset_api_token(cx);
+        self.rejected_oauth_token = None;
+        if self.oauth_token.take().is_some() {
+            cx.emit(Event::OAuthTokenChanged);
+        }
+        delete_credentials
+    }
 
-        let oauth_token = oauth_token.ok_or_else(|| anyhow!("No OAuth token available"))?;
+    /// Discards the cached API token, both in memory and in the keychain.
+    pub fn reset_api_token(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        self.api_token = None;
+        self.pending_api_token = None;
+        self.refresh_api_token_task = None;
+        cx.notify();
+        cx.delete_credentials(API_TOKEN_CREDENTIALS_URL)
+    }
 
-        let token = match api_token {
-            Some(api_token) if api_token.remaining_seconds() > 5 * 60 => api_token.clone(),
-            _ => {
-                let token =
-                    request_api_token(&oauth_token, client.clone(), low_speed_timeout).await?;
-                this.update(&mut cx, |this, cx| {
-                    this.api_token = Some(token.clone());
-                    cx.notify();
-                })?;
-                token
-            }
+    /// Checks that the OAuth token still grants access to Copilot Chat by requesting an API token.
+    ///
+    /// If GitHub rejects the OAuth token, e.g. because the Copilot subscription has expired,
+    /// the token is discarded so that the user is prompted to sign in again, as it is whenever
+    /// an API token request is rejected.
+    pub fn verify_oauth_token(
+        &mut self,
+        low_speed_timeout: Option<Duration>,
+        cx: &mut ModelContext<Self>,
+    ) -> Task<Result<()>> {
+        let api_token = self.refresh_api_token(low_speed_timeout, cx);
+        cx.background_executor()
+            .spawn(async move { api_token.await.map(|_| ()).map_err(|error| anyhow!(error)) })
+    }
+
+    /// Tries the OAuth token GitHub last rejected again, e.g. after the user renewed their
+    /// Copilot subscription, and checks whether it's accepted now. If it's rejected again,
+    /// it's discarded as before.
+    pub fn retry_rejected_oauth_token(
+        &mut self,
+        low_speed_timeout: Option<Duration>,
+        cx: &mut ModelContext<Self>,
+    ) -> Task<Result<()>> {
+        let Some(oauth_token) = self.rejected_oauth_token.take() else {
+            return Task::ready(Err(NotSignedInError.into()));
         };
+        self.set_oauth_token(Some(oauth_token), cx);
+        self.verify_oauth_token(low_speed_timeout, cx)
+    }
 
-        stream_completion(client.clone(), token.api_key, request, low_speed_timeout).await
+    /// Returns the API token request that is in flight, if anyone is still waiting for it.
+    fn pending_api_token(&self) -> Option<Shared<Task<Result<ApiToken, Arc<anyhow::Error>>>>> {
+        self.pending_api_token.as_ref()?.upgrade()
     }
-}
 
-async fn request_api_token(
-    oauth_token: &str,
-    client: Arc<dyn HttpClient>,
-    low_speed_timeout: Option<Duration>,
-) -> Result<ApiToken> {
-    let mut request_builder = HttpRequest::builder()
-        .method(Method::GET)
-        .uri(COPILOT_CHAT_AUTH_URL)
-        .header("Authorization", format!("token {}", oauth_token))
-        .header("Accept", "application/json");
+    /// Requests a new API token, or returns the request that is already in flight.
+    ///
+    /// The request is aborted once every future returned for it has been dropped.
+    fn refresh_api_token(
+        &mut self,
+        low_speed_timeout: Option<Duration>,
+        cx: &mut ModelContext<Self>,
+    ) -> Shared<Task<Result<ApiToken, Arc<anyhow::Error>>>> {
+        if let Some(pending_api_token) = self.pending_api_token() {
+            return pending_api_token;
+        }
 
-    if let Some(low_speed_timeout) = low_speed_timeout {
-        request_builder = request_builder.read_timeout(low_speed_timeout);
+        let Some(oauth_token) = self.oauth_token.clone() else {
+            return Task::ready(Err(Arc::new(NotSignedInError.into()))).shared();
+        };
+        let client = self.http_client();
+        let editor_info = self.editor_info.clone();
+        let retry_policy = self.api_token_retry_policy;
+        let executor = cx.background_executor().clone();
+        let task = cx
+            .spawn(|this, mut cx| async move {
+                let mut attempt = 0;
+                let result = loop {
+                    let request = request_api_token(
+                        &oauth_token,
+                        &editor_info,
+                        client.clone(),
+                        low_speed_timeout,
+                    );
+                    // The HTTP client's low-speed timeout isn't guaranteed to cover waiting for
+                    // the response to start, and everyone waiting for a token shares this
+                    // request, so we enforce the timeout here too.
+                    let result = match low_speed_timeout {
+                        Some(timeout) => {
+                            match future::select(request.boxed_local(), executor.timer(timeout))
+                                .await
+                            {
+                                Either::Left((result, _)) => result,
+                                Either::Right(_) => Err(anyhow!(
+                                    "Timed out requesting a Copilot Chat API token after {} seconds",
+                                    timeout.as_secs_f32()
+                                )),
+                            }
+                        }
+                        None => request.await,
+                    };
+                    match result {
+                        Err(error)
+                            if attempt < retry_policy.max_retries
+                                && is_transient_api_token_error(&error) =>
+                        {
+                            let delay = error
+                                .downcast_ref::<ApiError>()
+                                .and_then(|error| error.retry_after)
+                                .unwrap_or_else(|| retry_policy.delay(attempt as u32));
+                            attempt += 1;
+                            log::warn!(
+                                "Copilot Chat API token request failed, retrying in {:?} (attempt {} of {}): {:#}",
+                                delay,
+                                attempt,
+                                retry_policy.max_retries,
+                                error
+                            );
+                            executor.timer(delay).await;
+                        }
+                        result => break result,
+                    }
+                };
+                this.update(&mut cx, |this, cx| {
+                    // Ignore the result if the user signed out while the token was being requested.
+                    if this.oauth_token.as_ref() != Some(&oauth_token) {
+                        return;
+                    }
+                    this.pending_api_token = None;
+                    match &result {
+                        Ok(token) => {
+                            let (username, password) = token.to_credentials(&oauth_token);
+                            cx.write_credentials(API_TOKEN_CREDENTIALS_URL, &username, &password)
+                                .detach_and_log_err(cx);
+                            this.set_api_token(token.clone(), low_speed_timeout, cx);
+                        }
+                        // GitHub no longer accepts the OAuth token, e.g. because the Copilot
+                        // subscription was revoked, so it's discarded to have the user sign in
+                        // again, and kept aside to try again when they do. Transient failures
+                        // leave it be.
+                        Err(error)
+                            if error
+                                .downcast_ref::<ApiError>()
+                                .map_or(false, ApiError::is_unauthorized) =>
+                        {
+                            this.set_oauth_token(None, cx);
+                            this.rejected_oauth_token = Some(oauth_token.clone());
+                        }
+                        Err(_) => {}
+                    }
+                })
+                .ok();
+                result.map_err(Arc::new)
+            })
+            .shared();
+        self.pending_api_token = task.downgrade();
+        task
     }
 
-    let request = request_builder.body(AsyncBody::empty())?;
+    /// Stores the API token and schedules its refresh, which uses `low_speed_timeout`.
+    fn set_api_token(
+        &mut self,
+        token: ApiToken,
+        low_speed_timeout: Option<Duration>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        let refresh_in = token
+            .remaining_seconds()
+            .saturating_sub(API_TOKEN_REFRESH_MARGIN_SECONDS)
+            .max(0);
+        self.api_token = Some(token);
+        self.refresh_api_token_task = Some(cx.spawn(|this, mut cx| async move {
+            cx.background_executor()
+                .timer(Duration::from_secs(refresh_in as u64))
+                .await;
+            let Ok(refresh) = this.update(&mut cx, |this, cx| {
+                this.refresh_api_token(low_speed_timeout, cx)
+            }) else {
+                return;
+            };
+            // Failures are left for the next request to retry.
+            refresh.await.ok();
+        }));
+        cx.notify();
+    }
 
-    let mut response = client.send(request).await?;
+    pub fn set_api_url(&mut self, api_url: Option<String>, cx: &mut ModelContext<Self>) {
+        if self.api_url != api_url {
+            self.api_url = api_url;
+            cx.notify();
+        }
+    }
 
-    if response.status().is_success() {
-        let mut body = Vec::new();
-        response.body_mut().read_to_end(&mut body).await?;
+    pub fn set_log_responses(&mut self, log_responses: bool) {
+        self.log_responses = log_responses;
+    }
 
-        let body_str = std::str::from_utf8(&body)?;
+    /// Sets how requests identify the editor, which defaults to this crate's version.
+    pub fn set_editor_info(&mut self, editor_info: EditorInfo) {
+        self.editor_info = editor_info;
+    }
+
+    pub fn set_api_token_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.api_token_retry_policy = retry_policy;
+    }
+
+    /// Sends requests through `proxy_client`, or through the client Copilot Chat was
+    /// initialized with if it's `None`.
+    pub fn set_proxy_client(&mut self, proxy_client: Option<Arc<dyn HttpClient>>) {
+        self.proxy_client = proxy_client;
+    }
+
+    fn http_client(&self) -> Arc<dyn HttpClient> {
+        self.proxy_client
+            .clone()
+            .unwrap_or_else(|| self.client.clone())
+    }
+
+    /// Streams a chat completion for the given request.
+    ///
+    /// The HTTP response body is owned by the returned stream, so dropping the stream
+    /// aborts the underlying request instead of reading it to completion. Nothing reads
+    /// the body ahead of the consumer either: it is read only as the stream is polled, so
+    /// a slow consumer applies backpressure to the connection instead of buffering chunks.
+    ///
+    /// `request_id` is sent in the `X-Request-Id` header, so that failures reported by users
+    /// can be matched up with the API's logs.
+    pub async fn stream_completion(
+        request: Request,
+        request_id: &str,
+        low_speed_timeout: Option<Duration>,
+        mut cx: AsyncAppContext,
+    ) -> Result<BoxStream<'static, Result<ResponseEvent>>> {
+        let (client, token, api_url, editor_info) =
+            Self::api_token(low_speed_timeout, &mut cx).await?;
+        let log_responses =
+            cx.update(|cx| Self::global(cx).map_or(false, |this| this.read(cx).log_responses))?;
+        let response = stream_completion(
+            client.clone(),
+            &api_url,
+            token.api_key.clone(),
+            &editor_info,
+            request.clone(),
+            request_id,
+            low_speed_timeout,
+            log_responses,
+        )
+        .await;
+
+        let rejected = response.as_ref().err().map_or(false, |error| {
+            error
+                .downcast_ref::<ApiError>()
+                .map_or(false, |error| error.status == StatusCode::UNAUTHORIZED)
+        });
+        if !rejected {
+            return response;
+        }
+
+        // When the token is refreshed is judged by the local clock, which may be skewed
+        // relative to GitHub's, and GitHub may also invalidate tokens early. So a rejected
+        // token is replaced and the request retried, but only once.
+        let token = Self::replace_rejected_api_token(&token, low_speed_timeout, &mut cx).await?;
+        stream_completion(
+            client,
+            &api_url,
+            token.api_key,
+            &editor_info,
+            request,
+            request_id,
+            low_speed_timeout,
+            log_responses,
+        )
+        .await
+    }
+
+    /// Fetches the metadata of the models served by the Copilot Chat API.
+    pub async fn fetch_models(
+        low_speed_timeout: Option<Duration>,
+        mut cx: AsyncAppContext,
+    ) -> Result<Vec<ModelMetadata>> {
+        let (client, token, api_url, editor_info) =
+            Self::api_token(low_speed_timeout, &mut cx).await?;
+        request_models(
+            client,
+            &api_url,
+            token.api_key,
+            &editor_info,
+            low_speed_timeout,
+        )
+        .await
+    }
+
+    /// Returns the embeddings of `texts` computed by the embedding model with the given ID,
+    /// in the same order as the texts.
+    pub async fn embed(
+        model: &str,
+        texts: Vec<String>,
+        low_speed_timeout: Option<Duration>,
+        mut cx: AsyncAppContext,
+    ) -> Result<Vec<Vec<f32>>> {
+        let (client, token, api_url, editor_info) =
+            Self::api_token(low_speed_timeout, &mut cx).await?;
+        request_embeddings(
+            client,
+            &api_url,
+            token.api_key,
+            &editor_info,
+            model,
+            &texts,
+            low_speed_timeout,
+        )
+        .await
+    }
+
+    /// Returns a valid API token, requesting a new one if the cached token is about to expire,
+    /// along with everything else needed to make a request with it.
+    async fn api_token(
+        low_speed_timeout: Option<Duration>,
+        cx: &mut AsyncAppContext,
+    ) -> Result<(Arc<dyn HttpClient>, ApiToken, String, EditorInfo)> {
+        let Some(this) = cx.update(|cx| Self::global(cx)).ok().flatten() else {
+            return Err(anyhow!("Copilot chat is not enabled"));
+        };
+
+        let (signed_in, api_token, api_url, client, editor_info) =
+            this.read_with(cx, |this, _| {
+                (
+                    this.is_authenticated(),
+                    this.api_token.clone(),
+                    this.api_url.clone(),
+                    this.http_client(),
+                    this.editor_info.clone(),
+                )
+            })?;
+        // The user may have signed out since the caller checked.
+        if !signed_in {
+            return Err(NotSignedInError.into());
+        }
+
+        let token = match api_token {
+            Some(api_token) if api_token.remaining_seconds() > API_TOKEN_REFRESH_MARGIN_SECONDS => {
+                api_token
+            }
+            _ => this
+                .update(cx, |this, cx| this.refresh_api_token(low_speed_timeout, cx))?
+                .await
+                .map_err(|error| anyhow!(error))?,
+        };
+
+        let api_url = api_url.unwrap_or_else(|| COPILOT_CHAT_API_URL.to_string());
+        Ok((client, token, api_url, editor_info))
+    }
+
+    /// Returns a new API token in place of one the API rejected, regardless of its expiry.
+    /// If another request has already replaced the rejected token, its replacement is used.
+    async fn replace_rejected_api_token(
+        rejected: &ApiToken,
+        low_speed_timeout: Option<Duration>,
+        cx: &mut AsyncAppContext,
+    ) -> Result<ApiToken> {
+        let Some(this) = cx.update(|cx| Self::global(cx)).ok().flatten() else {
+            return Err(anyhow!("Copilot chat is not enabled"));
+        };
+        let refresh = this.update(cx, |this, cx| match &this.api_token {
+            Some(api_token) if api_token.api_key != rejected.api_key => {
+                Task::ready(Ok(api_token.clone())).shared()
+            }
+            _ => {
+                this.api_token = None;
+                this.refresh_api_token(low_speed_timeout, cx)
+            }
+        })?;
+        refresh.await.map_err(|error| anyhow!(error))
+    }
+}
+
+/// Fails the completion if it doesn't finish within `timeout` of this function being called.
+///
+/// The deadline covers both establishing the stream and reading it to completion. When it
+/// passes, the response stream is dropped, which aborts the underlying HTTP request.
+pub fn with_completion_timeout<T: Send + 'static>(
+    response: impl Future<Output = Result<BoxStream<'static, Result<T>>>> + 'static,
+    timeout: Duration,
+    executor: &BackgroundExecutor,
+) -> impl Future<Output = Result<BoxStream<'static, Result<T>>>> {
+    let deadline = executor.timer(timeout).boxed();
+    async move {
+        let (stream, deadline) = match future::select(response.boxed_local(), deadline).await {
+            Either::Left((response, deadline)) => (response?, deadline),
+            Either::Right(_) => return Err(completion_timeout_error(timeout)),
+        };
+
+        let stream = stream::unfold(Some((stream, deadline)), move |state| async move {
+            let (mut stream, deadline) = state?;
+            let next = future::select(stream.next(), deadline).await;
+            match next {
+                Either::Left((event, deadline)) => {
+                    event.map(|event| (event, Some((stream, deadline))))
+                }
+                Either::Right(_) => Some((Err(completion_timeout_error(timeout)), None)),
+            }
+        });
+        Ok(stream.boxed())
+    }
+}
+
+fn completion_timeout_error(timeout: Duration) -> anyhow::Error {
+    CompletionTimeoutError { timeout }.into()
+}
+
+/// The error returned when a completion exceeds its `completion_timeout`.
+#[derive(Debug)]
+pub struct CompletionTimeoutError {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CompletionTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Copilot Chat completion timed out after {} seconds",
+            self.timeout.as_secs_f32()
+        )
+    }
+}
+
+impl std::error::Error for CompletionTimeoutError {}
+
+/// The error returned when a request needs an API token, but the user isn't signed in.
+#[derive(Debug)]
+pub struct NotSignedInError;
+
+impl fmt::Display for NotSignedInError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not signed in to GitHub Copilot")
+    }
+}
+
+impl std::error::Error for NotSignedInError {}
+
+/// Sends a request, mentioning the proxy in the error if it couldn't be sent through one.
+async fn send_request(
+    client: &dyn HttpClient,
+    request: HttpRequest<AsyncBody>,
+) -> Result<Response<AsyncBody>> {
+    let proxy = client.proxy().cloned();
+    client.send(request).await.map_err(|error| match proxy {
+        Some(proxy) => error.context(format!(
+            "Failed to connect to Copilot Chat through the proxy at {proxy}"
+        )),
+        None => error,
+    })
+}
+
+async fn request_api_token(
+    oauth_token: &str,
+    editor_info: &EditorInfo,
+    client: Arc<dyn HttpClient>,
+    low_speed_timeout: Option<Duration>,
+) -> Result<ApiToken> {
+    let mut request_builder = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(COPILOT_CHAT_AUTH_URL)
+        .header("Editor-Version", &editor_info.editor_version)
+        .header("User-Agent", &editor_info.user_agent)
+        .header("Authorization", format!("token {}", oauth_token))
+        .header("Accept", "application/json");
+
+    if let Some(low_speed_timeout) = low_speed_timeout {
+        request_builder = request_builder.read_timeout(low_speed_timeout);
+    }
+
+    let request = request_builder.body(AsyncBody::empty())?;
+
+    let mut response = send_request(client.as_ref(), request).await?;
+
+    if response.status().is_success() {
+        let mut body = Vec::new();
+        response.body_mut().read_to_end(&mut body).await?;
+
+        let body_str = std::str::from_utf8(&body)?;
 
         let parsed: ApiTokenResponse = serde_json::from_str(body_str)?;
         ApiToken::try_from(parsed)
@@ -295,7 +1391,91 @@ async fn request_api_token(
 
         let body_str = std::str::from_utf8(&body)?;
 
-        Err(anyhow!("Failed to request API token: {}", body_str))
+        Err(ApiError::from_response(&response, body_str.to_string()).into())
+    }
+}
+
+async fn request_models(
+    client: Arc<dyn HttpClient>,
+    api_url: &str,
+    api_key: String,
+    editor_info: &EditorInfo,
+    low_speed_timeout: Option<Duration>,
+) -> Result<Vec<ModelMetadata>> {
+    let mut request_builder = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(format!("{api_url}/models"))
+        .header("Editor-Version", &editor_info.editor_version)
+        .header("User-Agent", &editor_info.user_agent)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Copilot-Integration-Id", "vscode-chat");
+
+    if let Some(low_speed_timeout) = low_speed_timeout {
+        request_builder = request_builder.read_timeout(low_speed_timeout);
+    }
+
+    let request = request_builder.body(AsyncBody::empty())?;
+
+    let mut response = send_request(client.as_ref(), request).await?;
+
+    let mut body = Vec::new();
+    response.body_mut().read_to_end(&mut body).await?;
+
+    let body_str = std::str::from_utf8(&body)?;
+
+    if response.status().is_success() {
+        let parsed: ModelsResponse = serde_json::from_str(body_str)?;
+        Ok(parsed.data)
+    } else {
+        Err(ApiError::from_response(&response, body_str.to_string()).into())
+    }
+}
+
+async fn request_embeddings(
+    client: Arc<dyn HttpClient>,
+    api_url: &str,
+    api_key: String,
+    editor_info: &EditorInfo,
+    model: &str,
+    texts: &[String],
+    low_speed_timeout: Option<Duration>,
+) -> Result<Vec<Vec<f32>>> {
+    let mut request_builder = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(format!("{api_url}/embeddings"))
+        .header("Editor-Version", &editor_info.editor_version)
+        .header("User-Agent", &editor_info.user_agent)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+        .header("Copilot-Integration-Id", "vscode-chat");
+
+    if let Some(low_speed_timeout) = low_speed_timeout {
+        request_builder = request_builder.read_timeout(low_speed_timeout);
+    }
+
+    let request = EmbeddingsRequest {
+        model,
+        input: texts,
+    };
+    let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
+
+    let mut response = send_request(client.as_ref(), request).await?;
+
+    let mut body = Vec::new();
+    response.body_mut().read_to_end(&mut body).await?;
+
+    let body_str = std::str::from_utf8(&body)?;
+
+    if response.status().is_success() {
+        let mut parsed: EmbeddingsResponse = serde_json::from_str(body_str)?;
+        parsed.data.sort_by_key(|embedding| embedding.index);
+        Ok(parsed
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    } else {
+        Err(ApiError::from_response(&response, body_str.to_string()).into())
     }
 }
 
@@ -312,58 +1492,78 @@ fn extract_oauth_token(contents: String) -> Option<String> {
 
 async fn stream_completion(
     client: Arc<dyn HttpClient>,
+    api_url: &str,
     api_key: String,
+    editor_info: &EditorInfo,
     request: Request,
+    request_id: &str,
     low_speed_timeout: Option<Duration>,
+    log_responses: bool,
 ) -> Result<BoxStream<'static, Result<ResponseEvent>>> {
     let mut request_builder = HttpRequest::builder()
         .method(Method::POST)
-        .uri(COPILOT_CHAT_COMPLETION_URL)
-        .header(
-            "Editor-Version",
-            format!(
-                "Zed/{}",
-                option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
-            ),
-        )
+        .uri(format!("{api_url}/chat/completions"))
+        .header("Editor-Version", &editor_info.editor_version)
+        .header("User-Agent", &editor_info.user_agent)
         .header("Authorization", format!("Bearer {}", api_key))
         .header("Content-Type", "application/json")
-        .header("Copilot-Integration-Id", "vscode-chat");
+        .header("Copilot-Integration-Id", "vscode-chat")
+        .header("X-Request-Id", request_id);
 
+    if request.has_images() {
+        request_builder = request_builder.header("Copilot-Vision-Request", "true");
+    }
     if let Some(low_speed_timeout) = low_speed_timeout {
         request_builder = request_builder.read_timeout(low_speed_timeout);
     }
     let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
-    let mut response = client.send(request).await?;
+    let mut response = send_request(client.as_ref(), request).await?;
     if response.status().is_success() {
-        let reader = BufReader::new(response.into_body());
-        Ok(reader
-            .lines()
-            .filter_map(|line| async move {
-                match line {
-                    Ok(line) => {
-                        let line = line.strip_prefix("data: ")?;
-                        if line.starts_with("[DONE]") {
-                            return None;
-                        }
+        let lines = BufReader::new(response.into_body()).lines();
+        // Malformed lines are skipped, since a proxy may have mangled them. The error is only
+        // returned if nothing else arrives after it, in which case the response was likely
+        // cut short.
+        Ok(stream::unfold(
+            (lines, None),
+            move |(mut lines, mut parse_error)| async move {
+                loop {
+                    let Some(line) = lines.next().await else {
+                        return parse_error.map(|error| (Err(error), (lines, None)));
+                    };
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(error) => return Some((Err(anyhow!(error)), (lines, parse_error))),
+                    };
+                    if log_responses {
+                        log::debug!("Copilot Chat response line: {line}");
+                    }
+                    let Some(line) = line.strip_prefix("data: ") else {
+                        continue;
+                    };
+                    if line.starts_with("[DONE]") {
+                        parse_error = None;
+                        continue;
+                    }
 
-                        match serde_json::from_str::<ResponseEvent>(line) {
-                            Ok(response) => {
-                                if response.choices.first().is_none()
-                                    || response.choices.first().unwrap().finish_reason.is_some()
-                                {
-                                    None
-                                } else {
-                                    Some(Ok(response))
-                                }
+                    match serde_json::from_str::<ResponseEvent>(line) {
+                        Ok(response) => {
+                            // Keep the final chunk around even though it might not contain
+                            // any choices, since it carries the token usage.
+                            if response.choices.is_empty() && response.usage.is_none() {
+                                parse_error = None;
+                                continue;
                             }
-                            Err(error) => Some(Err(anyhow!(error))),
+                            return Some((Ok(response), (lines, None)));
+                        }
+                        Err(error) => {
+                            log::warn!("Skipping malformed Copilot Chat response line: {error}");
+                            parse_error = Some(anyhow!(error));
                         }
                     }
-                    Err(error) => Some(Err(anyhow!(error))),
                 }
-            })
-            .boxed())
+            },
+        )
+        .boxed())
     } else {
         let mut body = Vec::new();
         response.body_mut().read_to_end(&mut body).await?;
@@ -375,11 +1575,841 @@ async fn stream_completion(
                 "Unexpected success response while expecting an error: {}",
                 body_str,
             )),
-            Err(_) => Err(anyhow!(
-                "Failed to connect to API: {} {}",
-                response.status(),
-                body_str,
-            )),
+            Err(_) => Err(ApiError::from_response(&response, body_str.to_string()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncRead;
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, HttpClientWithProxy, HttpClientWithUrl};
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+    use std::task::{Context, Poll};
+    use strum::IntoEnumIterator;
+
+    /// A response body that yields a single chunk and then never completes, like a
+    /// completion that is still being generated.
+    struct PendingBody {
+        chunk: Option<Vec<u8>>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl AsyncRead for PendingBody {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.chunk.take() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Poll::Ready(Ok(chunk.len()))
+                }
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl Drop for PendingBody {
+        fn drop(&mut self) {
+            self.dropped.store(true, SeqCst);
         }
     }
+
+    /// Returns a client that responds with a [`PendingBody`], recording when it is dropped.
+    fn pending_client(body_dropped: Arc<AtomicBool>) -> Arc<HttpClientWithUrl> {
+        FakeHttpClient::create(move |_| {
+            let body = PendingBody {
+                chunk: Some(
+                    concat!(
+                        r#"data: {"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+                        "\n"
+                    )
+                    .as_bytes()
+                    .to_vec(),
+                ),
+                dropped: body_dropped.clone(),
+            };
+            async move {
+                Ok(Response::builder()
+                    .status(200)
+                    .body(AsyncBody::from_reader(body))
+                    .unwrap())
+            }
+        })
+    }
+
+    /// A response body that yields a chunk on every read and never ends, counting the reads.
+    struct EndlessBody {
+        chunk: Vec<u8>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for EndlessBody {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.reads.fetch_add(1, SeqCst);
+            buf[..self.chunk.len()].copy_from_slice(&self.chunk);
+            Poll::Ready(Ok(self.chunk.len()))
+        }
+    }
+
+    fn test_request() -> Request {
+        Request::new(Model::Gpt4o, vec![ChatMessage::new(Role::User, "Hi")])
+    }
+
+    #[test]
+    fn test_dropping_stream_aborts_request() {
+        let body_dropped = Arc::new(AtomicBool::new(false));
+        let client = pending_client(body_dropped.clone());
+
+        smol::block_on(async {
+            let mut stream = stream_completion(
+                client,
+                COPILOT_CHAT_API_URL,
+                "api-key".into(),
+                &EditorInfo::default(),
+                test_request(),
+                "request-id",
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+            let event = stream.next().await.unwrap().unwrap();
+            assert_eq!(event.choices[0].delta.content.as_deref(), Some("Hello"));
+            assert!(!body_dropped.load(SeqCst));
+
+            drop(stream);
+            assert!(body_dropped.load(SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_body_is_read_only_as_fast_as_the_stream_is_polled() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let reads = reads.clone();
+            move |_| {
+                let body = EndlessBody {
+                    chunk: concat!(
+                        r#"data: {"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello"}}],"created":0,"id":"1"}"#,
+                        "\n"
+                    )
+                    .as_bytes()
+                    .to_vec(),
+                    reads: reads.clone(),
+                };
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from_reader(body))
+                        .unwrap())
+                }
+            }
+        });
+
+        smol::block_on(async {
+            let mut stream = stream_completion(
+                client,
+                COPILOT_CHAT_API_URL,
+                "api-key".into(),
+                &EditorInfo::default(),
+                test_request(),
+                "request-id",
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(reads.load(SeqCst), 0);
+
+            for _ in 0..3 {
+                stream.next().await.unwrap().unwrap();
+            }
+            assert_eq!(reads.load(SeqCst), 3);
+        });
+    }
+
+    #[test]
+    fn test_malformed_lines_are_skipped() {
+        let body = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|line| format!("data: {line}\n"))
+                .collect::<String>()
+        };
+        let hello = r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello"}}],"created":0,"id":"1"}"#;
+        let world = r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":"world"}}],"created":0,"id":"1"}"#;
+        let garbage = r#"{"choices":[{"index":0,"#;
+
+        let contents = |body: String| {
+            let client = FakeHttpClient::create(move |_| {
+                let body = body.clone();
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(body))
+                        .unwrap())
+                }
+            });
+            smol::block_on(async {
+                stream_completion(
+                    client,
+                    COPILOT_CHAT_API_URL,
+                    "api-key".into(),
+                    &EditorInfo::default(),
+                    test_request(),
+                    "request-id",
+                    None,
+                    false,
+                )
+                .await
+                .unwrap()
+                .map(|event| event.map(|event| event.choices[0].delta.content.clone()))
+                .collect::<Vec<_>>()
+                .await
+            })
+        };
+
+        let events = contents(body(&[hello, garbage, world, "[DONE]"]));
+        assert_eq!(
+            events
+                .into_iter()
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+            [Some("Hello".to_string()), Some("world".to_string())]
+        );
+
+        let mut events = contents(body(&[hello, garbage]));
+        assert_eq!(events.len(), 2);
+        assert!(events.pop().unwrap().unwrap_err().is::<serde_json::Error>());
+    }
+
+    #[gpui::test]
+    async fn test_completion_timeout(cx: &mut TestAppContext) {
+        let body_dropped = Arc::new(AtomicBool::new(false));
+        let client = pending_client(body_dropped.clone());
+        let executor = cx.executor();
+
+        let timeout = Duration::from_secs(30);
+        let mut stream = with_completion_timeout(
+            stream_completion(
+                client,
+                COPILOT_CHAT_API_URL,
+                "api-key".into(),
+                &EditorInfo::default(),
+                test_request(),
+                "request-id",
+                None,
+                false,
+            ),
+            timeout,
+            &executor,
+        )
+        .await
+        .unwrap();
+        let completion = executor.spawn(async move {
+            while let Some(event) = stream.next().await {
+                event?;
+            }
+            anyhow::Ok(())
+        });
+
+        executor.advance_clock(timeout - Duration::from_secs(1));
+        executor.run_until_parked();
+        assert!(!body_dropped.load(SeqCst));
+
+        executor.advance_clock(Duration::from_secs(1));
+        let error = completion.await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Copilot Chat completion timed out after 30 seconds"
+        );
+        assert!(error.is::<CompletionTimeoutError>());
+        assert!(body_dropped.load(SeqCst));
+    }
+
+    #[gpui::test]
+    async fn test_concurrent_api_token_refreshes_are_deduplicated(cx: &mut TestAppContext) {
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let token_requests = token_requests.clone();
+            move |request| {
+                assert_eq!(request.uri().to_string(), COPILOT_CHAT_AUTH_URL);
+                token_requests.fetch_add(1, SeqCst);
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(format!(
+                            r#"{{"token":"api-key","expires_at":{}}}"#,
+                            Utc::now().timestamp() + 30 * 60
+                        )))
+                        .unwrap())
+                }
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(client));
+
+        let (first, second) = copilot_chat.update(cx, |copilot_chat, cx| {
+            (
+                copilot_chat.refresh_api_token(None, cx),
+                copilot_chat.refresh_api_token(None, cx),
+            )
+        });
+        let (first, second) = futures::join!(first, second);
+        assert_eq!(first.unwrap().api_key, "api-key");
+        assert_eq!(second.unwrap().api_key, "api-key");
+        assert_eq!(token_requests.load(SeqCst), 1);
+
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.pending_api_token.is_none());
+            assert!(copilot_chat.api_token.is_some());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_api_token_request_times_out(cx: &mut TestAppContext) {
+        let client = FakeHttpClient::create(|_| future::pending());
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(client));
+        let executor = cx.executor();
+
+        let timeout = Duration::from_secs(30);
+        let token = copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.refresh_api_token(Some(timeout), cx)
+        });
+        let token = executor.spawn(token);
+
+        executor.advance_clock(timeout - Duration::from_secs(1));
+        executor.run_until_parked();
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.pending_api_token().is_some());
+        });
+
+        executor.advance_clock(Duration::from_secs(1));
+        let error = token.await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Timed out requesting a Copilot Chat API token after 30 seconds"
+        );
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.pending_api_token.is_none());
+            assert!(copilot_chat.api_token.is_none());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_rejected_oauth_token_is_discarded(cx: &mut TestAppContext) {
+        let client = FakeHttpClient::create(|_| async move {
+            Ok(Response::builder()
+                .status(403)
+                .body(AsyncBody::from(
+                    r#"{"error":{"message":"Your Copilot subscription has expired"}}"#,
+                ))
+                .unwrap())
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(client));
+
+        let error = copilot_chat
+            .update(cx, |copilot_chat, cx| {
+                copilot_chat.verify_oauth_token(None, cx)
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Copilot Chat API error (403 Forbidden): Your Copilot subscription has expired"
+        );
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(!copilot_chat.is_authenticated());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_oauth_token_is_discarded_only_when_rejected(cx: &mut TestAppContext) {
+        // The first request fails transiently, and the rest are rejected.
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create(move |_| {
+            let status = match token_requests.fetch_add(1, SeqCst) {
+                0 => 503,
+                _ => 401,
+            };
+            async move {
+                Ok(Response::builder()
+                    .status(status)
+                    .body(AsyncBody::empty())
+                    .unwrap())
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat {
+            api_token_retry_policy: RetryPolicy {
+                max_retries: 0,
+                base_delay: Duration::from_secs(1),
+            },
+            ..CopilotChat::fake(client)
+        });
+
+        // A transient failure, e.g. during a completion, keeps the user signed in.
+        copilot_chat
+            .update(cx, |copilot_chat, cx| {
+                copilot_chat.refresh_api_token(None, cx)
+            })
+            .await
+            .unwrap_err();
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.is_authenticated());
+        });
+
+        // A rejected OAuth token signs them out.
+        copilot_chat
+            .update(cx, |copilot_chat, cx| {
+                copilot_chat.refresh_api_token(None, cx)
+            })
+            .await
+            .unwrap_err();
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(!copilot_chat.is_authenticated());
+        });
+
+        // Trying the rejected token again signs them out again when it's still rejected.
+        copilot_chat
+            .update(cx, |copilot_chat, cx| {
+                copilot_chat.retry_rejected_oauth_token(None, cx)
+            })
+            .await
+            .unwrap_err();
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(!copilot_chat.is_authenticated());
+            assert!(copilot_chat.rejected_oauth_token.is_some());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_oauth_token_changes_are_emitted(cx: &mut TestAppContext) {
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(FakeHttpClient::with_404_response()));
+        let changes = Arc::new(AtomicUsize::new(0));
+        let _subscription = cx.update(|cx| {
+            let changes = changes.clone();
+            cx.subscribe(&copilot_chat, move |_, event, _| match event {
+                Event::OAuthTokenChanged => {
+                    changes.fetch_add(1, SeqCst);
+                }
+            })
+        });
+
+        copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.api_token = Some(ApiToken {
+                api_key: "api-key".into(),
+                expires_at: Utc::now() + chrono::Duration::minutes(30),
+            });
+            copilot_chat.set_oauth_token(Some("fake-oauth-token".into()), cx);
+        });
+        cx.run_until_parked();
+        assert_eq!(changes.load(SeqCst), 0);
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.api_token.is_some());
+        });
+
+        copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.set_oauth_token(Some("other-oauth-token".into()), cx);
+        });
+        cx.run_until_parked();
+        assert_eq!(changes.load(SeqCst), 1);
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.api_token.is_none());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_api_token_request_is_retried_after_transient_failures(cx: &mut TestAppContext) {
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let token_requests = token_requests.clone();
+            move |_| {
+                let attempt = token_requests.fetch_add(1, SeqCst);
+                async move {
+                    match attempt {
+                        0 => Ok(Response::builder()
+                            .status(503)
+                            .body(AsyncBody::from("Service Unavailable"))
+                            .unwrap()),
+                        1 => Err(anyhow!("connection reset by peer")),
+                        _ => Ok(Response::builder()
+                            .status(200)
+                            .body(AsyncBody::from(format!(
+                                r#"{{"token":"api-key","expires_at":{}}}"#,
+                                Utc::now().timestamp() + 30 * 60
+                            )))
+                            .unwrap()),
+                    }
+                }
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat {
+            api_token_retry_policy: RetryPolicy {
+                max_retries: 3,
+                base_delay: Duration::from_secs(1),
+            },
+            ..CopilotChat::fake(client)
+        });
+        let executor = cx.executor();
+
+        let token = copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.refresh_api_token(None, cx)
+        });
+        let token = executor.spawn(token);
+
+        executor.run_until_parked();
+        assert_eq!(token_requests.load(SeqCst), 1);
+        // Each delay is followed by up to 50% jitter.
+        executor.advance_clock(Duration::from_millis(1500));
+        assert_eq!(token_requests.load(SeqCst), 2);
+        // The delay doubles after each attempt.
+        executor.advance_clock(Duration::from_millis(1900));
+        assert_eq!(token_requests.load(SeqCst), 2);
+        executor.advance_clock(Duration::from_millis(1100));
+        assert_eq!(token_requests.load(SeqCst), 3);
+
+        assert_eq!(token.await.unwrap().api_key, "api-key");
+        copilot_chat.read_with(cx, |copilot_chat, _| {
+            assert!(copilot_chat.api_token.is_some());
+        });
+    }
+
+    #[test]
+    fn test_connection_errors_mention_the_proxy() {
+        let client = Arc::new(HttpClientWithProxy::new_uri(
+            FakeHttpClient::create(|_| async move { Err(anyhow!("Connection refused")) }),
+            Some("http://proxy.example.com:8080".parse().unwrap()),
+        ));
+
+        let error = smol::block_on(request_api_token(
+            "oauth-token",
+            &EditorInfo::default(),
+            client,
+            None,
+        ))
+        .unwrap_err();
+        assert!(error.to_string().starts_with(
+            "Failed to connect to Copilot Chat through the proxy at http://proxy.example.com:8080"
+        ));
+        assert_eq!(error.root_cause().to_string(), "Connection refused");
+    }
+
+    #[gpui::test]
+    async fn test_unauthorized_api_token_request_is_not_retried(cx: &mut TestAppContext) {
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let token_requests = token_requests.clone();
+            move |_| {
+                token_requests.fetch_add(1, SeqCst);
+                async move {
+                    Ok(Response::builder()
+                        .status(401)
+                        .body(AsyncBody::from("Unauthorized"))
+                        .unwrap())
+                }
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat {
+            api_token_retry_policy: RetryPolicy {
+                max_retries: 3,
+                base_delay: Duration::from_secs(1),
+            },
+            ..CopilotChat::fake(client)
+        });
+
+        let error = copilot_chat
+            .update(cx, |copilot_chat, cx| {
+                copilot_chat.refresh_api_token(None, cx)
+            })
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<ApiError>().unwrap().is_unauthorized());
+        assert_eq!(token_requests.load(SeqCst), 1);
+    }
+
+    #[gpui::test]
+    async fn test_rejected_api_token_is_replaced_once(cx: &mut TestAppContext) {
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let completion_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let token_requests = token_requests.clone();
+            let completion_requests = completion_requests.clone();
+            move |request| {
+                let (status, body) = if request.uri().to_string() == COPILOT_CHAT_AUTH_URL {
+                    // The token claims to be valid for half an hour, but the first one has
+                    // already been invalidated.
+                    let token = token_requests.fetch_add(1, SeqCst) + 1;
+                    let body = format!(
+                        r#"{{"token":"api-key-{token}","expires_at":{}}}"#,
+                        Utc::now().timestamp() + 30 * 60
+                    );
+                    (200, body)
+                } else {
+                    completion_requests.fetch_add(1, SeqCst);
+                    if request.headers()["Authorization"] == "Bearer api-key-1" {
+                        (401, "Unauthorized".to_string())
+                    } else {
+                        (200, "data: [DONE]\n".to_string())
+                    }
+                };
+                async move {
+                    Ok(Response::builder()
+                        .status(status)
+                        .body(AsyncBody::from(body))
+                        .unwrap())
+                }
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(client));
+        cx.update(|cx| CopilotChat::set_global(copilot_chat, cx));
+
+        let stream =
+            CopilotChat::stream_completion(test_request(), "request-id", None, cx.to_async())
+                .await
+                .unwrap();
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 0);
+        assert_eq!(token_requests.load(SeqCst), 2);
+        assert_eq!(completion_requests.load(SeqCst), 2);
+    }
+
+    #[gpui::test]
+    async fn test_rejected_api_token_is_not_replaced_twice(cx: &mut TestAppContext) {
+        let completion_requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let completion_requests = completion_requests.clone();
+            move |request| {
+                let (status, body) = if request.uri().to_string() == COPILOT_CHAT_AUTH_URL {
+                    let body = format!(
+                        r#"{{"token":"api-key","expires_at":{}}}"#,
+                        Utc::now().timestamp() + 30 * 60
+                    );
+                    (200, body)
+                } else {
+                    completion_requests.fetch_add(1, SeqCst);
+                    (401, "Unauthorized".to_string())
+                };
+                async move {
+                    Ok(Response::builder()
+                        .status(status)
+                        .body(AsyncBody::from(body))
+                        .unwrap())
+                }
+            }
+        });
+        let copilot_chat = cx.new_model(|_| CopilotChat::fake(client));
+        cx.update(|cx| CopilotChat::set_global(copilot_chat, cx));
+
+        let error =
+            CopilotChat::stream_completion(test_request(), "request-id", None, cx.to_async())
+                .await
+                .err()
+                .unwrap();
+        assert_eq!(
+            error.downcast_ref::<ApiError>().unwrap().status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(completion_requests.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_api_error_message() {
+        let error = ApiError::new(
+            StatusCode::FORBIDDEN,
+            r#"{"error":{"message":"Your Copilot subscription has expired","code":"forbidden"}}"#
+                .into(),
+        );
+        assert!(error.is_unauthorized());
+        assert!(!error.is_retryable());
+        assert_eq!(
+            error.to_string(),
+            "Copilot Chat API error (403 Forbidden): Your Copilot subscription has expired"
+        );
+
+        let error = ApiError::new(StatusCode::BAD_GATEWAY, "Bad Gateway".into());
+        assert!(!error.is_unauthorized());
+        assert!(error.is_retryable());
+        assert_eq!(
+            error.to_string(),
+            "Copilot Chat API error (502 Bad Gateway): Bad Gateway"
+        );
+    }
+
+    #[test]
+    fn test_content_filtered_error() {
+        let error = ApiError::new(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":{"message":"The prompt was filtered","code":"content_filter"}}"#.into(),
+        );
+        assert!(error.is_content_filtered());
+
+        // Only the error's code counts, not text that mentions it.
+        let error = ApiError::new(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":{"message":"Unknown parameter content_filter","code":"invalid_request"}}"#
+                .into(),
+        );
+        assert!(!error.is_content_filtered());
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let client = FakeHttpClient::create(|_| async move {
+            Ok(Response::builder()
+                .status(429)
+                .header("Retry-After", "30")
+                .body(AsyncBody::from("Too Many Requests"))
+                .unwrap())
+        });
+
+        let error = smol::block_on(stream_completion(
+            client,
+            COPILOT_CHAT_API_URL,
+            "api-key".into(),
+            &EditorInfo::default(),
+            test_request(),
+            "request-id",
+            None,
+            false,
+        ))
+        .err()
+        .unwrap();
+        let error = error.downcast_ref::<ApiError>().unwrap();
+        assert_eq!(error.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.retry_after, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_client_headers() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.headers()["X-Request-Id"], "request-id");
+            assert_eq!(request.headers()["Editor-Version"], "Zed/1.2.3");
+            assert!(request.headers()["User-Agent"]
+                .to_str()
+                .unwrap()
+                .starts_with("Zed/1.2.3 ("));
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from("data: [DONE]\n"))
+                .unwrap())
+        });
+
+        smol::block_on(stream_completion(
+            client,
+            COPILOT_CHAT_API_URL,
+            "api-key".into(),
+            &EditorInfo::new("1.2.3"),
+            test_request(),
+            "request-id",
+            None,
+            false,
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_unknown_model_serialization() {
+        let model = Model::from_api_id("o1-mini");
+        assert_eq!(model, Model::Other("o1-mini".into()));
+        assert_eq!(
+            serde_json::to_value(&model).unwrap(),
+            serde_json::json!("o1-mini")
+        );
+        assert_eq!(
+            serde_json::from_value::<Model>(serde_json::json!("o1-mini")).unwrap(),
+            model
+        );
+        assert_eq!(
+            serde_json::from_value::<Model>(serde_json::json!("gpt-4")).unwrap(),
+            Model::Gpt4
+        );
+        assert_eq!(Model::iter().count(), 3);
+    }
+
+    #[test]
+    fn test_reasoning_models_listed_by_the_api() {
+        for id in ["o1", "o1-mini", "o1-preview", "o3-mini"] {
+            let model = serde_json::from_value::<Model>(serde_json::json!(id)).unwrap();
+            assert!(model.is_reasoning_model(), "{id}");
+        }
+        for id in ["gpt-4o-mini", "claude-3.5-sonnet", "o100"] {
+            let model = serde_json::from_value::<Model>(serde_json::json!(id)).unwrap();
+            assert!(!model.is_reasoning_model(), "{id}");
+        }
+        assert!(!Model::Gpt4o.is_reasoning_model());
+    }
+
+    #[test]
+    fn test_message_content_serialization() {
+        assert_eq!(
+            serde_json::to_value(ChatMessage::new(Role::User, "Hi")).unwrap(),
+            serde_json::json!({ "role": "user", "content": "Hi" })
+        );
+        assert_eq!(
+            serde_json::to_value(ChatMessage::new(
+                Role::User,
+                ChatMessageContent::Multipart(vec![
+                    ChatMessagePart::Text {
+                        text: "What's this?".into()
+                    },
+                    ChatMessagePart::ImageUrl {
+                        image_url: ImageUrl {
+                            url: "data:image/png;base64,aGk=".into()
+                        }
+                    },
+                ])
+            ))
+            .unwrap(),
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What's this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,aGk=" } },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_api_token_credentials() {
+        let token = ApiToken {
+            api_key: "api-key".into(),
+            expires_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        };
+        let (username, password) = token.to_credentials("oauth-token");
+
+        let restored =
+            ApiToken::from_credentials("oauth-token", &username, password.clone()).unwrap();
+        assert_eq!(restored.api_key, token.api_key);
+        assert_eq!(restored.expires_at, token.expires_at);
+
+        // Tokens issued for another account are never restored.
+        assert!(ApiToken::from_credentials("other-oauth-token", &username, password).is_none());
+    }
+
+    #[test]
+    fn test_retry_after_http_date() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:27:30 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
 }
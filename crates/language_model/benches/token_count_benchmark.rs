@@ -0,0 +1,55 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use language_model::provider::copilot_chat::{
+    estimate_token_count, TOKEN_COUNT_FAST_PATH_MAX_CHARS,
+};
+use language_model::{LanguageModelRequest, LanguageModelRequestMessage, MessageContent, Role};
+
+fn generate_request(char_count: usize) -> LanguageModelRequest {
+    LanguageModelRequest {
+        messages: vec![LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text("x".repeat(char_count))],
+            cache: false,
+        }],
+        ..Default::default()
+    }
+}
+
+fn count_with_tokenizer(request: &LanguageModelRequest) -> usize {
+    let messages = request
+        .messages
+        .iter()
+        .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+            role: "user".into(),
+            content: Some(message.string_contents()),
+            name: None,
+            function_call: None,
+        })
+        .collect::<Vec<_>>();
+    tiktoken_rs::num_tokens_from_messages("gpt-4o", &messages).unwrap()
+}
+
+fn token_count_benchmark(c: &mut Criterion) {
+    let sizes = [16, 64, TOKEN_COUNT_FAST_PATH_MAX_CHARS - 1];
+
+    let mut group = c.benchmark_group("estimate");
+    for size in sizes.iter() {
+        let request = generate_request(*size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &request, |b, request| {
+            b.iter(|| estimate_token_count(black_box(request)));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("tokenizer");
+    for size in sizes.iter() {
+        let request = generate_request(*size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &request, |b, request| {
+            b.iter(|| count_with_tokenizer(black_box(request)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, token_count_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,174 @@
+use crate::{LanguageModelCompletionEvent, LanguageModelError};
+use anyhow::{anyhow, Result};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// Shares a completion's events between any number of subscribers, e.g. to render and
+/// persist a response at the same time.
+///
+/// Each subscriber receives every event from the start of the completion, however late it
+/// subscribes, so the events are buffered until the broadcast and its subscribers are dropped.
+/// The completion is only read as fast as its fastest subscriber reads it.
+pub fn broadcast_completion(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+) -> CompletionBroadcast {
+    CompletionBroadcast {
+        state: Arc::new(Mutex::new(BroadcastState {
+            events: Some(events),
+            received: Vec::new(),
+            wakers: Vec::new(),
+        })),
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionBroadcast {
+    state: Arc<Mutex<BroadcastState>>,
+}
+
+impl CompletionBroadcast {
+    /// Returns a stream of the completion's events, starting from the first one.
+    pub fn subscribe(&self) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+        Subscriber {
+            state: self.state.clone(),
+            next_ix: 0,
+        }
+        .boxed()
+    }
+}
+
+struct BroadcastState {
+    /// The completion's events, until it has finished.
+    events: Option<BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+    received: Vec<Result<LanguageModelCompletionEvent, Arc<anyhow::Error>>>,
+    /// The subscribers waiting for the next event.
+    wakers: Vec<Waker>,
+}
+
+impl BroadcastState {
+    fn wake_subscribers(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+struct Subscriber {
+    state: Arc<Mutex<BroadcastState>>,
+    next_ix: usize,
+}
+
+impl Stream for Subscriber {
+    type Item = Result<LanguageModelCompletionEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let state = self.state.clone();
+        let mut state = state.lock();
+        if let Some(event) = state.received.get(self.next_ix) {
+            self.next_ix += 1;
+            return Poll::Ready(Some(clone_event(event)));
+        }
+
+        let Some(events) = state.events.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match events.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => {
+                let event = event.map_err(Arc::new);
+                let cloned_event = clone_event(&event);
+                state.received.push(event);
+                state.wake_subscribers();
+                self.next_ix += 1;
+                Poll::Ready(Some(cloned_event))
+            }
+            Poll::Ready(None) => {
+                state.events = None;
+                state.wake_subscribers();
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                // The completion only wakes whoever polled it last, who passes it on.
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        // The completion may have been waiting to wake this subscriber, so another one has
+        // to poll it instead.
+        self.state.lock().wake_subscribers();
+    }
+}
+
+/// Copies an event for a subscriber. Errors can't be cloned, so each subscriber gets one
+/// with the same message and category.
+fn clone_event(
+    event: &Result<LanguageModelCompletionEvent, Arc<anyhow::Error>>,
+) -> Result<LanguageModelCompletionEvent> {
+    match event {
+        Ok(event) => Ok(event.clone()),
+        Err(error) => {
+            let cloned_error = anyhow!("{error}");
+            Err(match error.downcast_ref::<LanguageModelError>() {
+                Some(category) => category.clone().attach(cloned_error),
+                None => cloned_error,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StopReason;
+
+    #[test]
+    fn test_broadcast_completion() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let broadcast = broadcast_completion(rx.boxed());
+        let mut early = broadcast.subscribe();
+
+        smol::block_on(async {
+            tx.unbounded_send(Ok(LanguageModelCompletionEvent::Text("Hello".into())))
+                .unwrap();
+            assert_eq!(
+                early.next().await.unwrap().unwrap(),
+                LanguageModelCompletionEvent::Text("Hello".into())
+            );
+
+            tx.unbounded_send(Err(
+                LanguageModelError::Network.attach(anyhow!("connection reset"))
+            ))
+            .unwrap();
+            tx.unbounded_send(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+                .unwrap();
+            drop(tx);
+
+            for subscriber in [early, broadcast.subscribe()] {
+                let events = subscriber.collect::<Vec<_>>().await;
+                let error = events[events.len() - 2].as_ref().unwrap_err();
+                assert_eq!(error.to_string(), "connection reset");
+                assert_eq!(
+                    error.downcast_ref::<LanguageModelError>(),
+                    Some(&LanguageModelError::Network)
+                );
+                assert!(matches!(
+                    events.last(),
+                    Some(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+                ));
+            }
+        });
+
+        let late = smol::block_on(broadcast.subscribe().collect::<Vec<_>>());
+        assert_eq!(late.len(), 3);
+    }
+}
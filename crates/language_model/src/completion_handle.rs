@@ -0,0 +1,129 @@
+use crate::LanguageModelCompletionEvent;
+use anyhow::Result;
+use futures::stream::{AbortHandle, BoxStream};
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// A completion being streamed, returned by
+/// [`LanguageModel::stream_completion_with_handle`](crate::LanguageModel::stream_completion_with_handle).
+///
+/// It streams the same events as the stream returned by `stream_completion`, but it can also be
+/// cancelled and asked how much of the response has arrived. That can be done from elsewhere,
+/// such as the UI, through its [`CompletionProgress`], while the handle itself is being iterated.
+pub struct CompletionHandle {
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    progress: CompletionProgress,
+}
+
+impl CompletionHandle {
+    pub fn new(events: BoxStream<'static, Result<LanguageModelCompletionEvent>>) -> Self {
+        let (events, abort_handle) = futures::stream::abortable(events);
+        let progress = CompletionProgress {
+            abort_handle,
+            bytes_received: Arc::default(),
+            completion_tokens: Arc::default(),
+        };
+        let events = events
+            .inspect({
+                let progress = progress.clone();
+                move |event| match event {
+                    Ok(LanguageModelCompletionEvent::Text(text)) => {
+                        progress.bytes_received.fetch_add(text.len(), SeqCst);
+                    }
+                    Ok(LanguageModelCompletionEvent::Usage {
+                        completion_tokens, ..
+                    }) => *progress.completion_tokens.lock() = Some(*completion_tokens),
+                    _ => {}
+                }
+            })
+            .boxed();
+        Self { events, progress }
+    }
+
+    pub fn progress(&self) -> CompletionProgress {
+        self.progress.clone()
+    }
+
+    /// Ends the stream and aborts the request. See [`CompletionProgress::cancel`].
+    pub fn cancel(&self) {
+        self.progress.cancel();
+    }
+}
+
+impl Stream for CompletionHandle {
+    type Item = Result<LanguageModelCompletionEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+/// Tracks a [`CompletionHandle`]'s response, and can cancel it.
+#[derive(Clone)]
+pub struct CompletionProgress {
+    abort_handle: AbortHandle,
+    bytes_received: Arc<AtomicUsize>,
+    completion_tokens: Arc<Mutex<Option<usize>>>,
+}
+
+impl CompletionProgress {
+    /// Ends the completion's stream. The request is aborted once the stream is dropped.
+    pub fn cancel(&self) {
+        self.abort_handle.abort();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.abort_handle.is_aborted()
+    }
+
+    /// The number of bytes of text the response has streamed so far.
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received.load(SeqCst)
+    }
+
+    /// The number of tokens the response consumed, once the provider has reported it.
+    pub fn completion_tokens(&self) -> Option<usize> {
+        *self.completion_tokens.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StopReason;
+
+    #[test]
+    fn test_completion_handle() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut handle = CompletionHandle::new(rx.boxed());
+        let progress = handle.progress();
+
+        smol::block_on(async {
+            tx.unbounded_send(Ok(LanguageModelCompletionEvent::Text("Hello".into())))
+                .unwrap();
+            tx.unbounded_send(Ok(LanguageModelCompletionEvent::Usage {
+                prompt_tokens: 10,
+                completion_tokens: 1,
+            }))
+            .unwrap();
+            handle.next().await.unwrap().unwrap();
+            handle.next().await.unwrap().unwrap();
+            assert_eq!(progress.bytes_received(), 5);
+            assert_eq!(progress.completion_tokens(), Some(1));
+
+            progress.cancel();
+            tx.unbounded_send(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+                .unwrap();
+            assert!(handle.next().await.is_none());
+            assert!(progress.is_cancelled());
+        });
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    LanguageModelId, LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
+};
+use anyhow::{anyhow, Result};
+use futures::{future::BoxFuture, FutureExt};
+use gpui::AsyncAppContext;
+
+/// A model that maps text to a vector, so that texts with similar meanings can be found by
+/// comparing their vectors, e.g. for semantic search.
+///
+/// Providers that serve embedding models return them from
+/// [`LanguageModelProvider::embedding_models`](crate::LanguageModelProvider::embedding_models),
+/// alongside their chat models, and authenticate their requests the same way.
+pub trait EmbeddingModel: Send + Sync {
+    fn id(&self) -> LanguageModelId;
+    fn name(&self) -> LanguageModelName;
+    fn provider_id(&self) -> LanguageModelProviderId;
+    fn provider_name(&self) -> LanguageModelProviderName;
+
+    /// The most texts the provider's API accepts in a single request.
+    fn max_batch_size(&self) -> usize;
+
+    /// Embeds at most [`EmbeddingModel::max_batch_size`] texts in a single request.
+    fn embed_batch(
+        &self,
+        texts: Vec<String>,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>>;
+
+    /// Returns the embeddings of `texts`, in the same order, sending them in as many requests
+    /// as [`EmbeddingModel::max_batch_size`] requires.
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let text_count = texts.len();
+        let batches = texts
+            .chunks(self.max_batch_size().max(1))
+            .map(|batch| (batch.len(), self.embed_batch(batch.to_vec(), cx)))
+            .collect::<Vec<_>>();
+
+        async move {
+            let mut embeddings = Vec::with_capacity(text_count);
+            for (batch_size, batch) in batches {
+                let batch = batch.await?;
+                if batch.len() != batch_size {
+                    return Err(anyhow!(
+                        "Expected {batch_size} embeddings, but received {}",
+                        batch.len()
+                    ));
+                }
+                embeddings.extend(batch);
+            }
+            Ok(embeddings)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Embeds each text as its length, recording the size of each batch it's asked to embed.
+    #[derive(Default)]
+    struct LengthEmbeddingModel {
+        batch_sizes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl EmbeddingModel for LengthEmbeddingModel {
+        fn id(&self) -> LanguageModelId {
+            LanguageModelId::from("length".to_string())
+        }
+
+        fn name(&self) -> LanguageModelName {
+            LanguageModelName::from("Length".to_string())
+        }
+
+        fn provider_id(&self) -> LanguageModelProviderId {
+            LanguageModelProviderId::from("test".to_string())
+        }
+
+        fn provider_name(&self) -> LanguageModelProviderName {
+            LanguageModelProviderName::from("Test".to_string())
+        }
+
+        fn max_batch_size(&self) -> usize {
+            2
+        }
+
+        fn embed_batch(
+            &self,
+            texts: Vec<String>,
+            _: &AsyncAppContext,
+        ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+            self.batch_sizes.lock().push(texts.len());
+            let embeddings = texts.iter().map(|text| vec![text.len() as f32]).collect();
+            futures::future::ready(Ok(embeddings)).boxed()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_embed_splits_texts_into_batches(cx: &mut TestAppContext) {
+        let model = LengthEmbeddingModel::default();
+        let texts = ["a", "bb", "ccc", "dddd", "eeeee"]
+            .map(String::from)
+            .to_vec();
+        let embeddings = model.embed(texts, &cx.to_async()).await.unwrap();
+        assert_eq!(
+            embeddings,
+            [[1.], [2.], [3.], [4.], [5.]].map(|embedding| embedding.to_vec())
+        );
+        assert_eq!(*model.batch_sizes.lock(), [2, 2, 1]);
+
+        assert_eq!(
+            model.embed(Vec::new(), &cx.to_async()).await.unwrap(),
+            Vec::<Vec<f32>>::new()
+        );
+    }
+}
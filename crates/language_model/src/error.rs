@@ -0,0 +1,74 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Why a request to a language model failed, in terms callers can react to without
+/// matching on error messages.
+///
+/// Providers attach it to the errors returned by [`crate::LanguageModel::stream_completion`]
+/// and [`crate::LanguageModelProvider::authenticate`] with [`LanguageModelError::attach`],
+/// so it can be retrieved with `error.downcast_ref::<LanguageModelError>()`. Errors that
+/// don't fit any category are returned without one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LanguageModelError {
+    /// The user isn't signed in, or their credentials were rejected.
+    NotAuthenticated,
+    /// The provider is limiting the rate of requests.
+    RateLimited {
+        /// How long the provider asked us to wait before retrying, if it said.
+        retry_after: Option<Duration>,
+    },
+    /// The request didn't complete in time.
+    Timeout,
+    /// The prompt doesn't fit in the model's context window.
+    ContextWindowExceeded,
+    /// The provider refused the prompt or the response because of its content.
+    ContentFiltered,
+    /// The provider couldn't be reached.
+    Network,
+    /// The connection was lost while the response was being streamed.
+    Interrupted {
+        /// The text of the response received before the connection was lost, which callers
+        /// can keep and ask the model to continue from.
+        partial: String,
+    },
+    /// The provider returned an error that doesn't fit any other category.
+    ApiError { status: u16, message: String },
+}
+
+impl LanguageModelError {
+    /// Attaches this category to `error`, which keeps its own message.
+    pub fn attach(self, error: anyhow::Error) -> anyhow::Error {
+        // An error's message is that of its outermost context, so the original message
+        // is repeated on top of the category.
+        let message = error.to_string();
+        error.context(self).context(message)
+    }
+}
+
+impl fmt::Display for LanguageModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAuthenticated => write!(f, "not authenticated"),
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "rate limited, retry after {} seconds",
+                retry_after.as_secs()
+            ),
+            Self::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::ContextWindowExceeded => write!(f, "context window exceeded"),
+            Self::ContentFiltered => write!(f, "content filtered"),
+            Self::Network => write!(f, "network error"),
+            Self::Interrupted { partial } => write!(
+                f,
+                "response interrupted after {} characters",
+                partial.chars().count()
+            ),
+            Self::ApiError { status, message } => write!(f, "API error ({status}): {message}"),
+        }
+    }
+}
+
+impl std::error::Error for LanguageModelError {}
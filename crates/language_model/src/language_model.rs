@@ -1,3 +1,7 @@
+mod completion_broadcast;
+mod completion_handle;
+mod embedding_model;
+mod error;
 mod model;
 pub mod provider;
 mod rate_limiter;
@@ -8,6 +12,10 @@ pub mod settings;
 
 use anyhow::Result;
 use client::{Client, UserStore};
+pub use completion_broadcast::*;
+pub use completion_handle::*;
+pub use embedding_model::*;
+pub use error::*;
 use futures::FutureExt;
 use futures::{future::BoxFuture, stream::BoxStream, StreamExt, TryStreamExt as _};
 use gpui::{
@@ -23,6 +31,7 @@ pub use role::*;
 use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
 use std::{future::Future, sync::Arc};
 use ui::IconName;
 
@@ -36,6 +45,14 @@ pub fn init(
     registry::init(user_store, client, cx);
 }
 
+/// The number of tokens each message adds to a prompt beyond those of its contents, for the
+/// role markers around it, in models that use OpenAI's tokenizers.
+///
+/// For those models, a prompt's token count is close to the sum of its messages' counts from
+/// [`LanguageModel::count_tokens_for_message`], plus this overhead for each message, plus
+/// another 3 tokens per prompt to prime the reply.
+pub const MESSAGE_TOKEN_OVERHEAD: usize = 3;
+
 /// The availability of a [`LanguageModel`].
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum LanguageModelAvailability {
@@ -45,6 +62,20 @@ pub enum LanguageModelAvailability {
     RequiresPlan(Plan),
 }
 
+/// What a [`LanguageModel`] is suited for, so that models can be grouped in the model picker.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ModelCategory {
+    /// Cheaper and quicker to respond, at the cost of quality.
+    Fast,
+    /// A general-purpose model.
+    #[default]
+    Balanced,
+    /// Thinks before it responds, which makes it slower but better at complex tasks.
+    Reasoning,
+    /// Superseded by newer models, and kept for those who still rely on it.
+    Legacy,
+}
+
 /// Configuration for caching language model messages.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct LanguageModelCacheConfiguration {
@@ -53,12 +84,123 @@ pub struct LanguageModelCacheConfiguration {
     pub min_total_token: usize,
 }
 
+/// The fraction of a model's context window that a prompt can fill before the user is
+/// warned that it's approaching the limit, unless its provider configures another one.
+pub const CONTEXT_WARNING_THRESHOLD: f32 = 0.9;
+
+/// How a request's prompt compares to the size of a model's context window, as returned by
+/// [`LanguageModel::would_exceed_context`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextFit {
+    pub token_count: usize,
+    pub max_token_count: usize,
+    /// Whether the prompt fits in the context window.
+    pub fits: bool,
+}
+
+impl ContextFit {
+    /// Whether the prompt fills more than `threshold` of the context window, e.g.
+    /// [`LanguageModel::context_warning_threshold`], so that the user can be warned or offered to truncate
+    /// it before it no longer fits.
+    pub fn exceeds(&self, threshold: f32) -> bool {
+        self.token_count as f64 > self.max_token_count as f64 * threshold as f64
+    }
+}
+
 /// A completion event from a language model.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum LanguageModelCompletionEvent {
     Stop(StopReason),
     Text(String),
     ToolUse(LanguageModelToolUse),
+    /// A fragment of the name or arguments of the tool use at `index` in the response, emitted
+    /// as they're streamed by providers that stream them, so that its progress can be shown.
+    /// The arguments are incomplete JSON until the tool use is emitted as a [`Self::ToolUse`],
+    /// which is the only event a tool should be run in response to.
+    ToolUseDelta {
+        index: usize,
+        name_delta: String,
+        arguments_delta: String,
+    },
+    /// The number of tokens consumed by the request, as reported by the provider.
+    Usage {
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    },
+    /// An estimate of the number of tokens the response has used so far, emitted
+    /// periodically while it's streamed by providers that make one. The final count is
+    /// reported by [`Self::Usage`].
+    PartialUsage {
+        completion_tokens: usize,
+    },
+    /// The provider started responding, `latency` after the completion was requested.
+    /// Emitted first, by providers that measure it.
+    Connected {
+        latency: Duration,
+    },
+    /// The first text or tool use of the response arrived, `latency` after the completion
+    /// was requested. Emitted just before it, by providers that measure it.
+    FirstToken {
+        latency: Duration,
+    },
+    /// The ID of the model that served the request, which may differ from the model that
+    /// was requested. Emitted once, by providers that report it.
+    ResponseModel {
+        id: String,
+    },
+    /// Identifies the backend configuration that served the request. Responses to requests
+    /// with the same seed are only expected to match while it's unchanged. Emitted once, by
+    /// providers that report it.
+    SystemFingerprint {
+        fingerprint: String,
+    },
+    /// The requested model was unavailable, so the completion was requested from the model
+    /// with ID `model_id` instead, whose events follow. Emitted first, by providers configured
+    /// to fall back to another model.
+    Fallback {
+        model_id: String,
+    },
+    /// An event of one of the candidate completions, when several were requested with
+    /// [`LanguageModelRequest::n`]. The text, tool uses and stop reason of each candidate
+    /// are wrapped in one of these.
+    Candidate {
+        choice_index: usize,
+        event: Box<LanguageModelCompletionEvent>,
+    },
+}
+
+impl LanguageModelCompletionEvent {
+    /// Wraps an event of the candidate at `choice_index` in a [`Self::Candidate`], if more
+    /// than one candidate was requested.
+    pub(crate) fn for_choice(self, choice_index: usize, choice_count: usize) -> Self {
+        if choice_count > 1 {
+            Self::Candidate {
+                choice_index,
+                event: Box::new(self),
+            }
+        } else {
+            self
+        }
+    }
+
+    /// Returns a [`Self::ToolUseDelta`] for a streamed fragment of a tool use, unless the
+    /// fragment adds nothing to its name or arguments.
+    pub(crate) fn tool_use_delta(
+        index: usize,
+        name_delta: Option<&str>,
+        arguments_delta: Option<&str>,
+    ) -> Option<Self> {
+        let name_delta = name_delta.unwrap_or_default();
+        let arguments_delta = arguments_delta.unwrap_or_default();
+        if name_delta.is_empty() && arguments_delta.is_empty() {
+            return None;
+        }
+        Some(Self::ToolUseDelta {
+            index,
+            name_delta: name_delta.to_string(),
+            arguments_delta: arguments_delta.to_string(),
+        })
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -67,6 +209,12 @@ pub enum StopReason {
     EndTurn,
     MaxTokens,
     ToolUse,
+    /// The response was cut short by the provider's content filter.
+    ContentFilter,
+    /// The response ended at one of the request's stop sequences.
+    StopSequence,
+    /// The response ended without the provider reporting why.
+    Unknown,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -92,17 +240,89 @@ pub trait LanguageModel: Send + Sync {
         LanguageModelAvailability::Public
     }
 
+    /// The category the model is listed under in the model picker. Models are
+    /// [`ModelCategory::Balanced`] unless their provider says otherwise.
+    fn category(&self) -> ModelCategory {
+        ModelCategory::default()
+    }
+
     fn max_token_count(&self) -> usize;
     fn max_output_tokens(&self) -> Option<u32> {
         None
     }
 
+    /// The price of a million prompt tokens in US dollars, for estimating the cost of a
+    /// completion from its [`LanguageModelCompletionEvent::Usage`]. `None` if unknown, or if
+    /// the model isn't billed per token.
+    fn input_price_per_million(&self) -> Option<f64> {
+        None
+    }
+
+    /// The price of a million completion tokens in US dollars. See
+    /// [`LanguageModel::input_price_per_million`].
+    fn output_price_per_million(&self) -> Option<f64> {
+        None
+    }
+
+    /// Whether the model accepts images in its requests.
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    /// Whether the model can be asked to call tools.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>>;
 
+    /// Counts the tokens in a single message, so that callers can cache each message's count
+    /// and sum them instead of recounting the whole prompt whenever it changes.
+    ///
+    /// By default, the message is counted as a prompt of its own. Models whose prompt counts
+    /// include a per-message overhead, like [`MESSAGE_TOKEN_OVERHEAD`], leave it out.
+    fn count_tokens_for_message(
+        &self,
+        message: LanguageModelRequestMessage,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        let request = LanguageModelRequest {
+            messages: vec![message],
+            ..Default::default()
+        };
+        self.count_tokens(request, cx)
+    }
+
+    /// Counts the request's tokens to check whether its prompt fits in the context window,
+    /// so that it can be shortened before it's sent rather than rejected by the API.
+    fn would_exceed_context(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<ContextFit>> {
+        let max_token_count = self.max_token_count();
+        let token_count = self.count_tokens(request, cx);
+        async move {
+            let token_count = token_count.await?;
+            Ok(ContextFit {
+                token_count,
+                max_token_count,
+                fits: token_count <= max_token_count,
+            })
+        }
+        .boxed()
+    }
+
+    /// The fraction of the context window a prompt can fill before the user is warned that
+    /// it's approaching the limit, to pass to [`ContextFit::exceeds`].
+    fn context_warning_threshold(&self, _cx: &AppContext) -> f32 {
+        CONTEXT_WARNING_THRESHOLD
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
@@ -124,6 +344,23 @@ pub trait LanguageModel: Send + Sync {
                         Ok(LanguageModelCompletionEvent::Text(text)) => Some(Ok(text)),
                         Ok(LanguageModelCompletionEvent::Stop(_)) => None,
                         Ok(LanguageModelCompletionEvent::ToolUse(_)) => None,
+                        Ok(LanguageModelCompletionEvent::ToolUseDelta { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::Usage { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::PartialUsage { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::Connected { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::FirstToken { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::ResponseModel { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::SystemFingerprint { .. }) => None,
+                        Ok(LanguageModelCompletionEvent::Fallback { .. }) => None,
+                        // Only the first candidate's text is streamed.
+                        Ok(LanguageModelCompletionEvent::Candidate {
+                            choice_index: 0,
+                            event,
+                        }) => match *event {
+                            LanguageModelCompletionEvent::Text(text) => Some(Ok(text)),
+                            _ => None,
+                        },
+                        Ok(LanguageModelCompletionEvent::Candidate { .. }) => None,
                         Err(err) => Some(Err(err)),
                     }
                 })
@@ -132,6 +369,50 @@ pub trait LanguageModel: Send + Sync {
         .boxed()
     }
 
+    /// Streams the rest of `prior_output`, the text `prior_request` produced before it ended
+    /// with [`StopReason::MaxTokens`]. See [`LanguageModelRequest::into_continuation`].
+    fn continue_completion(
+        &self,
+        prior_request: LanguageModelRequest,
+        prior_output: &str,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        self.stream_completion(prior_request.into_continuation(prior_output), cx)
+    }
+
+    /// Like [`LanguageModel::stream_completion`], but returns a [`CompletionHandle`], which
+    /// can also cancel the completion and report its progress.
+    fn stream_completion_with_handle(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<CompletionHandle>> {
+        let events = self.stream_completion(request, cx);
+        async move { Ok(CompletionHandle::new(events.await?)) }.boxed()
+    }
+
+    /// Requests a completion and returns its full text once the response has finished.
+    ///
+    /// If the response fails partway through, the error is returned and any text received
+    /// before it is discarded.
+    fn complete(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        let chunks = self.stream_completion_text(request, cx);
+
+        async move {
+            let mut chunks = chunks.await?;
+            let mut text = String::new();
+            while let Some(chunk) = chunks.next().await {
+                text.push_str(&chunk?);
+            }
+            Ok(text)
+        }
+        .boxed()
+    }
+
     fn use_any_tool(
         &self,
         request: LanguageModelRequest,
@@ -141,6 +422,20 @@ pub trait LanguageModel: Send + Sync {
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>>;
 
+    /// Returns the JSON body that [`LanguageModel::stream_completion`] would send for the
+    /// request, without sending it. Useful for debugging how prompts are constructed.
+    fn serialize_request(
+        &self,
+        _request: LanguageModelRequest,
+        _cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        futures::future::ready(Err(anyhow::anyhow!(
+            "{} doesn't support serializing requests",
+            self.name().0
+        )))
+        .boxed()
+    }
+
     fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
         None
     }
@@ -176,6 +471,48 @@ impl dyn LanguageModel {
         let schema_json = serde_json::to_value(&schema).unwrap();
         self.use_any_tool(request, T::name(), T::description(), schema_json, cx)
     }
+
+    /// Shortens a conversation that no longer fits in the context window, as reported by
+    /// [`LanguageModel::would_exceed_context`], by having the model summarize all but its last
+    /// `recent_turn_count` turns. See [`LanguageModelRequest::conversation_summary_request`].
+    ///
+    /// The request is returned unchanged, without asking the model for a summary, if there's
+    /// nothing before those turns to summarize.
+    pub fn compact_conversation(
+        &self,
+        request: LanguageModelRequest,
+        recent_turn_count: usize,
+        cx: &AsyncAppContext,
+    ) -> Task<Result<LanguageModelRequest>> {
+        let Some(summary_request) = request.conversation_summary_request(recent_turn_count) else {
+            return Task::ready(Ok(request));
+        };
+        let summary = self.complete(summary_request, cx);
+        cx.background_executor().spawn(async move {
+            let summary = summary.await?;
+            Ok(request.into_compacted(recent_turn_count, summary.trim()))
+        })
+    }
+
+    /// Streams a completion, calling `on_chunk` with each chunk of text as it arrives.
+    ///
+    /// The returned task resolves once the response has finished, or with the first error
+    /// encountered while streaming it.
+    pub fn stream_completion_with(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+        mut on_chunk: impl 'static + FnMut(String),
+    ) -> Task<Result<()>> {
+        let chunks = self.stream_completion_text(request, cx);
+        cx.spawn(|_| async move {
+            let mut chunks = chunks.await?;
+            while let Some(chunk) = chunks.next().await {
+                on_chunk(chunk?);
+            }
+            Ok(())
+        })
+    }
 }
 
 pub trait LanguageModelTool: 'static + DeserializeOwned + JsonSchema {
@@ -190,6 +527,14 @@ pub trait LanguageModelProvider: 'static {
         IconName::ZedAssistant
     }
     fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>>;
+    /// The models the provider serves for computing embeddings, which share its credentials.
+    fn embedding_models(&self, _cx: &AppContext) -> Vec<Arc<dyn EmbeddingModel>> {
+        Vec::new()
+    }
+    /// The model to select by default for new conversations, if the provider has one.
+    fn default_model(&self, _cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        None
+    }
     fn load_model(&self, _model: Arc<dyn LanguageModel>, _cx: &AppContext) {}
     fn is_authenticated(&self, cx: &AppContext) -> bool;
     fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>>;
@@ -201,6 +546,31 @@ pub trait LanguageModelProvider: 'static {
         None
     }
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>>;
+    /// The name of software that has to be installed separately for the provider to work,
+    /// such as an editor extension or a local server. `None` for providers that only need
+    /// credentials.
+    fn requires_external_dependency(&self) -> Option<&str> {
+        None
+    }
+    /// Checks whether the provider is currently usable, without running a completion.
+    ///
+    /// Providers without a cheap way to probe their service report themselves healthy.
+    fn check_health(&self, _cx: &mut AppContext) -> Task<Result<()>> {
+        Task::ready(Ok(()))
+    }
+    /// Cancels every completion of this provider's models that is still being requested
+    /// or streamed.
+    ///
+    /// Providers that don't track their completions leave them to be cancelled by dropping
+    /// their streams.
+    fn cancel_all(&self, _cx: &mut AppContext) {}
+    /// Does one-time work that would otherwise delay the first request, such as loading a
+    /// tokenizer, on a background thread.
+    ///
+    /// Called once the provider is registered. Providers without such work do nothing.
+    fn warm_up(&self, _cx: &mut AppContext) -> Task<()> {
+        Task::ready(())
+    }
 }
 
 pub trait LanguageModelProviderState: 'static {
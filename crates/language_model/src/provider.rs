@@ -4,5 +4,8 @@ pub mod copilot_chat;
 #[cfg(any(test, feature = "test-support"))]
 pub mod fake;
 pub mod google;
+#[cfg(any(test, feature = "test-support", feature = "mock-provider"))]
+pub mod mock;
 pub mod ollama;
 pub mod open_ai;
+pub mod openai_compatible;
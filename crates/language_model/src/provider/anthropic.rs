@@ -364,6 +364,22 @@ impl LanguageModel for AnthropicModel {
         Some(self.model.max_output_tokens())
     }
 
+    fn input_price_per_million(&self) -> Option<f64> {
+        self.model.input_price_per_million()
+    }
+
+    fn output_price_per_million(&self) -> Option<f64> {
+        self.model.output_price_per_million()
+    }
+
+    fn supports_images(&self) -> bool {
+        true
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -377,6 +393,9 @@ impl LanguageModel for AnthropicModel {
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        if let Err(error) = request.reject_response_format(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
         let request = request.into_anthropic(
             self.model.id().into(),
             self.model.default_temperature(),
@@ -522,6 +541,7 @@ pub fn map_to_language_model_completion_events(
                                     "end_turn" => StopReason::EndTurn,
                                     "max_tokens" => StopReason::MaxTokens,
                                     "tool_use" => StopReason::ToolUse,
+                                    "stop_sequence" => StopReason::StopSequence,
                                     _ => StopReason::EndTurn,
                                 };
 
@@ -458,6 +458,15 @@ impl LanguageModel for CloudLanguageModel {
         self.model.max_token_count()
     }
 
+    fn supports_images(&self) -> bool {
+        // Images are only sent to the model for Anthropic requests.
+        matches!(self.model, CloudModel::Anthropic(_))
+    }
+
+    fn supports_tools(&self) -> bool {
+        !matches!(self.model, CloudModel::Google(_))
+    }
+
     fn cache_configuration(&self) -> Option<LanguageModelCacheConfiguration> {
         match &self.model {
             CloudModel::Anthropic(model) => {
@@ -509,6 +518,9 @@ impl LanguageModel for CloudLanguageModel {
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
         let openai_low_speed_timeout =
             AllLanguageModelSettings::try_read_global(cx, |s| s.openai.low_speed_timeout.unwrap());
+        if let Err(error) = request.reject_response_format(self.model.display_name()) {
+            return future::ready(Err(error)).boxed();
+        }
 
         match &self.model {
             CloudModel::Anthropic(model) => {
@@ -542,6 +554,7 @@ impl LanguageModel for CloudLanguageModel {
             CloudModel::OpenAi(model) => {
                 let client = self.client.clone();
                 let request = request.into_open_ai(model.id().into(), model.max_output_tokens());
+                let choice_count = request.n.unwrap_or(1);
                 let llm_api_token = self.llm_api_token.clone();
                 let future = self.request_limiter.stream(async move {
                     let response = Self::perform_llm_completion(
@@ -557,17 +570,17 @@ impl LanguageModel for CloudLanguageModel {
                         openai_low_speed_timeout,
                     )
                     .await?;
-                    Ok(open_ai::extract_text_from_events(response_lines(response)))
+                    Ok(super::open_ai::map_to_language_model_completion_events(
+                        response_lines(response).boxed(),
+                        choice_count,
+                    ))
                 });
-                async move {
-                    Ok(future
-                        .await?
-                        .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                        .boxed())
-                }
-                .boxed()
+                async move { Ok(future.await?.boxed()) }.boxed()
             }
             CloudModel::Google(model) => {
+                if let Err(error) = request.reject_tools(model.display_name()) {
+                    return future::ready(Err(error)).boxed();
+                }
                 let client = self.client.clone();
                 let request = request.into_google(model.id().into());
                 let llm_api_token = self.llm_api_token.clone();
@@ -1,51 +1,185 @@
+use std::fmt;
 use std::future;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use client::telemetry::Telemetry;
+use collections::{BTreeMap, BTreeSet, HashMap};
 use copilot::copilot_chat::{
-    ChatMessage, CopilotChat, Model as CopilotChatModel, Request as CopilotChatRequest,
-    Role as CopilotChatRole,
+    with_completion_timeout, ApiError, ChatMessage, ChatMessageContent, ChatMessagePart,
+    CompletionTimeoutError, CopilotChat, EditorInfo, Event as CopilotChatEvent,
+    Function as CopilotChatFunction, FunctionContent, ImageUrl, JsonSchemaFormat,
+    Model as CopilotChatModel, ModelMetadata, NotSignedInError,
+    ReasoningEffort as CopilotChatReasoningEffort, Request as CopilotChatRequest, ResponseEvent,
+    ResponseFormat as CopilotChatResponseFormat, RetryPolicy, Role as CopilotChatRole,
+    Tool as CopilotChatTool, ToolCall, ToolCallContent,
 };
 use copilot::{Copilot, Status};
-use futures::future::BoxFuture;
-use futures::stream::BoxStream;
-use futures::{FutureExt, StreamExt};
+use futures::future::{
+    AbortHandle, AbortRegistration, Abortable, Aborted, BoxFuture, Either, LocalBoxFuture,
+};
+use futures::stream::{BoxStream, Fuse};
+use futures::{FutureExt, Stream, StreamExt};
 use gpui::{
-    percentage, svg, Animation, AnimationExt, AnyView, AppContext, AsyncAppContext, Model, Render,
-    Subscription, Task, Transformation,
+    percentage, svg, Animation, AnimationExt, AnyView, AppContext, AsyncAppContext,
+    BackgroundExecutor, EventEmitter, Model, ModelContext, Render, Subscription, Task,
+    Transformation,
 };
+use http_client::{HttpClient, HttpClientWithProxy, StatusCode, Uri, Url};
+use isahc_http_client::IsahcHttpClient;
+use parking_lot::Mutex;
+use rand::Rng as _;
+use release_channel::AppVersion;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use settings::{Settings, SettingsStore};
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use strum::IntoEnumIterator;
+use telemetry_events::{CompletionEvent, CompletionFailureReason};
 use ui::{
     div, h_flex, v_flex, Button, ButtonCommon, Clickable, Color, Context, FixedWidth, Icon,
     IconName, IconPosition, IconSize, IntoElement, Label, LabelCommon, ParentElement, Styled,
     ViewContext, VisualContext, WindowContext,
 };
+use util::{ResultExt, TryFutureExt};
+use uuid::Uuid;
 
 use crate::settings::AllLanguageModelSettings;
 use crate::{
-    LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
-    LanguageModelProviderId, LanguageModelProviderName, LanguageModelRequest, RateLimiter, Role,
+    is_language_instruction, LanguageModelCompletionEvent, LanguageModelProviderState,
+    LanguageModelRequestMessage, MessageContent, ModelCategory, StopReason,
+};
+use crate::{
+    EmbeddingModel, LanguageModel, LanguageModelError, LanguageModelId, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelRequest, RateLimiter, ReasoningEffort, ResponseFormat, Role,
 };
-use crate::{LanguageModelCompletionEvent, LanguageModelProviderState};
 
-use super::open_ai::count_open_ai_tokens;
+use super::open_ai::{
+    count_open_ai_message_tokens, count_open_ai_text_tokens, count_open_ai_tokens,
+    warm_up_open_ai_tokenizers, RawToolCall,
+};
 
 const PROVIDER_ID: &str = "copilot_chat";
 const PROVIDER_NAME: &str = "GitHub Copilot Chat";
+const COPILOT_CHAT_DOCS_URL: &str =
+    "https://zed.dev/docs/assistant/configuration#github-copilot-chat";
+
+/// How long a successful check of the OAuth token is trusted before `authenticate` checks again.
+const AUTH_VERIFICATION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The most stop sequences the Copilot Chat API accepts in a request.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Requests whose messages contain fewer characters than this skip the
+/// tokenizer and use a cheap characters-per-token estimate instead.
+pub const TOKEN_COUNT_FAST_PATH_MAX_CHARS: usize = 200;
+
+/// How many bytes of a response's text are received between estimates of its token count.
+const PARTIAL_USAGE_INTERVAL_BYTES: usize = 400;
+
+/// The embedding model that Copilot Chat serves to every subscription.
+const EMBEDDING_MODEL_ID: &str = "text-embedding-3-small";
+
+/// The most texts sent in a single embeddings request. The API doesn't document its limit,
+/// so this stays well below OpenAI's.
+const MAX_EMBEDDING_BATCH_SIZE: usize = 512;
 
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct CopilotChatSettings {
+    /// Overrides the Copilot Chat API URL, e.g. for Copilot Enterprise.
+    pub api_url: Option<String>,
+    /// The proxy to send Copilot Chat requests through, in place of the one Zed uses for
+    /// everything else.
+    pub proxy: Option<String>,
+    /// How long a response may stall, receiving too little data, before the request fails.
+    /// Defaults to 30 seconds, and is disabled by setting it to 0.
     pub low_speed_timeout: Option<Duration>,
+    /// The maximum duration of a completion, from requesting it to receiving its final token.
+    pub completion_timeout: Option<Duration>,
+    /// How long to collect a response's text for before emitting it as a single chunk, to
+    /// render it less often on slow connections. Text is emitted as it's received if unset.
+    pub coalesce_chunks: Option<Duration>,
+    /// The number of characters of a response's text to collect before emitting any of it,
+    /// so that it doesn't start with a flickering single character. The text is emitted
+    /// anyway once `first_chunk_timeout` has passed. Text is emitted as it's received if unset.
+    pub min_first_chunk: Option<usize>,
+    /// How long the start of a response's text is held back for when it's shorter than
+    /// `min_first_chunk`.
+    pub first_chunk_timeout: Duration,
+    /// The number of times a request is retried after a transient API error.
+    pub max_retries: usize,
+    /// The delay before the first retry, doubled on each subsequent attempt.
+    pub retry_base_delay: Duration,
+    /// A system prompt prepended to requests that don't contain one.
+    pub default_system_prompt: Option<String>,
+    /// The ID of the model to select by default for new conversations.
+    pub default_model: Option<String>,
+    /// The ID of the model to request a completion from when the selected model is unavailable
+    /// or rate limited, even after retrying.
+    pub fallback_model: Option<String>,
+    /// The maximum number of completions that may be streamed at once, across all models.
+    pub max_concurrent_requests: usize,
+    /// The maximum number of tokens to generate for requests that don't set their own limit.
+    pub default_max_tokens: Option<usize>,
+    /// Whether to drop the oldest messages from prompts that don't fit in the context window,
+    /// instead of sending them as-is for the API to reject.
+    pub truncate_prompt: bool,
+    /// The fraction of the context window a prompt can fill before the user is warned that
+    /// it's approaching the limit.
+    pub context_warning_threshold: f32,
+    /// Whether a response the content filter stops, or one of its candidates, fails with a
+    /// content-filter error instead of ending early. Requests the content filter rejects
+    /// always fail, without being sent again, since it would reject them again.
+    pub retry_on_content_filter: bool,
+    /// Whether requests the API is expected to reject, such as those with an empty final
+    /// message or one that isn't from the user, fail before they're sent.
+    pub strict_validation: bool,
+    /// How the system messages of a request are arranged before it's sent.
+    pub system_message_policy: SystemMessagePolicy,
+    /// Whether to log requests and each line of their responses at debug level.
+    pub log_requests: bool,
+    /// Whether logged requests include the content of their messages, which is redacted
+    /// by default.
+    pub log_prompt_content: bool,
+}
+
+/// How the system messages of a request are arranged before it's sent to Copilot Chat.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMessagePolicy {
+    /// Merge all system messages into a single message at the start of the conversation.
+    #[default]
+    Merge,
+    /// Keep system messages where they appear in the conversation. Consecutive system
+    /// messages are still merged. The API may reject system messages that follow other messages.
+    Preserve,
 }
 
 pub struct CopilotChatLanguageModelProvider {
     state: Model<State>,
+    telemetry: Arc<Telemetry>,
 }
 
 pub struct State {
-    _copilot_chat_subscription: Option<Subscription>,
+    /// The chat models the Copilot Chat API reported as available, once they've been fetched.
+    models: Option<Vec<CopilotChatModel>>,
+    /// The context window sizes reported by the Copilot Chat API, keyed by model ID.
+    max_token_counts: HashMap<String, usize>,
+    fetch_models_task: Option<Task<Option<()>>>,
+    /// How long until a rate-limited request is retried, while one is waiting to be.
+    rate_limit_retry_delay: Option<Duration>,
+    /// Limits the number of concurrent completions, shared by every Copilot Chat model.
+    request_limiter: RateLimiter,
+    max_concurrent_requests: usize,
+    /// The completions being requested or streamed, shared by every Copilot Chat model.
+    in_flight_completions: InFlightCompletions,
+    /// When GitHub last accepted the OAuth token, if it has since this session signed in.
+    verified_at: Option<Instant>,
+    /// The proxy that [`CopilotChat`] was last told to send requests through.
+    proxy: Option<Uri>,
+    _copilot_chat_subscriptions: Vec<Subscription>,
     _settings_subscription: Subscription,
 }
 
@@ -55,25 +189,352 @@ impl State {
             .map(|m| m.read(cx).is_authenticated())
             .unwrap_or(false)
     }
+
+    fn is_verified(&self) -> bool {
+        self.verified_at.map_or(false, |verified_at| {
+            verified_at.elapsed() < AUTH_VERIFICATION_TTL
+        })
+    }
+
+    /// Checks that GitHub still accepts the OAuth token, unless it did so recently.
+    fn verify(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        if self.is_verified() {
+            return Task::ready(Ok(()));
+        }
+        let Some(copilot_chat) = CopilotChat::global(cx) else {
+            return Task::ready(Err(anyhow!("Copilot chat is not enabled")));
+        };
+
+        let low_speed_timeout = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .low_speed_timeout;
+        let verification = copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.verify_oauth_token(low_speed_timeout, cx)
+        });
+        cx.spawn(|this, mut cx| async move {
+            verification.await?;
+            this.update(&mut cx, |this, _| this.verified_at = Some(Instant::now()))
+        })
+    }
+
+    pub fn rate_limit_retry_delay(&self) -> Option<Duration> {
+        self.rate_limit_retry_delay
+    }
+
+    fn set_rate_limit_retry_delay(&mut self, delay: Option<Duration>, cx: &mut ModelContext<Self>) {
+        self.rate_limit_retry_delay = delay;
+        cx.notify();
+    }
+
+    fn fetch_models(&mut self, cx: &mut ModelContext<Self>) {
+        if self.fetch_models_task.is_some() || !self.is_authenticated(cx) {
+            return;
+        }
+
+        let low_speed_timeout = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .low_speed_timeout;
+        self.fetch_models_task = Some(cx.spawn(|this, mut cx| {
+            async move {
+                let models = CopilotChat::fetch_models(low_speed_timeout, cx.clone()).await?;
+                this.update(&mut cx, |this, cx| this.set_models(models, cx))
+            }
+            .log_err()
+        }));
+    }
+
+    fn set_models(&mut self, models: Vec<ModelMetadata>, cx: &mut ModelContext<Self>) {
+        self.max_token_counts = models
+            .iter()
+            .filter_map(|model| Some((model.id.clone(), model.max_token_count()?)))
+            .collect();
+
+        // Models the user can't access would only fail once a completion is requested.
+        let mut chat_models = Vec::new();
+        for model in models
+            .iter()
+            .filter(|model| model.is_chat_model() && model.is_enabled())
+        {
+            let model = CopilotChatModel::from_api_id(&model.id);
+            if !chat_models.contains(&model) {
+                chat_models.push(model);
+            }
+        }
+        // Keep offering the models we know about rather than none at all.
+        self.models = (!chat_models.is_empty()).then_some(chat_models);
+        cx.notify();
+    }
+
+    fn update_request_limiter(&mut self, cx: &AppContext) {
+        let max_concurrent_requests = max_concurrent_requests(cx);
+        if max_concurrent_requests != self.max_concurrent_requests {
+            // Requests already holding a permit keep the previous limiter alive until they finish.
+            self.request_limiter = RateLimiter::new(max_concurrent_requests);
+            self.max_concurrent_requests = max_concurrent_requests;
+        }
+    }
+
+    /// Forgets what was learned about the previous GitHub account, after the user signs in as
+    /// a different one. The models are fetched again once [`CopilotChat`] notifies us.
+    fn reset_account_state(&mut self, cx: &mut ModelContext<Self>) {
+        self.verified_at = None;
+        self.models = None;
+        self.max_token_counts.clear();
+        self.fetch_models_task = None;
+        cx.notify();
+    }
+
+    /// Passes the settings that [`CopilotChat`] applies to every request on to it, along with
+    /// the version of Zed that requests identify themselves with. API token requests share
+    /// the completion retry settings.
+    fn update_copilot_chat_settings(&mut self, cx: &mut ModelContext<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).copilot_chat;
+        // Malformed URLs are ignored here and reported by `authenticate` instead.
+        let api_url = settings
+            .api_url
+            .as_ref()
+            .filter(|api_url| validate_api_url(api_url).is_ok())
+            .map(|api_url| api_url.trim_end_matches('/').to_string());
+        let log_responses = settings.log_requests;
+        let editor_info = EditorInfo::new(AppVersion::global(cx));
+        let api_token_retry_policy = RetryPolicy {
+            max_retries: settings.max_retries,
+            base_delay: settings.retry_base_delay,
+        };
+        let proxy = settings.proxy.as_ref().and_then(|proxy| {
+            proxy
+                .parse::<Uri>()
+                .inspect_err(|error| log::error!("Invalid Copilot Chat proxy {proxy:?}: {error}"))
+                .ok()
+        });
+        // Creating a client is expensive, so it's only replaced when the proxy changes.
+        let proxy_client = if proxy != self.proxy {
+            self.proxy = proxy.clone();
+            Some(proxy.map(|proxy| {
+                let client = IsahcHttpClient::new(Some(proxy.clone()), None);
+                Arc::new(HttpClientWithProxy::new_uri(client, Some(proxy))) as Arc<dyn HttpClient>
+            }))
+        } else {
+            None
+        };
+        if let Some(copilot_chat) = CopilotChat::global(cx) {
+            copilot_chat.update(cx, |copilot_chat, cx| {
+                copilot_chat.set_api_url(api_url, cx);
+                copilot_chat.set_log_responses(log_responses);
+                copilot_chat.set_editor_info(editor_info);
+                copilot_chat.set_api_token_retry_policy(api_token_retry_policy);
+                if let Some(proxy_client) = proxy_client {
+                    copilot_chat.set_proxy_client(proxy_client);
+                }
+            });
+        }
+    }
+}
+
+/// The completions of Copilot Chat models that are still being requested or streamed, so
+/// that they can all be cancelled at once, and so that a new completion can cancel the
+/// previous one of the same session.
+#[derive(Clone, Default)]
+struct InFlightCompletions {
+    completions: Arc<Mutex<HashMap<usize, InFlightCompletionState>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+struct InFlightCompletionState {
+    session_id: Option<String>,
+    /// Aborts the stage of the completion that is currently running.
+    abort_handle: Option<AbortHandle>,
+    /// Whether the completion was cancelled, so that its next stage is aborted too.
+    cancelled: bool,
+}
+
+impl InFlightCompletionState {
+    fn cancel(&mut self) {
+        self.cancelled = true;
+        if let Some(abort_handle) = &self.abort_handle {
+            abort_handle.abort();
+        }
+    }
+}
+
+impl InFlightCompletions {
+    /// Registers a new completion, cancelling any that is still in flight for the same session.
+    fn register(&self, session_id: Option<String>) -> InFlightCompletion {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut completions = self.completions.lock();
+        if let Some(session_id) = &session_id {
+            for completion in completions.values_mut() {
+                if completion.session_id.as_ref() == Some(session_id) {
+                    completion.cancel();
+                }
+            }
+        }
+        completions.insert(
+            id,
+            InFlightCompletionState {
+                session_id,
+                abort_handle: None,
+                cancelled: false,
+            },
+        );
+        InFlightCompletion {
+            id,
+            completions: self.completions.clone(),
+        }
+    }
+
+    fn cancel_all(&self) {
+        for completion in self.completions.lock().values_mut() {
+            completion.cancel();
+        }
+    }
+}
+
+/// A completion registered with [`InFlightCompletions`], deregistered when dropped.
+struct InFlightCompletion {
+    id: usize,
+    completions: Arc<Mutex<HashMap<usize, InFlightCompletionState>>>,
+}
+
+impl InFlightCompletion {
+    /// Returns a registration for the next stage of the completion, which is aborted when
+    /// the completion is cancelled.
+    fn registration(&self) -> AbortRegistration {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        if let Some(completion) = self.completions.lock().get_mut(&self.id) {
+            if completion.cancelled {
+                abort_handle.abort();
+            }
+            completion.abort_handle = Some(abort_handle);
+        }
+        abort_registration
+    }
+}
+
+impl Drop for InFlightCompletion {
+    fn drop(&mut self) {
+        self.completions.lock().remove(&self.id);
+    }
+}
+
+fn max_concurrent_requests(cx: &AppContext) -> usize {
+    // A limit of zero would block every request forever.
+    AllLanguageModelSettings::get_global(cx)
+        .copilot_chat
+        .max_concurrent_requests
+        .max(1)
+}
+
+fn validate_api_url(api_url: &str) -> Result<()> {
+    let url = Url::parse(api_url)
+        .map_err(|error| anyhow!("Invalid Copilot Chat API URL \"{api_url}\": {error}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!(
+            "Invalid Copilot Chat API URL \"{api_url}\": expected an http or https URL"
+        ));
+    }
+    Ok(())
 }
 
 impl CopilotChatLanguageModelProvider {
-    pub fn new(cx: &mut AppContext) -> Self {
+    pub fn new(telemetry: Arc<Telemetry>, cx: &mut AppContext) -> Self {
         let state = cx.new_model(|cx| {
-            let _copilot_chat_subscription = CopilotChat::global(cx)
-                .map(|copilot_chat| cx.observe(&copilot_chat, |_, _, cx| cx.notify()));
-            State {
-                _copilot_chat_subscription,
-                _settings_subscription: cx.observe_global::<SettingsStore>(|_, cx| {
-                    cx.notify();
-                }),
+            let mut _copilot_chat_subscriptions = Vec::new();
+            if let Some(copilot_chat) = CopilotChat::global(cx) {
+                _copilot_chat_subscriptions.push(cx.subscribe(
+                    &copilot_chat,
+                    |this: &mut State, _, event, cx| match event {
+                        CopilotChatEvent::OAuthTokenChanged => this.reset_account_state(cx),
+                    },
+                ));
+                _copilot_chat_subscriptions.push(cx.observe(
+                    &copilot_chat,
+                    |this: &mut State, copilot_chat, cx| {
+                        if !copilot_chat.read(cx).is_authenticated() {
+                            this.verified_at = None;
+                        }
+                        this.fetch_models(cx);
+                        cx.notify();
+                    },
+                ));
             }
+            let max_concurrent_requests = max_concurrent_requests(cx);
+            let mut state = State {
+                models: None,
+                max_token_counts: HashMap::default(),
+                fetch_models_task: None,
+                rate_limit_retry_delay: None,
+                request_limiter: RateLimiter::new(max_concurrent_requests),
+                max_concurrent_requests,
+                in_flight_completions: InFlightCompletions::default(),
+                verified_at: None,
+                proxy: None,
+                _copilot_chat_subscriptions,
+                _settings_subscription: cx.observe_global::<SettingsStore>(
+                    |this: &mut State, cx| {
+                        this.update_copilot_chat_settings(cx);
+                        this.update_request_limiter(cx);
+                        cx.notify();
+                    },
+                ),
+            };
+            state.update_copilot_chat_settings(cx);
+            state.fetch_models(cx);
+            state
         });
 
-        Self { state }
+        Self { state, telemetry }
+    }
+
+    /// Fetches the models available to the user from the Copilot Chat API, which vary over
+    /// time and by subscription. The result is cached, so that
+    /// [`LanguageModelProvider::provided_models`] returns the same models afterwards.
+    pub fn list_models(&self, cx: &mut AppContext) -> Task<Result<Vec<Arc<dyn LanguageModel>>>> {
+        let low_speed_timeout = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .low_speed_timeout;
+        let state = self.state.clone();
+        let telemetry = self.telemetry.clone();
+        cx.spawn(|mut cx| async move {
+            let models = CopilotChat::fetch_models(low_speed_timeout, cx.clone()).await?;
+            state.update(&mut cx, |state, cx| state.set_models(models, cx))?;
+            cx.update(|cx| language_models(&state, &telemetry, cx))
+        })
     }
 }
 
+/// Returns the models the Copilot Chat API last reported, or the models we know about if
+/// it hasn't reported any yet.
+fn language_models(
+    state: &Model<State>,
+    telemetry: &Arc<Telemetry>,
+    cx: &AppContext,
+) -> Vec<Arc<dyn LanguageModel>> {
+    let State {
+        models,
+        max_token_counts,
+        request_limiter,
+        in_flight_completions,
+        ..
+    } = state.read(cx);
+    let models = models
+        .clone()
+        .unwrap_or_else(|| CopilotChatModel::iter().collect());
+    models
+        .into_iter()
+        .map(|model| {
+            Arc::new(CopilotChatLanguageModel {
+                max_token_count: max_token_counts.get(model.id()).copied(),
+                model,
+                state: state.clone(),
+                request_limiter: request_limiter.clone(),
+                in_flight_completions: in_flight_completions.clone(),
+                telemetry: telemetry.clone(),
+            }) as Arc<dyn LanguageModel>
+        })
+        .collect()
+}
+
 impl LanguageModelProviderState for CopilotChatLanguageModelProvider {
     type ObservableEntity = State;
 
@@ -95,15 +556,33 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
         IconName::Copilot
     }
 
-    fn provided_models(&self, _cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
-        CopilotChatModel::iter()
-            .map(|model| {
-                Arc::new(CopilotChatLanguageModel {
-                    model,
-                    request_limiter: RateLimiter::new(4),
-                }) as Arc<dyn LanguageModel>
-            })
-            .collect()
+    fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+        language_models(&self.state, &self.telemetry, cx)
+    }
+
+    fn embedding_models(&self, _cx: &AppContext) -> Vec<Arc<dyn EmbeddingModel>> {
+        vec![Arc::new(CopilotChatEmbeddingModel)]
+    }
+
+    fn default_model(&self, cx: &AppContext) -> Option<Arc<dyn LanguageModel>> {
+        let default_model = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .default_model
+            .as_deref()?;
+        let models = self.provided_models(cx);
+        if let Some(model) = models
+            .iter()
+            .find(|model| model.id().0.as_ref() == default_model)
+        {
+            return Some(model.clone());
+        }
+
+        let fallback = models.into_iter().next()?;
+        log::warn!(
+            "Unknown Copilot Chat default model \"{default_model}\", falling back to {}",
+            fallback.id().0
+        );
+        Some(fallback)
     }
 
     fn is_authenticated(&self, cx: &AppContext) -> bool {
@@ -111,25 +590,46 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
     }
 
     fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>> {
-        let result = if self.is_authenticated(cx) {
-            Ok(())
-        } else if let Some(copilot) = Copilot::global(cx) {
-            let error_msg = match copilot.read(cx).status() {
-                Status::Disabled => anyhow::anyhow!("Copilot must be enabled for Copilot Chat to work. Please enable Copilot and try again."),
-                Status::Error(e) => anyhow::anyhow!(format!("Received the following error while signing into Copilot: {e}")),
-                Status::Starting { task: _ } => anyhow::anyhow!("Copilot is still starting, please wait for Copilot to start then try again"),
-                Status::Unauthorized => anyhow::anyhow!("Unable to authorize with Copilot. Please make sure that you have an active Copilot and Copilot Chat subscription."),
-                Status::Authorized => return Task::ready(Ok(())),
-                Status::SignedOut => anyhow::anyhow!("You have signed out of Copilot. Please sign in to Copilot and try again."),
-                Status::SigningIn { prompt: _ } => anyhow::anyhow!("Still signing into Copilot..."),
-            };
-            Err(error_msg)
-        } else {
-            Err(anyhow::anyhow!(
-                "Copilot must be enabled for Copilot Chat to work. Please enable Copilot and try again."
-            ))
+        if let Some(api_url) = &AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .api_url
+        {
+            if let Err(error) = validate_api_url(api_url) {
+                return Task::ready(Err(error));
+            }
+        }
+
+        if self.is_authenticated(cx) {
+            // A stored OAuth token may no longer grant access, e.g. when the subscription has
+            // expired, in which case verifying it signs the user out.
+            let verification = self.state.update(cx, |state, cx| state.verify(cx));
+            return cx.spawn(|_| async move { verification.await.map_err(categorize_error) });
+        }
+        if let Some(error) = copilot_status_error(cx) {
+            return Task::ready(Err(LanguageModelError::NotAuthenticated.attach(error)));
+        }
+        let Some(copilot_chat) = CopilotChat::global(cx) else {
+            return Task::ready(Err(anyhow!("Copilot chat is not enabled")));
         };
-        Task::ready(result)
+
+        // Copilot is signed in, but GitHub rejected its OAuth token. The user may have renewed
+        // their subscription since, so the token is tried again before they're asked to sign in.
+        let low_speed_timeout = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .low_speed_timeout;
+        let retry = copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.retry_rejected_oauth_token(low_speed_timeout, cx)
+        });
+        let not_authenticated_error = not_authenticated_error(cx);
+        cx.spawn(|_| async move {
+            match retry.await {
+                Ok(()) => Ok(()),
+                Err(error) if error.is::<NotSignedInError>() => {
+                    Err(LanguageModelError::NotAuthenticated.attach(not_authenticated_error))
+                }
+                Err(error) => Err(categorize_error(error)),
+            }
+        })
     }
 
     fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
@@ -137,16 +637,101 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
         cx.new_view(|cx| ConfigurationView::new(state, cx)).into()
     }
 
-    fn reset_credentials(&self, _cx: &mut AppContext) -> Task<Result<()>> {
-        Task::ready(Err(anyhow!(
-            "Signing out of GitHub Copilot Chat is currently not supported."
-        )))
+    fn requires_external_dependency(&self) -> Option<&str> {
+        Some("GitHub Copilot")
+    }
+
+    /// Signs out of Copilot Chat completely: the OAuth token and the API token are forgotten,
+    /// the API token stored in the keychain is deleted, and so is everything learned about
+    /// the account, such as its models. Copilot is signed out too, because the OAuth token
+    /// is only read again once Copilot stores a new one after the user signs back in.
+    fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        let sign_out_of_copilot = Copilot::global(cx)
+            .filter(|copilot| {
+                matches!(
+                    copilot.read(cx).status(),
+                    Status::Authorized | Status::Unauthorized
+                )
+            })
+            .map(|copilot| copilot.update(cx, |copilot, cx| copilot.sign_out(cx)));
+        let sign_out_of_copilot_chat = CopilotChat::global(cx).map(|copilot_chat| {
+            copilot_chat.update(cx, |copilot_chat, cx| copilot_chat.sign_out(cx))
+        });
+        self.state
+            .update(cx, |state, cx| state.reset_account_state(cx));
+
+        cx.background_executor().spawn(async move {
+            if let Some(sign_out_of_copilot_chat) = sign_out_of_copilot_chat {
+                sign_out_of_copilot_chat.await?;
+            }
+            if let Some(sign_out_of_copilot) = sign_out_of_copilot {
+                sign_out_of_copilot.await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn check_health(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        if !self.is_authenticated(cx) {
+            return Task::ready(Err(not_authenticated_error(cx)));
+        }
+        let Some(copilot_chat) = CopilotChat::global(cx) else {
+            return Task::ready(Err(anyhow!("Copilot chat is not enabled")));
+        };
+
+        // Unlike `authenticate`, always mint a fresh API token rather than trusting a recent check.
+        let low_speed_timeout = AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .low_speed_timeout;
+        copilot_chat.update(cx, |copilot_chat, cx| {
+            copilot_chat.verify_oauth_token(low_speed_timeout, cx)
+        })
+    }
+
+    fn cancel_all(&self, cx: &mut AppContext) {
+        self.state.read(cx).in_flight_completions.cancel_all();
+    }
+
+    fn warm_up(&self, cx: &mut AppContext) -> Task<()> {
+        warm_up_open_ai_tokenizers(cx)
     }
 }
 
+/// Describes why Copilot Chat isn't authenticated, either because Copilot isn't signed in or
+/// because Copilot Chat has no OAuth token, e.g. after GitHub rejected it.
+fn not_authenticated_error(cx: &AppContext) -> anyhow::Error {
+    copilot_status_error(cx).unwrap_or_else(|| {
+        anyhow!("Copilot is signed in, but Copilot Chat has no valid OAuth token. Please make sure that you have an active Copilot Chat subscription and try again.")
+    })
+}
+
+/// Describes why Copilot can't be used for Copilot Chat, or returns `None` if it's signed in.
+fn copilot_status_error(cx: &AppContext) -> Option<anyhow::Error> {
+    let Some(copilot) = Copilot::global(cx) else {
+        return Some(anyhow!(
+            "Copilot must be enabled for Copilot Chat to work. Please enable Copilot and try again."
+        ));
+    };
+    let error = match copilot.read(cx).status() {
+        Status::Disabled => anyhow!("Copilot must be enabled for Copilot Chat to work. Please enable Copilot and try again."),
+        Status::Error(e) => anyhow!(format!("Received the following error while signing into Copilot: {e}")),
+        Status::Starting { task: _ } => anyhow!("Copilot is still starting, please wait for Copilot to start then try again"),
+        Status::Unauthorized => anyhow!("Unable to authorize with Copilot. Please make sure that you have an active Copilot and Copilot Chat subscription."),
+        Status::Authorized => return None,
+        Status::SignedOut => anyhow!("You have signed out of Copilot. Please sign in to Copilot and try again."),
+        Status::SigningIn { prompt: _ } => anyhow!("Still signing into Copilot..."),
+    };
+    Some(error)
+}
+
 pub struct CopilotChatLanguageModel {
     model: CopilotChatModel,
+    /// The context window size reported by the API, if it could be fetched.
+    max_token_count: Option<usize>,
+    state: Model<State>,
     request_limiter: RateLimiter,
+    in_flight_completions: InFlightCompletions,
+    telemetry: Arc<Telemetry>,
 }
 
 impl LanguageModel for CopilotChatLanguageModel {
@@ -171,7 +756,27 @@ impl LanguageModel for CopilotChatLanguageModel {
     }
 
     fn max_token_count(&self) -> usize {
-        self.model.max_token_count()
+        self.max_token_count
+            .unwrap_or_else(|| self.model.max_token_count())
+    }
+
+    fn category(&self) -> ModelCategory {
+        model_category(&self.model)
+    }
+
+    fn context_warning_threshold(&self, cx: &AppContext) -> f32 {
+        AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .context_warning_threshold
+            .clamp(0.0, 1.0)
+    }
+
+    fn supports_images(&self) -> bool {
+        self.model.supports_images()
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
     }
 
     fn count_tokens(
@@ -179,76 +784,66 @@ impl LanguageModel for CopilotChatLanguageModel {
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        let model = match self.model {
-            CopilotChatModel::Gpt4o => open_ai::Model::FourOmni,
-            CopilotChatModel::Gpt4 => open_ai::Model::Four,
-            CopilotChatModel::Gpt3_5Turbo => open_ai::Model::ThreePointFiveTurbo,
-        };
+        count_tokens(&self.model, request, cx)
+    }
 
-        count_open_ai_tokens(request, model, cx)
+    fn count_tokens_for_message(
+        &self,
+        message: LanguageModelRequestMessage,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        let fallback_token_count = message.string_contents().chars().count().div_ceil(4);
+        let token_count = count_open_ai_message_tokens(message, open_ai_model(&self.model), cx);
+        async move {
+            Ok(token_count.await.unwrap_or_else(|error| {
+                log::warn!(
+                    "Failed to count tokens, estimating from the message's length instead: {error}"
+                );
+                fallback_token_count
+            }))
+        }
+        .boxed()
     }
 
+    /// When the model is unavailable or rate limited even after retrying, the completion is
+    /// requested from the `fallback_model` instead, if one is set, and is preceded by a
+    /// [`LanguageModelCompletionEvent::Fallback`]. Other errors, such as invalid requests,
+    /// and errors after the response started streaming are returned as they are.
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
-        if let Some(message) = request.messages.last() {
-            if message.contents_empty() {
-                const EMPTY_PROMPT_MSG: &str =
-                    "Empty prompts aren't allowed. Please provide a non-empty prompt.";
-                return futures::future::ready(Err(anyhow::anyhow!(EMPTY_PROMPT_MSG))).boxed();
-            }
-
-            // Copilot Chat has a restriction that the final message must be from the user.
-            // While their API does return an error message for this, we can catch it earlier
-            // and provide a more helpful error message.
-            if !matches!(message.role, Role::User) {
-                const USER_ROLE_MSG: &str = "The final message must be from the user. To provide a system prompt, you must provide the system prompt followed by a user prompt.";
-                return futures::future::ready(Err(anyhow::anyhow!(USER_ROLE_MSG))).boxed();
-            }
-        }
-
-        let request = self.to_copilot_chat_request(request);
-        let Ok(low_speed_timeout) = cx.update(|cx| {
+        let Ok(settings) = cx.update(|cx| {
             AllLanguageModelSettings::get_global(cx)
                 .copilot_chat
-                .low_speed_timeout
+                .clone()
         }) else {
             return futures::future::ready(Err(anyhow::anyhow!("App state dropped"))).boxed();
         };
-
-        let request_limiter = self.request_limiter.clone();
-        let future = cx.spawn(|cx| async move {
-            let response = CopilotChat::stream_completion(request, low_speed_timeout, cx);
-            request_limiter.stream(async move {
-                let response = response.await?;
-                let stream = response
-                    .filter_map(|response| async move {
-                        match response {
-                            Ok(result) => {
-                                let choice = result.choices.first();
-                                match choice {
-                                    Some(choice) => Some(Ok(choice.delta.content.clone().unwrap_or_default())),
-                                    None => Some(Err(anyhow::anyhow!(
-                                        "The Copilot Chat API returned a response with no choices, but hadn't finished the message yet. Please try again."
-                                    ))),
-                                }
-                            }
-                            Err(err) => Some(Err(err)),
-                        }
-                    })
-                    .boxed();
-                Ok(stream)
-            }).await
-        });
-
-        async move {
-            Ok(future
-                .await?
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
-        }
+        let Some(fallback_model) = self.fallback_model(&settings, cx) else {
+            return self.stream_completion_without_fallback(request, settings, cx);
+        };
+        let fallback_request = request.clone();
+        let completion = self.stream_completion_without_fallback(request, settings, cx);
+        cx.spawn(|cx| async move {
+            match completion.await {
+                Err(error) if is_retryable(&error) => {
+                    let model_id = fallback_model.id().0.to_string();
+                    log::warn!(
+                        "Copilot Chat model is unavailable, falling back to {model_id}: {error}"
+                    );
+                    let events = fallback_model
+                        .stream_completion(fallback_request, &cx)
+                        .await?;
+                    let fallback = LanguageModelCompletionEvent::Fallback { model_id };
+                    Ok(futures::stream::once(future::ready(Ok(fallback)))
+                        .chain(events)
+                        .boxed())
+                }
+                result => result,
+            }
+        })
         .boxed()
     }
 
@@ -262,130 +857,3530 @@ impl LanguageModel for CopilotChatLanguageModel {
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
         future::ready(Err(anyhow!("not implemented"))).boxed()
     }
+
+    fn serialize_request(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        let Ok(settings) = cx.update(|cx| {
+            AllLanguageModelSettings::get_global(cx)
+                .copilot_chat
+                .clone()
+        }) else {
+            return futures::future::ready(Err(anyhow::anyhow!("App state dropped"))).boxed();
+        };
+        let copilot_request = match self.prepare_request(request, &settings, cx) {
+            Ok(copilot_request) => copilot_request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+
+        let future =
+            cx.spawn(|_| async move { Ok(serde_json::to_string_pretty(&copilot_request.await?)?) });
+        future.boxed()
+    }
 }
 
 impl CopilotChatLanguageModel {
-    pub fn to_copilot_chat_request(&self, request: LanguageModelRequest) -> CopilotChatRequest {
-        CopilotChatRequest::new(
-            self.model.clone(),
-            request
-                .messages
-                .into_iter()
-                .map(|msg| ChatMessage {
-                    role: match msg.role {
-                        Role::User => CopilotChatRole::User,
-                        Role::Assistant => CopilotChatRole::Assistant,
-                        Role::System => CopilotChatRole::System,
-                    },
-                    content: msg.string_contents(),
-                })
-                .collect(),
-        )
+    /// Streams a completion from this model alone, without falling back to another one.
+    fn stream_completion_without_fallback(
+        &self,
+        mut request: LanguageModelRequest,
+        settings: CopilotChatSettings,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let session_id = request.session_id.clone();
+        let choice_count = request.n.unwrap_or(1);
+        // The metadata is only for telemetry, and must not reach the API.
+        let metadata = std::mem::take(&mut request.metadata);
+        let copilot_request = match self.prepare_request(request, &settings, cx) {
+            Ok(copilot_request) => copilot_request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+
+        let mut metrics = CompletionMetrics::new(self.telemetry.clone(), self.model.id(), metadata);
+        let executor = cx.background_executor().clone();
+        let mut usage_estimator =
+            CompletionUsageEstimator::new(open_ai_model(&self.model), executor.clone());
+        let coalesce_chunks = settings.coalesce_chunks;
+        let min_first_chunk = settings.min_first_chunk;
+        let first_chunk_timeout = settings.first_chunk_timeout;
+        let request_id = Uuid::new_v4().to_string();
+        let state = self.state.clone();
+        let request_limiter = self.request_limiter.clone();
+        let in_flight_completion = self.in_flight_completions.register(session_id);
+        let future = cx.spawn({
+            let request_id = request_id.clone();
+            |cx| async move {
+                let copilot_request = copilot_request.await?;
+                if settings.log_requests {
+                    log_request(&copilot_request, settings.log_prompt_content);
+                }
+                let completion_timeout = settings.completion_timeout;
+                let retry_on_content_filter = settings.retry_on_content_filter;
+                let executor = cx.background_executor().clone();
+                let response = stream_completion_with_retries(
+                    copilot_request,
+                    request_id,
+                    settings,
+                    state,
+                    cx,
+                );
+                let response = match completion_timeout {
+                    Some(timeout) => {
+                        with_completion_timeout(response, timeout, &executor).boxed_local()
+                    }
+                    None => response.boxed_local(),
+                };
+                request_limiter
+                    .stream(async move {
+                        let response = response.await?;
+                        let stream =
+                            map_to_language_model_completion_events(response, choice_count)
+                                .map(move |event| match event {
+                                    Ok(event)
+                                        if retry_on_content_filter
+                                            && stopped_by_content_filter(&event) =>
+                                    {
+                                        Err(content_filtered_error())
+                                    }
+                                    event => event,
+                                })
+                                .boxed();
+                        Ok(stream)
+                    })
+                    .await
+            }
+        });
+
+        let future = Abortable::new(future, in_flight_completion.registration());
+        async move {
+            let result = match future.await {
+                Ok(result) => result,
+                Err(Aborted) => Err(CompletionCancelledError.into()),
+            };
+            match result {
+                Ok(stream) => {
+                    let connected = LanguageModelCompletionEvent::Connected {
+                        latency: metrics.started_at.elapsed(),
+                    };
+                    let stream = Abortable::new(stream, in_flight_completion.registration());
+                    // The text received so far, which is returned with the error if the
+                    // connection is lost.
+                    let mut partial = String::new();
+                    let events = stream.flat_map(move |event| {
+                        // Deregisters the completion once its stream is dropped.
+                        let _ = &in_flight_completion;
+                        if let Ok(LanguageModelCompletionEvent::Text(text)) = &event {
+                            partial.push_str(text);
+                        }
+                        // Every candidate's text counts towards the completion tokens.
+                        let partial_usage =
+                            event
+                                .as_ref()
+                                .ok()
+                                .and_then(event_text)
+                                .and_then(|text| usage_estimator.push(text))
+                                .map(|completion_tokens| {
+                                    Ok(LanguageModelCompletionEvent::PartialUsage {
+                                        completion_tokens,
+                                    })
+                                });
+                        let awaiting_first_token = metrics.time_to_first_token.is_none();
+                        metrics.record(&event);
+                        let first_token = metrics
+                            .time_to_first_token
+                            .filter(|_| awaiting_first_token)
+                            .map(|latency| {
+                                Ok(LanguageModelCompletionEvent::FirstToken { latency })
+                            });
+                        let event = event.map_err(|error| {
+                            with_request_id(categorize_stream_error(error, &partial), &request_id)
+                        });
+                        futures::stream::iter(
+                            first_token.into_iter().chain([event]).chain(partial_usage),
+                        )
+                    });
+                    let events = futures::stream::once(future::ready(Ok(connected)))
+                        .chain(events)
+                        .boxed();
+                    let events = match min_first_chunk {
+                        Some(min_chars) => buffer_first_text_chunk(
+                            events,
+                            min_chars,
+                            first_chunk_timeout,
+                            executor.clone(),
+                        ),
+                        None => events,
+                    };
+                    Ok(match coalesce_chunks {
+                        Some(window) => coalesce_text_chunks(events, window, executor),
+                        None => events,
+                    })
+                }
+                Err(error) => {
+                    metrics.record_failure(&error);
+                    Err(with_request_id(categorize_error(error), &request_id))
+                }
+            }
+        }
+        .boxed()
     }
-}
 
-struct ConfigurationView {
-    copilot_status: Option<copilot::Status>,
-    state: Model<State>,
-    _subscription: Option<Subscription>,
-}
+    /// Returns the model set by the `fallback_model` setting, unless it's this one.
+    fn fallback_model(
+        &self,
+        settings: &CopilotChatSettings,
+        cx: &AsyncAppContext,
+    ) -> Option<Arc<dyn LanguageModel>> {
+        let fallback_model = settings.fallback_model.as_deref()?;
+        if fallback_model == self.model.id() {
+            return None;
+        }
+        let models = cx
+            .update(|cx| language_models(&self.state, &self.telemetry, cx))
+            .ok()?;
+        let model = models
+            .into_iter()
+            .find(|model| model.id().0.as_ref() == fallback_model);
+        if model.is_none() {
+            log::warn!("Unknown Copilot Chat fallback model \"{fallback_model}\"");
+        }
+        model
+    }
 
-impl ConfigurationView {
-    pub fn new(state: Model<State>, cx: &mut ViewContext<Self>) -> Self {
-        let copilot = Copilot::global(cx);
+    /// Converts a request into the one sent to the Copilot Chat API. Both
+    /// `stream_completion` and `serialize_request` go through here, so that what
+    /// we inspect can't drift from what we send.
+    ///
+    /// Requests the API would reject are refused immediately, unless the `strict_validation`
+    /// setting is off. Truncating the prompt and clamping `max_tokens` need its token count,
+    /// so they happen in the returned future.
+    fn prepare_request(
+        &self,
+        mut request: LanguageModelRequest,
+        settings: &CopilotChatSettings,
+        cx: &AsyncAppContext,
+    ) -> Result<LocalBoxFuture<'static, Result<CopilotChatRequest>>> {
+        request.max_tokens = request.max_tokens.or(settings.default_max_tokens);
+        let model = self.model.clone();
+        let max_token_count = self.max_token_count();
+        let truncate_prompt = settings.truncate_prompt;
+        let default_system_prompt = settings.default_system_prompt.clone();
 
-        Self {
-            copilot_status: copilot.as_ref().map(|copilot| copilot.read(cx).status()),
-            state,
-            _subscription: copilot.as_ref().map(|copilot| {
-                cx.observe(copilot, |this, model, cx| {
-                    this.copilot_status = Some(model.read(cx).status());
-                    cx.notify();
-                })
-            }),
+        let system_message_policy = settings.system_message_policy;
+
+        if let Some(response_format) = &request.response_format {
+            validate_response_format(response_format, &model)?;
+        }
+        let mut copilot_request = to_copilot_chat_request(
+            model.clone(),
+            request.clone(),
+            default_system_prompt.as_deref(),
+            system_message_policy,
+        );
+        // Truncation never removes the final message, so it can't affect validation.
+        if settings.strict_validation {
+            validate_copilot_chat_request(&copilot_request)?;
         }
+
+        let cx = cx.clone();
+        Ok(async move {
+            // The prompt is only counted when it may need truncating, or when its size is
+            // needed to clamp the token limit.
+            let prompt_token_count = if truncate_prompt {
+                let (request, prompt_token_count) =
+                    truncate_to_fit(request, &model, max_token_count, &cx).await?;
+                copilot_request = to_copilot_chat_request(
+                    model,
+                    request,
+                    default_system_prompt.as_deref(),
+                    system_message_policy,
+                );
+                Some(prompt_token_count)
+            } else if copilot_request.max_tokens.is_some() {
+                cx.update(|cx| count_tokens(&model, request, cx))?
+                    .await
+                    .log_err()
+            } else {
+                None
+            };
+            if let (Some(max_tokens), Some(prompt_token_count)) =
+                (copilot_request.max_tokens, prompt_token_count)
+            {
+                copilot_request.max_tokens = Some(clamp_max_tokens(
+                    max_tokens,
+                    prompt_token_count,
+                    max_token_count,
+                ));
+            }
+            Ok(copilot_request)
+        }
+        .boxed_local())
     }
 }
 
-impl Render for ConfigurationView {
-    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        if self.state.read(cx).is_authenticated(cx) {
-            const LABEL: &str = "Authorized.";
-            h_flex()
-                .gap_1()
-                .child(Icon::new(IconName::Check).color(Color::Success))
-                .child(Label::new(LABEL))
-        } else {
-            let loading_icon = svg()
-                .size_8()
-                .path(IconName::ArrowCircle.path())
-                .text_color(cx.text_style().color)
-                .with_animation(
-                    "icon_circle_arrow",
-                    Animation::new(Duration::from_secs(2)).repeat(),
-                    |svg, delta| svg.with_transformation(Transformation::rotate(percentage(delta))),
-                );
+/// Copilot Chat's embedding model, whose requests are authenticated with the same API token
+/// as completions.
+pub struct CopilotChatEmbeddingModel;
 
-            const ERROR_LABEL: &str = "Copilot Chat requires an active GitHub Copilot subscription. Please ensure Copilot is configured and try again, or use a different Assistant provider.";
+impl EmbeddingModel for CopilotChatEmbeddingModel {
+    fn id(&self) -> LanguageModelId {
+        LanguageModelId::from(EMBEDDING_MODEL_ID.to_string())
+    }
 
-            match &self.copilot_status {
-                Some(status) => match status {
-                    Status::Disabled => v_flex().gap_6().p_4().child(Label::new(ERROR_LABEL)),
-                    Status::Starting { task: _ } => {
-                        const LABEL: &str = "Starting Copilot...";
-                        v_flex()
-                            .gap_6()
-                            .justify_center()
-                            .items_center()
-                            .child(Label::new(LABEL))
-                            .child(loading_icon)
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(EMBEDDING_MODEL_ID.to_string())
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MAX_EMBEDDING_BATCH_SIZE
+    }
+
+    fn embed_batch(
+        &self,
+        texts: Vec<String>,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let Ok(low_speed_timeout) = cx.update(|cx| {
+            AllLanguageModelSettings::get_global(cx)
+                .copilot_chat
+                .low_speed_timeout
+        }) else {
+            return futures::future::ready(Err(anyhow::anyhow!("App state dropped"))).boxed();
+        };
+        cx.spawn(|cx| async move {
+            CopilotChat::embed(EMBEDDING_MODEL_ID, texts, low_speed_timeout, cx)
+                .await
+                .map_err(categorize_error)
+        })
+        .boxed()
+    }
+}
+
+/// Maps a response's chunks to completion events. When `choice_count` candidates were
+/// requested, each candidate's events are wrapped in a
+/// [`LanguageModelCompletionEvent::Candidate`].
+pub fn map_to_language_model_completion_events(
+    events: BoxStream<'static, Result<ResponseEvent>>,
+    choice_count: usize,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: BoxStream<'static, Result<ResponseEvent>>,
+        /// Keyed by the index of the choice, then by that of the tool call.
+        tool_calls_by_index: BTreeMap<(usize, usize), RawToolCall>,
+        reported_model: bool,
+        reported_system_fingerprint: bool,
+        received_choices: bool,
+        /// The indices of the choices that have finished.
+        stopped_choices: BTreeSet<usize>,
+        finished: bool,
+    }
+
+    futures::stream::unfold(
+        State {
+            events,
+            tool_calls_by_index: BTreeMap::default(),
+            reported_model: false,
+            reported_system_fingerprint: false,
+            received_choices: false,
+            stopped_choices: BTreeSet::default(),
+            finished: false,
+        },
+        move |mut state| async move {
+            if state.finished {
+                return None;
+            }
+
+            let Some(event) = state.events.next().await else {
+                state.finished = true;
+                if !state.received_choices {
+                    let error = anyhow!(
+                        "The Copilot Chat API finished responding without returning a message. Please try again."
+                    );
+                    return Some((vec![Err(error)], state));
+                }
+                // The stream was closed without a finish reason, so we can't tell how the
+                // remaining choices ended.
+                let events = (0..choice_count.max(1))
+                    .filter(|choice_index| !state.stopped_choices.contains(choice_index))
+                    .map(|choice_index| {
+                        Ok(LanguageModelCompletionEvent::Stop(StopReason::Unknown)
+                            .for_choice(choice_index, choice_count))
+                    })
+                    .collect::<Vec<_>>();
+                return Some((events, state));
+            };
+
+            let mut events = Vec::new();
+            match event {
+                Ok(response) => {
+                    if !state.reported_model {
+                        if let Some(model) = response.model.filter(|model| !model.is_empty()) {
+                            state.reported_model = true;
+                            events.push(Ok(LanguageModelCompletionEvent::ResponseModel {
+                                id: model,
+                            }));
+                        }
                     }
-                    Status::SigningIn { prompt: _ } => {
-                        const LABEL: &str = "Signing in to Copilot...";
-                        v_flex()
-                            .gap_6()
-                            .justify_center()
-                            .items_center()
-                            .child(Label::new(LABEL))
-                            .child(loading_icon)
+                    if !state.reported_system_fingerprint {
+                        if let Some(fingerprint) = response
+                            .system_fingerprint
+                            .filter(|fingerprint| !fingerprint.is_empty())
+                        {
+                            state.reported_system_fingerprint = true;
+                            events.push(Ok(LanguageModelCompletionEvent::SystemFingerprint {
+                                fingerprint,
+                            }));
+                        }
                     }
-                    Status::Error(_) => {
-                        const LABEL: &str = "Copilot had issues starting. Please try restarting it. If the issue persists, try reinstalling Copilot.";
-                        v_flex()
-                            .gap_6()
-                            .child(Label::new(LABEL))
-                            .child(svg().size_8().path(IconName::CopilotError.path()))
+
+                    // Keep-alive and usage-only chunks have no choices, and are skipped.
+                    for choice in &response.choices {
+                        let choice_index = choice.index;
+                        state.received_choices = true;
+                        for chunk in &choice.delta.tool_calls {
+                            let function = chunk.function.as_ref();
+                            let name = function.and_then(|function| function.name.as_deref());
+                            let arguments =
+                                function.and_then(|function| function.arguments.as_deref());
+                            state
+                                .tool_calls_by_index
+                                .entry((choice_index, chunk.index))
+                                .or_default()
+                                .push(chunk.id.as_deref(), name, arguments);
+                            if let Some(delta) = LanguageModelCompletionEvent::tool_use_delta(
+                                chunk.index,
+                                name,
+                                arguments,
+                            ) {
+                                events.push(Ok(delta.for_choice(choice_index, choice_count)));
+                            }
+                        }
+
+                        match choice.finish_reason.as_deref() {
+                            Some(finish_reason) => {
+                                if let Some(content) =
+                                    choice.delta.content.clone().filter(|c| !c.is_empty())
+                                {
+                                    events.push(Ok(LanguageModelCompletionEvent::Text(content)
+                                        .for_choice(choice_index, choice_count)));
+                                }
+                                let (finished_tool_calls, pending_tool_calls): (
+                                    BTreeMap<_, _>,
+                                    _,
+                                ) = std::mem::take(&mut state.tool_calls_by_index)
+                                    .into_iter()
+                                    .partition(|((index, _), _)| *index == choice_index);
+                                state.tool_calls_by_index = pending_tool_calls;
+                                for tool_call in finished_tool_calls.into_values() {
+                                    events.push(tool_call.into_tool_use().map(|tool_use| {
+                                        LanguageModelCompletionEvent::ToolUse(tool_use)
+                                            .for_choice(choice_index, choice_count)
+                                    }));
+                                }
+                                let stop_reason = match finish_reason {
+                                    "stop" => StopReason::EndTurn,
+                                    "length" => StopReason::MaxTokens,
+                                    "content_filter" => StopReason::ContentFilter,
+                                    "tool_calls" => StopReason::ToolUse,
+                                    _ => StopReason::Unknown,
+                                };
+                                events.push(Ok(LanguageModelCompletionEvent::Stop(stop_reason)
+                                    .for_choice(choice_index, choice_count)));
+                                state.stopped_choices.insert(choice_index);
+                            }
+                            None => {
+                                // The first chunk of a message often carries only its role.
+                                if let Some(content) = choice.delta.content.clone() {
+                                    events.push(Ok(LanguageModelCompletionEvent::Text(content)
+                                        .for_choice(choice_index, choice_count)));
+                                }
+                            }
+                        }
                     }
-                    _ => {
-                        const LABEL: &str =
-                    "To use Zed's assistant with GitHub Copilot, you need to be logged in to GitHub. Note that your GitHub account must have an active Copilot Chat subscription.";
-                        v_flex().gap_6().child(Label::new(LABEL)).child(
-                            v_flex()
-                                .gap_2()
-                                .child(
-                                    Button::new("sign_in", "Sign In")
-                                        .icon_color(Color::Muted)
-                                        .icon(IconName::Github)
-                                        .icon_position(IconPosition::Start)
-                                        .icon_size(IconSize::Medium)
-                                        .style(ui::ButtonStyle::Filled)
-                                        .full_width()
-                                        .on_click(|_, cx| {
-                                            inline_completion_button::initiate_sign_in(cx)
-                                        }),
-                                )
-                                .child(
-                                    div().flex().w_full().items_center().child(
-                                        Label::new("Sign in to start using Github Copilot Chat.")
-                                            .color(Color::Muted)
-                                            .size(ui::LabelSize::Small),
-                                    ),
-                                ),
-                        )
+                    if let Some(usage) = response.usage {
+                        events.push(Ok(LanguageModelCompletionEvent::Usage {
+                            prompt_tokens: usage.prompt_tokens,
+                            completion_tokens: usage.completion_tokens,
+                        }));
                     }
-                },
-                None => v_flex().gap_6().child(Label::new(ERROR_LABEL)),
+                }
+                Err(error) => events.push(Err(error)),
+            }
+            Some((events, state))
+        },
+    )
+    .flat_map(futures::stream::iter)
+}
+
+/// Starts a Copilot Chat completion, retrying transient API errors with exponential backoff.
+///
+/// Only failures to establish the stream are retried, so a stream that has already
+/// started emitting tokens is never restarted.
+///
+/// Every attempt is sent with the same `request_id`, so retries show up together in the API's logs.
+///
+/// When the API asks us to back off with a `Retry-After` header, we wait exactly that long
+/// instead of using exponential backoff, and expose the delay on [`State`] while waiting
+/// after a rate limit.
+async fn stream_completion_with_retries(
+    request: CopilotChatRequest,
+    request_id: String,
+    settings: CopilotChatSettings,
+    state: Model<State>,
+    mut cx: AsyncAppContext,
+) -> Result<BoxStream<'static, Result<ResponseEvent>>> {
+    let mut attempt = 0;
+    loop {
+        let result = CopilotChat::stream_completion(
+            request.clone(),
+            &request_id,
+            settings.low_speed_timeout,
+            cx.clone(),
+        )
+        .await;
+        match result {
+            Err(error) if attempt < settings.max_retries && is_retryable(&error) => {
+                let api_error = error.downcast_ref::<ApiError>();
+                let rate_limited =
+                    api_error.map_or(false, |error| error.status == StatusCode::TOO_MANY_REQUESTS);
+                let delay = api_error
+                    .and_then(|error| error.retry_after)
+                    .unwrap_or_else(|| retry_delay(settings.retry_base_delay, attempt as u32));
+                log::warn!(
+                    "Copilot Chat request failed, retrying in {delay:?} (attempt {} of {}): {error}",
+                    attempt + 1,
+                    settings.max_retries
+                );
+
+                if rate_limited {
+                    state
+                        .update(&mut cx, |state, cx| {
+                            state.set_rate_limit_retry_delay(Some(delay), cx)
+                        })
+                        .ok();
+                }
+                cx.background_executor().timer(delay).await;
+                if rate_limited {
+                    state
+                        .update(&mut cx, |state, cx| {
+                            state.set_rate_limit_retry_delay(None, cx)
+                        })
+                        .ok();
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn log_request(request: &CopilotChatRequest, include_prompt_content: bool) {
+    match request_log_body(request, include_prompt_content) {
+        Ok(body) => log::debug!("Copilot Chat request: {body}"),
+        Err(error) => log::debug!("Failed to serialize Copilot Chat request for logging: {error}"),
+    }
+}
+
+/// Returns the request's JSON body, with the content of its messages redacted unless
+/// `include_prompt_content` is set. Roles, tools and parameters are always included.
+fn request_log_body(request: &CopilotChatRequest, include_prompt_content: bool) -> Result<String> {
+    let body = if include_prompt_content {
+        serde_json::to_string(request)
+    } else {
+        let mut request = request.clone();
+        for message in &mut request.messages {
+            message.content = match &message.content {
+                ChatMessageContent::Plain(text) => redact(text).into(),
+                ChatMessageContent::Multipart(parts) => ChatMessageContent::Multipart(
+                    parts
+                        .iter()
+                        .map(|part| match part {
+                            ChatMessagePart::Text { text } => {
+                                ChatMessagePart::Text { text: redact(text) }
+                            }
+                            ChatMessagePart::ImageUrl { image_url } => ChatMessagePart::ImageUrl {
+                                image_url: ImageUrl {
+                                    url: redact(&image_url.url),
+                                },
+                            },
+                        })
+                        .collect(),
+                ),
+            };
+            for tool_call in &mut message.tool_calls {
+                let ToolCallContent::Function { function } = &mut tool_call.content;
+                function.arguments = redact(&function.arguments);
+            }
+        }
+        serde_json::to_string(&request)
+    };
+    Ok(body?)
+}
+
+fn redact(text: &str) -> String {
+    format!("[{} characters redacted]", text.chars().count())
+}
+
+/// Attaches the [`LanguageModelError`] that describes the error, if there is one.
+fn categorize_error(error: anyhow::Error) -> anyhow::Error {
+    let category = if let Some(error) = error.downcast_ref::<ApiError>() {
+        if error.is_unauthorized() {
+            Some(LanguageModelError::NotAuthenticated)
+        } else if error.status == StatusCode::TOO_MANY_REQUESTS {
+            Some(LanguageModelError::RateLimited {
+                retry_after: error.retry_after,
+            })
+        } else if error.body.contains("context_length_exceeded") {
+            Some(LanguageModelError::ContextWindowExceeded)
+        } else if error.is_content_filtered() {
+            Some(LanguageModelError::ContentFiltered)
+        } else {
+            Some(LanguageModelError::ApiError {
+                status: error.status.as_u16(),
+                message: error.message.clone().unwrap_or_else(|| error.body.clone()),
+            })
+        }
+    } else if error.is::<NotSignedInError>() {
+        Some(LanguageModelError::NotAuthenticated)
+    } else if error.is::<CompletionTimeoutError>() {
+        Some(LanguageModelError::Timeout)
+    } else if error.is::<std::io::Error>() {
+        Some(LanguageModelError::Network)
+    } else {
+        None
+    };
+
+    match category {
+        Some(category) => category.attach(error),
+        None => error,
+    }
+}
+
+/// Like [`categorize_error`], for an error that ends a response stream after `partial`
+/// text was received. A network failure at that point interrupts the response.
+fn categorize_stream_error(error: anyhow::Error, partial: &str) -> anyhow::Error {
+    if error.is::<std::io::Error>() {
+        LanguageModelError::Interrupted {
+            partial: partial.to_string(),
+        }
+        .attach(error)
+    } else {
+        categorize_error(error)
+    }
+}
+
+/// Adds the request's ID to the error's message, so that users can include it when
+/// reporting the failure. The original error can still be downcast to.
+fn with_request_id(error: anyhow::Error, request_id: &str) -> anyhow::Error {
+    let message = format!("{error} (request ID: {request_id})");
+    error.context(message)
+}
+
+fn count_tokens(
+    model: &CopilotChatModel,
+    request: LanguageModelRequest,
+    cx: &AppContext,
+) -> BoxFuture<'static, Result<usize>> {
+    if let Some(token_count) = estimate_token_count(&request) {
+        return futures::future::ready(Ok(token_count)).boxed();
+    }
+
+    // Callers only need a rough size, so a tokenizer that fails to load shouldn't stop them.
+    let fallback_token_count = approximate_token_count(&request);
+    let token_count = count_open_ai_tokens(request, open_ai_model(model), cx);
+    async move {
+        Ok(token_count.await.unwrap_or_else(|error| {
+            log::warn!(
+                "Failed to count tokens, estimating from the prompt's length instead: {error}"
+            );
+            fallback_token_count
+        }))
+    }
+    .boxed()
+}
+
+/// The OpenAI model whose tokenizer counts a Copilot Chat model's tokens.
+fn open_ai_model(model: &CopilotChatModel) -> open_ai::Model {
+    match model {
+        CopilotChatModel::Gpt4o => open_ai::Model::FourOmni,
+        CopilotChatModel::Gpt4 => open_ai::Model::Four,
+        CopilotChatModel::Gpt3_5Turbo => open_ai::Model::ThreePointFiveTurbo,
+        // Newer models use GPT-4o's tokenizer, which is close enough for the rest.
+        CopilotChatModel::Other(_) => open_ai::Model::FourOmni,
+    }
+}
+
+/// The category of a Copilot Chat model. Models we don't know about are categorized by
+/// their ID, which names smaller models `mini`.
+fn model_category(model: &CopilotChatModel) -> ModelCategory {
+    match model {
+        CopilotChatModel::Gpt3_5Turbo => ModelCategory::Fast,
+        CopilotChatModel::Gpt4 | CopilotChatModel::Gpt4o => ModelCategory::Balanced,
+        CopilotChatModel::Other(_) if model.is_reasoning_model() => ModelCategory::Reasoning,
+        CopilotChatModel::Other(id) if id.split('-').any(|segment| segment == "mini") => {
+            ModelCategory::Fast
+        }
+        CopilotChatModel::Other(_) => ModelCategory::Balanced,
+    }
+}
+
+/// Drops the oldest messages from the request until its prompt fits in the context window,
+/// returning the truncated request and its token count.
+///
+/// System messages and the latest user message, along with anything after it, are kept.
+/// If the prompt still doesn't fit once nothing else can be dropped, an error is returned.
+async fn truncate_to_fit(
+    mut request: LanguageModelRequest,
+    model: &CopilotChatModel,
+    max_token_count: usize,
+    cx: &AsyncAppContext,
+) -> Result<(LanguageModelRequest, usize)> {
+    let mut dropped_message_count = 0;
+    loop {
+        let token_count = cx
+            .update(|cx| count_tokens(model, request.clone(), cx))?
+            .await?;
+        if token_count <= max_token_count {
+            if dropped_message_count > 0 {
+                log::info!(
+                    "Dropped the {dropped_message_count} oldest messages to fit the prompt in {}'s {max_token_count}-token context window",
+                    model.display_name()
+                );
+            }
+            return Ok((request, token_count));
+        }
+
+        if !drop_oldest_message(&mut request.messages) {
+            return Err(anyhow!(
+                "The prompt uses {token_count} tokens, which exceeds the {max_token_count}-token context window of {}, even after dropping all earlier messages. Please shorten your latest message.",
+                model.display_name()
+            ));
+        }
+        dropped_message_count += 1;
+    }
+}
+
+/// Removes the oldest message that precedes the latest user message and isn't a system
+/// message, along with any tool results that answered it. Returns whether one was removed.
+fn drop_oldest_message(messages: &mut Vec<LanguageModelRequestMessage>) -> bool {
+    let Some(latest_user_ix) = messages
+        .iter()
+        .rposition(|message| message.role == Role::User && !is_tool_result(message))
+    else {
+        return false;
+    };
+    let Some(ix) = messages[..latest_user_ix]
+        .iter()
+        .position(|message| message.role != Role::System)
+    else {
+        return false;
+    };
+
+    let removed = messages.remove(ix);
+    // Tool results can't be sent without the tool calls they answer.
+    if removed.role == Role::Assistant {
+        while messages.get(ix).map_or(false, is_tool_result) {
+            messages.remove(ix);
+        }
+    }
+    true
+}
+
+fn is_tool_result(message: &LanguageModelRequestMessage) -> bool {
+    !message.content.is_empty()
+        && message
+            .content
+            .iter()
+            .all(|content| matches!(content, MessageContent::ToolResult(_)))
+}
+
+/// Estimates the token count of trivially small requests without running the
+/// tokenizer, returning `None` when the request is too large to estimate.
+pub fn estimate_token_count(request: &LanguageModelRequest) -> Option<usize> {
+    let mut char_count = 0;
+    for message in &request.messages {
+        char_count += message.string_contents().chars().count();
+        if char_count >= TOKEN_COUNT_FAST_PATH_MAX_CHARS {
+            return None;
+        }
+    }
+    Some(char_count.div_ceil(4))
+}
+
+/// Estimates the token count of a request from its length, at about four characters per token.
+fn approximate_token_count(request: &LanguageModelRequest) -> usize {
+    request
+        .messages
+        .iter()
+        .map(|message| message.string_contents().chars().count())
+        .sum::<usize>()
+        .div_ceil(4)
+}
+
+fn is_retryable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .map_or(false, |error| error.is_retryable())
+}
+
+/// Whether the event ends a response, or one of its candidates, that the content filter stopped.
+fn stopped_by_content_filter(event: &LanguageModelCompletionEvent) -> bool {
+    match event {
+        LanguageModelCompletionEvent::Stop(StopReason::ContentFilter) => true,
+        LanguageModelCompletionEvent::Candidate { event, .. } => stopped_by_content_filter(event),
+        _ => false,
+    }
+}
+
+/// The error that ends a response the content filter stopped, when the
+/// `retry_on_content_filter` setting asks for one.
+fn content_filtered_error() -> anyhow::Error {
+    LanguageModelError::ContentFiltered.attach(anyhow!(
+        "The response was blocked by Copilot's content filter"
+    ))
+}
+
+/// Returns the delay before the given retry attempt: the base delay doubled for each
+/// prior attempt, plus up to 50% random jitter so that clients don't retry in lockstep.
+fn retry_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter = delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+    delay.saturating_add(jitter)
+}
+
+/// Collects the latency, token usage and outcome of a single completion, and reports them
+/// to telemetry when dropped. Only metadata is recorded, never prompt or response content.
+///
+/// Reporting is subject to the user's telemetry settings, which [`Telemetry`] enforces.
+struct CompletionMetrics {
+    telemetry: Arc<Telemetry>,
+    model: String,
+    started_at: Instant,
+    time_to_first_token: Option<Duration>,
+    completion_tokens: Option<usize>,
+    failure_reason: Option<CompletionFailureReason>,
+    metadata: HashMap<String, String>,
+}
+
+impl CompletionMetrics {
+    fn new(telemetry: Arc<Telemetry>, model: &str, metadata: HashMap<String, String>) -> Self {
+        Self {
+            telemetry,
+            model: model.to_string(),
+            started_at: Instant::now(),
+            time_to_first_token: None,
+            completion_tokens: None,
+            failure_reason: None,
+            metadata,
+        }
+    }
+
+    fn record(&mut self, event: &Result<LanguageModelCompletionEvent>) {
+        match event {
+            Ok(
+                LanguageModelCompletionEvent::Text(_)
+                | LanguageModelCompletionEvent::ToolUse(_)
+                | LanguageModelCompletionEvent::ToolUseDelta { .. },
+            ) => {
+                self.time_to_first_token
+                    .get_or_insert_with(|| self.started_at.elapsed());
+            }
+            Ok(LanguageModelCompletionEvent::Usage {
+                completion_tokens, ..
+            }) => self.completion_tokens = Some(*completion_tokens),
+            // Attribute the completion to the model that actually served it.
+            Ok(LanguageModelCompletionEvent::ResponseModel { id }) => self.model = id.clone(),
+            Ok(LanguageModelCompletionEvent::Candidate { event, .. }) => {
+                if let LanguageModelCompletionEvent::Text(_)
+                | LanguageModelCompletionEvent::ToolUse(_)
+                | LanguageModelCompletionEvent::ToolUseDelta { .. } = **event
+                {
+                    self.time_to_first_token
+                        .get_or_insert_with(|| self.started_at.elapsed());
+                }
+            }
+            Ok(
+                LanguageModelCompletionEvent::Stop(_)
+                | LanguageModelCompletionEvent::PartialUsage { .. }
+                | LanguageModelCompletionEvent::Connected { .. }
+                | LanguageModelCompletionEvent::FirstToken { .. }
+                | LanguageModelCompletionEvent::SystemFingerprint { .. }
+                | LanguageModelCompletionEvent::Fallback { .. },
+            ) => {}
+            Err(error) => self.record_failure(error),
+        }
+    }
+
+    fn record_failure(&mut self, error: &anyhow::Error) {
+        self.failure_reason
+            .get_or_insert_with(|| completion_failure_reason(error));
+    }
+}
+
+impl Drop for CompletionMetrics {
+    fn drop(&mut self) {
+        self.telemetry.report_completion_event(CompletionEvent {
+            model: std::mem::take(&mut self.model),
+            model_provider: PROVIDER_ID.to_string(),
+            time_to_first_token: self.time_to_first_token,
+            duration: self.started_at.elapsed(),
+            completion_tokens: self.completion_tokens,
+            failure_reason: self.failure_reason.take(),
+            metadata: self.metadata.drain().collect(),
+        });
+    }
+}
+
+/// Holds back the start of the response's text until it's at least `min_chars` characters
+/// long, or until `timeout` has passed since it started, and emits it as a
+/// single event. The rest of the stream is passed through unchanged.
+///
+/// Events received before any text are emitted right away, and other events received while
+/// text is held back are emitted after it, as is text still held back when the stream ends.
+fn buffer_first_text_chunk(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    min_chars: usize,
+    timeout: Duration,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: Fuse<BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+        /// The text received so far, until the first text event is emitted.
+        pending_text: String,
+        /// When the pending text is due to be emitted, however short it is.
+        deadline: Option<Task<()>>,
+        /// An event received after the pending text, which is emitted once the text has been.
+        queued_event: Option<Result<LanguageModelCompletionEvent>>,
+        /// Whether the first text event has been emitted, after which events pass through.
+        flushed: bool,
+    }
+
+    fn flush(state: &mut State) -> Result<LanguageModelCompletionEvent> {
+        state.deadline = None;
+        state.flushed = true;
+        Ok(LanguageModelCompletionEvent::Text(std::mem::take(
+            &mut state.pending_text,
+        )))
+    }
+
+    let state = State {
+        events: events.fuse(),
+        pending_text: String::new(),
+        deadline: None,
+        queued_event: None,
+        flushed: false,
+    };
+    futures::stream::unfold(state, move |mut state| {
+        let executor = executor.clone();
+        async move {
+            if let Some(event) = state.queued_event.take() {
+                return Some((event, state));
+            }
+            if state.flushed {
+                let event = state.events.next().await?;
+                return Some((event, state));
+            }
+
+            loop {
+                // `None` if the deadline passed first.
+                let next = match state.deadline.take() {
+                    Some(deadline) => {
+                        match futures::future::select(state.events.next(), deadline).await {
+                            Either::Left((event, deadline)) => {
+                                state.deadline = Some(deadline);
+                                Some(event)
+                            }
+                            Either::Right(_) => None,
+                        }
+                    }
+                    None => Some(state.events.next().await),
+                };
+                match next {
+                    Some(Some(Ok(LanguageModelCompletionEvent::Text(text)))) => {
+                        state.pending_text.push_str(&text);
+                        if state.pending_text.chars().count() >= min_chars {
+                            let text = flush(&mut state);
+                            return Some((text, state));
+                        }
+                        if state.deadline.is_none() {
+                            state.deadline = Some(executor.timer(timeout));
+                        }
+                    }
+                    Some(Some(event)) if state.pending_text.is_empty() => {
+                        return Some((event, state));
+                    }
+                    Some(Some(event)) => {
+                        state.queued_event = Some(event);
+                        let text = flush(&mut state);
+                        return Some((text, state));
+                    }
+                    Some(None) if state.pending_text.is_empty() => return None,
+                    Some(None) | None => {
+                        let text = flush(&mut state);
+                        return Some((text, state));
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Combines the text events received within `window` of the first into a single event.
+///
+/// Other events are passed through as they're received, after any text that preceded them,
+/// and any text that hasn't been emitted yet when the stream ends is emitted before it does.
+fn coalesce_text_chunks(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    window: Duration,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: Option<BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+        /// The text received since the last text event was emitted.
+        pending_text: String,
+        /// When the pending text is due to be emitted.
+        deadline: Option<Task<()>>,
+        /// An event received after the pending text, which is emitted once the text has been.
+        queued_event: Option<Result<LanguageModelCompletionEvent>>,
+    }
+
+    fn flush(state: &mut State) -> Result<LanguageModelCompletionEvent> {
+        state.deadline = None;
+        Ok(LanguageModelCompletionEvent::Text(std::mem::take(
+            &mut state.pending_text,
+        )))
+    }
+
+    let state = State {
+        events: Some(events),
+        pending_text: String::new(),
+        deadline: None,
+        queued_event: None,
+    };
+    futures::stream::unfold(state, move |mut state| {
+        let executor = executor.clone();
+        async move {
+            loop {
+                if let Some(event) = state.queued_event.take() {
+                    return Some((event, state));
+                }
+                let Some(mut events) = state.events.take() else {
+                    if state.pending_text.is_empty() {
+                        return None;
+                    }
+                    let text = flush(&mut state);
+                    return Some((text, state));
+                };
+
+                let Some(deadline) = state.deadline.take() else {
+                    match events.next().await {
+                        Some(Ok(LanguageModelCompletionEvent::Text(text))) => {
+                            state.pending_text = text;
+                            state.deadline = Some(executor.timer(window));
+                            state.events = Some(events);
+                        }
+                        Some(event) => {
+                            state.events = Some(events);
+                            return Some((event, state));
+                        }
+                        None => {}
+                    }
+                    continue;
+                };
+
+                // `None` if the deadline passed first.
+                let next = match futures::future::select(events.next(), deadline).await {
+                    Either::Left((event, deadline)) => {
+                        state.deadline = Some(deadline);
+                        Some(event)
+                    }
+                    Either::Right(_) => None,
+                };
+                match next {
+                    Some(Some(Ok(LanguageModelCompletionEvent::Text(text)))) => {
+                        state.pending_text.push_str(&text);
+                        state.events = Some(events);
+                    }
+                    Some(Some(event)) => {
+                        state.events = Some(events);
+                        state.queued_event = Some(event);
+                        let text = flush(&mut state);
+                        return Some((text, state));
+                    }
+                    Some(None) => {}
+                    None => {
+                        state.events = Some(events);
+                        let text = flush(&mut state);
+                        return Some((text, state));
+                    }
+                }
             }
         }
+    })
+    .boxed()
+}
+
+/// Returns the text of a text event, including those of candidates.
+fn event_text(event: &LanguageModelCompletionEvent) -> Option<&str> {
+    match event {
+        LanguageModelCompletionEvent::Text(text) => Some(text),
+        LanguageModelCompletionEvent::Candidate { event, .. } => event_text(event),
+        _ => None,
+    }
+}
+
+/// Estimates a response's token count while it's streamed, for
+/// [`LanguageModelCompletionEvent::PartialUsage`] events.
+///
+/// Rather than retokenizing the whole response after every chunk, the text received since
+/// the last estimate is tokenized on the background executor once it's
+/// [`PARTIAL_USAGE_INTERVAL_BYTES`] long, so that the stream isn't held up. Until then, it's
+/// estimated at four bytes per token. A token split between two batches is counted twice,
+/// which is close enough for an estimate.
+struct CompletionUsageEstimator {
+    model: open_ai::Model,
+    executor: BackgroundExecutor,
+    /// The text that has been tokenized so far.
+    counted: Arc<Mutex<CountedText>>,
+    /// The number of bytes of text in the batches sent to be tokenized.
+    batched_bytes: usize,
+    pending_text: String,
+    /// The last estimate, which later ones never go below.
+    completion_tokens: usize,
+}
+
+#[derive(Default)]
+struct CountedText {
+    tokens: usize,
+    bytes: usize,
+}
+
+impl CompletionUsageEstimator {
+    fn new(model: open_ai::Model, executor: BackgroundExecutor) -> Self {
+        Self {
+            model,
+            executor,
+            counted: Arc::default(),
+            batched_bytes: 0,
+            pending_text: String::new(),
+            completion_tokens: 0,
+        }
+    }
+
+    /// Adds text received from the response, returning a new estimate if it's time for one.
+    fn push(&mut self, text: &str) -> Option<usize> {
+        self.pending_text.push_str(text);
+        if self.pending_text.len() < PARTIAL_USAGE_INTERVAL_BYTES {
+            return None;
+        }
+        let text = std::mem::take(&mut self.pending_text);
+        self.batched_bytes += text.len();
+        let counted = self.counted.clone();
+        let model = self.model.clone();
+        self.executor
+            .spawn(async move {
+                let tokens = count_open_ai_text_tokens(&text, &model)
+                    .unwrap_or_else(|_| text.chars().count().div_ceil(4));
+                let mut counted = counted.lock();
+                counted.tokens += tokens;
+                counted.bytes += text.len();
+            })
+            .detach();
+
+        let counted = self.counted.lock();
+        let uncounted_bytes = self.batched_bytes - counted.bytes;
+        self.completion_tokens = self
+            .completion_tokens
+            .max(counted.tokens + uncounted_bytes.div_ceil(4));
+        Some(self.completion_tokens)
+    }
+}
+
+/// The error a completion fails with when it's cancelled, either by
+/// [`LanguageModelProvider::cancel_all`] or by a newer completion of the same session.
+#[derive(Debug)]
+struct CompletionCancelledError;
+
+impl fmt::Display for CompletionCancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Copilot Chat completion cancelled")
+    }
+}
+
+impl std::error::Error for CompletionCancelledError {}
+
+/// Categorizes why a completion failed. Errors that didn't come from the API itself
+/// mean the request never got a response, which we attribute to the network.
+fn completion_failure_reason(error: &anyhow::Error) -> CompletionFailureReason {
+    if let Some(error) = error.downcast_ref::<ApiError>() {
+        if error.is_unauthorized() {
+            CompletionFailureReason::Auth
+        } else if error.status == StatusCode::TOO_MANY_REQUESTS {
+            CompletionFailureReason::RateLimit
+        } else {
+            CompletionFailureReason::ApiError
+        }
+    } else if error.is::<CompletionTimeoutError>() {
+        CompletionFailureReason::Timeout
+    } else if error.is::<CompletionCancelledError>() {
+        CompletionFailureReason::Cancelled
+    } else if error.is::<serde_json::Error>() {
+        CompletionFailureReason::ApiError
+    } else {
+        CompletionFailureReason::Network
+    }
+}
+
+/// Lowers `max_tokens` so that the prompt and the completion fit in the model's context window.
+fn clamp_max_tokens(max_tokens: usize, prompt_token_count: usize, max_token_count: usize) -> usize {
+    let available_tokens = max_token_count.saturating_sub(prompt_token_count).max(1);
+    if max_tokens > available_tokens {
+        log::info!(
+            "Clamping max_tokens of {max_tokens} to {available_tokens}, as the prompt already uses {prompt_token_count} of the model's {max_token_count} tokens"
+        );
+        available_tokens
+    } else {
+        max_tokens
+    }
+}
+
+fn to_copilot_chat_request(
+    model: CopilotChatModel,
+    request: LanguageModelRequest,
+    default_system_prompt: Option<&str>,
+    system_message_policy: SystemMessagePolicy,
+) -> CopilotChatRequest {
+    let temperature = request.open_ai_temperature();
+    let top_p = request.open_ai_top_p();
+    let presence_penalty = request.open_ai_presence_penalty();
+    let frequency_penalty = request.open_ai_frequency_penalty();
+    let logit_bias = request.open_ai_logit_bias();
+    let locale_instruction = request.locale_instruction();
+    let locale_language = request.locale_language().map(ToString::to_string);
+
+    let mut messages = Vec::new();
+    for msg in request.messages {
+        match msg.role {
+            Role::User => {
+                // Each tool result is sent in its own message, following the assistant
+                // message that requested it.
+                let mut has_tool_results = false;
+                for content in &msg.content {
+                    if let MessageContent::ToolResult(tool_result) = content {
+                        has_tool_results = true;
+                        messages.push(ChatMessage {
+                            tool_call_id: Some(tool_result.tool_use_id.clone()),
+                            ..ChatMessage::new(CopilotChatRole::Tool, tool_result.content.clone())
+                        });
+                    }
+                }
+                let content = user_message_content(&msg, &model);
+                if !has_tool_results || !content.is_blank() {
+                    messages.push(ChatMessage::new(msg.role.into(), content));
+                }
+            }
+            Role::Assistant => {
+                let tool_calls = msg
+                    .content
+                    .iter()
+                    .filter_map(|content| match content {
+                        MessageContent::ToolUse(tool_use) => Some(ToolCall {
+                            id: tool_use.id.clone(),
+                            content: ToolCallContent::Function {
+                                function: FunctionContent {
+                                    name: tool_use.name.clone(),
+                                    arguments: tool_use.input.to_string(),
+                                },
+                            },
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                messages.push(ChatMessage {
+                    tool_calls,
+                    ..ChatMessage::new(msg.role.into(), msg.text_contents())
+                });
+            }
+            Role::System => {
+                messages.push(ChatMessage::new(msg.role.into(), msg.string_contents()));
+            }
+        }
+    }
+    if let Some(default_system_prompt) = default_system_prompt {
+        if !messages
+            .iter()
+            .any(|message| message.role == CopilotChatRole::System)
+        {
+            messages.insert(
+                0,
+                ChatMessage::new(CopilotChatRole::System, default_system_prompt),
+            );
+        }
+    }
+    if let Some(locale_instruction) = locale_instruction {
+        if !has_language_instruction(&messages, locale_language.as_deref()) {
+            // Placed after the leading system messages, so that it's merged into them and
+            // can never become the final message.
+            let index = messages
+                .iter()
+                .take_while(|message| message.role == CopilotChatRole::System)
+                .count();
+            messages.insert(
+                index,
+                ChatMessage::new(CopilotChatRole::System, locale_instruction),
+            );
+        }
+    }
+
+    let mut copilot_request =
+        CopilotChatRequest::new(model, normalize_messages(messages, system_message_policy));
+    if let Some(temperature) = temperature {
+        copilot_request.temperature = temperature;
+    }
+    copilot_request.top_p = top_p;
+    copilot_request.presence_penalty = presence_penalty;
+    copilot_request.frequency_penalty = frequency_penalty;
+    copilot_request.logit_bias = logit_bias;
+    copilot_request.seed = request.seed;
+    copilot_request.n = request.n.unwrap_or(1);
+    copilot_request.max_tokens = request.max_tokens;
+    copilot_request.stop = request.stop;
+    if copilot_request.stop.len() > MAX_STOP_SEQUENCES {
+        log::warn!(
+            "Copilot Chat accepts at most {MAX_STOP_SEQUENCES} stop sequences, ignoring {:?}",
+            &copilot_request.stop[MAX_STOP_SEQUENCES..]
+        );
+        copilot_request.stop.truncate(MAX_STOP_SEQUENCES);
+    }
+    if let Some(reasoning_effort) = request.reasoning_effort {
+        if model.is_reasoning_model() {
+            copilot_request.reasoning_effort = Some(match reasoning_effort {
+                ReasoningEffort::Low => CopilotChatReasoningEffort::Low,
+                ReasoningEffort::Medium => CopilotChatReasoningEffort::Medium,
+                ReasoningEffort::High => CopilotChatReasoningEffort::High,
+            });
+        } else {
+            log::debug!(
+                "{} isn't a reasoning model, ignoring reasoning effort {reasoning_effort:?}",
+                model.display_name()
+            );
+        }
+    }
+    copilot_request.response_format =
+        request
+            .response_format
+            .map(|response_format| match response_format {
+                ResponseFormat::Text => CopilotChatResponseFormat::Text,
+                ResponseFormat::JsonObject => CopilotChatResponseFormat::JsonObject,
+                ResponseFormat::JsonSchema { schema } => CopilotChatResponseFormat::JsonSchema {
+                    json_schema: JsonSchemaFormat {
+                        name: "response".into(),
+                        schema,
+                    },
+                },
+            });
+    copilot_request.tools = request
+        .tools
+        .into_iter()
+        .map(|tool| CopilotChatTool::Function {
+            function: CopilotChatFunction {
+                name: tool.name,
+                description: tool.description,
+                parameters: tool.input_schema,
+            },
+        })
+        .collect();
+    copilot_request
+}
+
+/// Whether a system message already tells the model which language to respond in.
+fn has_language_instruction(messages: &[ChatMessage], locale_language: Option<&str>) -> bool {
+    messages.iter().any(|message| {
+        message.role == CopilotChatRole::System
+            && matches!(
+                &message.content,
+                ChatMessageContent::Plain(text) if is_language_instruction(text, locale_language)
+            )
+    })
+}
+
+/// Returns the content of a user message, including its images if the model accepts them.
+fn user_message_content(
+    message: &LanguageModelRequestMessage,
+    model: &CopilotChatModel,
+) -> ChatMessageContent {
+    let has_images = message
+        .content
+        .iter()
+        .any(|content| matches!(content, MessageContent::Image(_)));
+    if !has_images {
+        return ChatMessageContent::Plain(message.text_contents());
+    }
+    if !model.supports_images() {
+        log::warn!(
+            "Dropping images from a Copilot Chat request, because {} doesn't support them",
+            model.display_name()
+        );
+        return ChatMessageContent::Plain(message.text_contents());
+    }
+
+    let parts = message
+        .content
+        .iter()
+        .filter_map(|content| match content {
+            MessageContent::Text(text) => Some(ChatMessagePart::Text { text: text.clone() }),
+            MessageContent::Image(image) => Some(ChatMessagePart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:image/png;base64,{}", image.source),
+                },
+            }),
+            MessageContent::ToolUse(_) | MessageContent::ToolResult(_) => None,
+        })
+        .collect();
+    ChatMessageContent::Multipart(parts)
+}
+
+/// Copilot Chat rejects requests in which consecutive messages share a role, so we merge
+/// consecutive messages from the same role. It also rejects system messages that follow a
+/// non-system message, so unless `system_message_policy` says otherwise, we hoist all
+/// system messages to the front first.
+fn normalize_messages(
+    messages: Vec<ChatMessage>,
+    system_message_policy: SystemMessagePolicy,
+) -> Vec<ChatMessage> {
+    let messages = match system_message_policy {
+        SystemMessagePolicy::Merge => {
+            let (system_messages, other_messages): (Vec<_>, Vec<_>) = messages
+                .into_iter()
+                .partition(|message| message.role == CopilotChatRole::System);
+            system_messages.into_iter().chain(other_messages).collect()
+        }
+        SystemMessagePolicy::Preserve => messages,
+    };
+
+    let mut normalized: Vec<ChatMessage> = Vec::new();
+    for message in messages {
+        match normalized.last_mut() {
+            // Tool results and tool calls are tied to their IDs, so they're never merged.
+            Some(last_message)
+                if last_message.role == message.role
+                    && message.role != CopilotChatRole::Tool
+                    && last_message.tool_calls.is_empty()
+                    && message.tool_calls.is_empty() =>
+            {
+                last_message.content.push(message.content);
+            }
+            _ => normalized.push(message),
+        }
+    }
+    normalized
+}
+
+/// Returns an error if `model` can't enforce `response_format`, or if its schema isn't
+/// well-formed, instead of letting the API ignore it.
+fn validate_response_format(
+    response_format: &ResponseFormat,
+    model: &CopilotChatModel,
+) -> Result<()> {
+    response_format.validate()?;
+    let supported = match response_format {
+        ResponseFormat::Text => true,
+        ResponseFormat::JsonObject => model.supports_json_object_response(),
+        ResponseFormat::JsonSchema { .. } => model.supports_json_schema_response(),
+    };
+    if supported {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} does not support structured output",
+            model.display_name()
+        ))
+    }
+}
+
+fn validate_copilot_chat_request(request: &CopilotChatRequest) -> Result<()> {
+    if let Some(message) = request.messages.last() {
+        if message.content.is_blank() {
+            const EMPTY_PROMPT_MSG: &str =
+                "Empty prompts aren't allowed. Please provide a non-empty prompt.";
+            return Err(anyhow!(EMPTY_PROMPT_MSG));
+        }
+
+        // Copilot Chat has a restriction that the final message must be from the user,
+        // or the result of a tool call. While their API does return an error message for
+        // this, we can catch it earlier and provide a more helpful error message.
+        if !matches!(message.role, CopilotChatRole::User | CopilotChatRole::Tool) {
+            const USER_ROLE_MSG: &str = "The final message must be from the user. To provide a system prompt, you must provide the system prompt followed by a user prompt.";
+            return Err(anyhow!(USER_ROLE_MSG));
+        }
+    }
+    Ok(())
+}
+
+pub enum AuthEvent {
+    /// Copilot finished signing in while the view was shown.
+    Authenticated,
+}
+
+pub struct ConfigurationView {
+    copilot_status: Option<copilot::Status>,
+    state: Model<State>,
+    /// Restarts Copilot after it failed to start, while the restart is in progress.
+    restart_copilot_task: Option<Task<()>>,
+    _subscription: Option<Subscription>,
+}
+
+impl EventEmitter<AuthEvent> for ConfigurationView {}
+
+impl ConfigurationView {
+    pub fn new(state: Model<State>, cx: &mut ViewContext<Self>) -> Self {
+        let copilot = Copilot::global(cx);
+
+        Self {
+            copilot_status: copilot.as_ref().map(|copilot| copilot.read(cx).status()),
+            state,
+            restart_copilot_task: None,
+            _subscription: copilot.as_ref().map(|copilot| {
+                cx.observe(copilot, |this, model, cx| {
+                    let status = model.read(cx).status();
+                    let was_authorized = this
+                        .copilot_status
+                        .as_ref()
+                        .map_or(false, |status| status.is_authorized());
+                    if status.is_authorized() && !was_authorized {
+                        cx.emit(AuthEvent::Authenticated);
+                    }
+                    this.copilot_status = Some(status);
+                    cx.notify();
+                })
+            }),
+        }
+    }
+
+    fn restart_copilot(&mut self, cx: &mut ViewContext<Self>) {
+        let Some(copilot) = Copilot::global(cx) else {
+            return;
+        };
+        let restart = copilot.update(cx, |copilot, cx| copilot.restart(cx));
+        self.restart_copilot_task = Some(cx.spawn(|this, mut cx| async move {
+            restart.await;
+            this.update(&mut cx, |this, cx| {
+                this.restart_copilot_task = None;
+                cx.notify();
+            })
+            .ok();
+        }));
+        cx.notify();
+    }
+}
+
+impl Render for ConfigurationView {
+    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        if self.state.read(cx).is_authenticated(cx) {
+            const LABEL: &str = "Authorized.";
+            h_flex()
+                .gap_1()
+                .child(Icon::new(IconName::Check).color(Color::Success))
+                .child(Label::new(LABEL))
+        } else {
+            let loading_icon = svg()
+                .size_8()
+                .path(IconName::ArrowCircle.path())
+                .text_color(cx.text_style().color)
+                .with_animation(
+                    "icon_circle_arrow",
+                    Animation::new(Duration::from_secs(2)).repeat(),
+                    |svg, delta| svg.with_transformation(Transformation::rotate(percentage(delta))),
+                );
+
+            const NOT_INITIALIZED_LABEL: &str = "Copilot couldn't be initialized, so Copilot Chat is unavailable. Try restarting Zed, or use a different Assistant provider.";
+            const DISABLED_LABEL: &str = "Copilot is turned off. To use Copilot Chat, enable it by setting \"inline_completion_provider\" to \"copilot\" under \"features\" in your settings.";
+
+            match &self.copilot_status {
+                Some(status) => match status {
+                    Status::Disabled => v_flex().gap_6().p_4().child(Label::new(DISABLED_LABEL)),
+                    Status::Starting { task: _ } => {
+                        const LABEL: &str = "Starting Copilot...";
+                        v_flex()
+                            .gap_6()
+                            .justify_center()
+                            .items_center()
+                            .child(Label::new(LABEL))
+                            .child(loading_icon)
+                    }
+                    Status::SigningIn { prompt: _ } => {
+                        const LABEL: &str = "Signing in to Copilot...";
+                        v_flex()
+                            .gap_6()
+                            .justify_center()
+                            .items_center()
+                            .child(Label::new(LABEL))
+                            .child(loading_icon)
+                    }
+                    Status::Error(_) => {
+                        const LABEL: &str = "Copilot had issues starting. Please try restarting it. If the issue persists, try reinstalling Copilot.";
+                        v_flex()
+                            .gap_6()
+                            .child(Label::new(LABEL))
+                            .child(svg().size_8().path(IconName::CopilotError.path()))
+                            .child(
+                                Button::new("restart_copilot", "Retry")
+                                    .icon(IconName::RotateCw)
+                                    .icon_size(IconSize::Small)
+                                    .icon_color(Color::Muted)
+                                    .icon_position(IconPosition::Start)
+                                    .style(ui::ButtonStyle::Filled)
+                                    .full_width()
+                                    .disabled(self.restart_copilot_task.is_some())
+                                    .on_click(cx.listener(|this, _, cx| this.restart_copilot(cx))),
+                            )
+                    }
+                    _ => {
+                        const LABEL: &str =
+                    "To use Zed's assistant with GitHub Copilot, you need to be logged in to GitHub. Note that your GitHub account must have an active Copilot Chat subscription.";
+                        v_flex().gap_6().child(Label::new(LABEL)).child(
+                            v_flex()
+                                .gap_2()
+                                .child(
+                                    Button::new("sign_in", "Sign In")
+                                        .icon_color(Color::Muted)
+                                        .icon(IconName::Github)
+                                        .icon_position(IconPosition::Start)
+                                        .icon_size(IconSize::Medium)
+                                        .style(ui::ButtonStyle::Filled)
+                                        .full_width()
+                                        .on_click(|_, cx| {
+                                            inline_completion_button::initiate_sign_in(cx)
+                                        }),
+                                )
+                                .child(
+                                    div().flex().w_full().items_center().child(
+                                        Label::new("Sign in to start using Github Copilot Chat.")
+                                            .color(Color::Muted)
+                                            .size(ui::LabelSize::Small),
+                                    ),
+                                ),
+                        )
+                    }
+                },
+                None => v_flex()
+                    .gap_6()
+                    .child(Label::new(NOT_INITIALIZED_LABEL))
+                    .child(
+                        Button::new("troubleshooting", "Troubleshooting")
+                            .icon(IconName::ExternalLink)
+                            .icon_size(IconSize::XSmall)
+                            .icon_color(Color::Muted)
+                            .icon_position(IconPosition::End)
+                            .on_click(|_, cx| cx.open_url(COPILOT_CHAT_DOCS_URL)),
+                    ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        LanguageModelImage, LanguageModelRequestTool, LanguageModelToolResult,
+        LanguageModelToolUse, CONTEXT_WARNING_THRESHOLD,
+    };
+    use clock::FakeSystemClock;
+    use copilot::copilot_chat::COPILOT_CHAT_AUTH_URL;
+    use futures::AsyncReadExt as _;
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, HttpClientWithUrl};
+    use semantic_version::SemanticVersion;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn message(role: Role, text: &str) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        }
+    }
+
+    fn chat_message(role: CopilotChatRole, content: impl Into<ChatMessageContent>) -> ChatMessage {
+        ChatMessage::new(role, content)
+    }
+
+    fn copilot_chat_request(messages: Vec<LanguageModelRequestMessage>) -> CopilotChatRequest {
+        to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages,
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        )
+    }
+
+    #[test]
+    fn test_alternating_roles_are_preserved() {
+        let request = copilot_chat_request(vec![
+            message(Role::System, "You are helpful."),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::User, "How are you?"),
+        ]);
+
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::System, "You are helpful."),
+                chat_message(CopilotChatRole::User, "Hi"),
+                chat_message(CopilotChatRole::Assistant, "Hello!"),
+                chat_message(CopilotChatRole::User, "How are you?"),
+            ]
+        );
+        assert!(validate_copilot_chat_request(&request).is_ok());
+    }
+
+    #[test]
+    fn test_system_messages_are_hoisted_and_merged() {
+        let request = copilot_chat_request(vec![
+            message(Role::System, "You are helpful."),
+            message(Role::User, "Hi"),
+            message(Role::System, "Be concise."),
+            message(Role::User, "How are you?"),
+        ]);
+
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::System, "You are helpful.\nBe concise."),
+                chat_message(CopilotChatRole::User, "Hi\nHow are you?"),
+            ]
+        );
+        assert!(validate_copilot_chat_request(&request).is_ok());
+    }
+
+    #[test]
+    fn test_system_messages_are_preserved_in_place() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![
+                    message(Role::System, "You are helpful."),
+                    message(Role::System, "Be concise."),
+                    message(Role::User, "Hi"),
+                    message(Role::System, "Answer in French."),
+                    message(Role::User, "How are you?"),
+                ],
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::Preserve,
+        );
+
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::System, "You are helpful.\nBe concise."),
+                chat_message(CopilotChatRole::User, "Hi"),
+                chat_message(CopilotChatRole::System, "Answer in French."),
+                chat_message(CopilotChatRole::User, "How are you?"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trailing_assistant_message_is_rejected() {
+        let request = copilot_chat_request(vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::Assistant, "How can I help?"),
+        ]);
+
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::User, "Hi"),
+                chat_message(CopilotChatRole::Assistant, "Hello!\nHow can I help?"),
+            ]
+        );
+        assert!(validate_copilot_chat_request(&request).is_err());
+    }
+
+    /// Sets up a fake Copilot Chat API that grants API tokens and answers each completion
+    /// request with the next of `responses`, given as a status code and body. Returns the
+    /// Copilot Chat model to test, and a count of the completion requests it received.
+    fn fake_copilot_chat(
+        responses: Vec<(u16, String)>,
+        cx: &mut TestAppContext,
+    ) -> (Arc<dyn LanguageModel>, Arc<AtomicUsize>) {
+        let completion_requests = Arc::new(AtomicUsize::new(0));
+        let responses = Mutex::new(VecDeque::from(responses));
+        let provider = fake_api(
+            {
+                let completion_requests = completion_requests.clone();
+                move |request| {
+                    let next_response = if request.uri().path().ends_with("/chat/completions") {
+                        completion_requests.fetch_add(1, SeqCst);
+                        responses.lock().pop_front()
+                    } else {
+                        None
+                    };
+                    let (status, body) = next_response.unwrap_or((404, String::new()));
+                    future::ready(Ok(response(status, body)))
+                }
+            },
+            cx,
+        );
+        let model = cx.update(|cx| provider.provided_models(cx).remove(0));
+        (model, completion_requests)
+    }
+
+    /// Sets up a fake Copilot Chat API that grants API tokens and answers every other
+    /// request with `respond`. Returns the Copilot Chat provider to test.
+    fn fake_api<F, Fut>(respond: F, cx: &mut TestAppContext) -> CopilotChatLanguageModelProvider
+    where
+        F: Fn(http_client::Request<http_client::AsyncBody>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<http_client::Response<http_client::AsyncBody>>>
+            + Send
+            + 'static,
+    {
+        let client = FakeHttpClient::create(move |request| {
+            if request.uri().to_string() == COPILOT_CHAT_AUTH_URL {
+                Either::Left(future::ready(Ok(response(200, api_token_body()))))
+            } else {
+                Either::Right(respond(request))
+            }
+        });
+        fake_provider(client, cx)
+    }
+
+    fn response(
+        status: u16,
+        body: impl Into<http_client::AsyncBody>,
+    ) -> http_client::Response<http_client::AsyncBody> {
+        http_client::Response::builder()
+            .status(status)
+            .body(body.into())
+            .unwrap()
+    }
+
+    fn fake_provider(
+        client: Arc<HttpClientWithUrl>,
+        cx: &mut TestAppContext,
+    ) -> CopilotChatLanguageModelProvider {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            release_channel::init(SemanticVersion::default(), cx);
+            AllLanguageModelSettings::register(cx);
+
+            let copilot_chat = cx.new_model(|_| CopilotChat::fake(client.clone()));
+            CopilotChat::set_global(copilot_chat, cx);
+
+            let telemetry = Telemetry::new(Arc::new(FakeSystemClock::default()), client, cx);
+            CopilotChatLanguageModelProvider::new(telemetry, cx)
+        })
+    }
+
+    /// A provider whose completion requests all fail with a 503, so that its completions
+    /// stay in flight while they're retried.
+    fn unavailable_provider(cx: &mut TestAppContext) -> CopilotChatLanguageModelProvider {
+        fake_api(
+            |_| future::ready(Ok(response(503, "Service Unavailable"))),
+            cx,
+        )
+    }
+
+    /// The body of a response granting an API token that expires in half an hour.
+    fn api_token_body() -> String {
+        format!(
+            r#"{{"token":"api-key","expires_at":{}}}"#,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 30 * 60
+        )
+    }
+
+    /// Formats responses as the server-sent events the Copilot Chat API streams.
+    fn sse_body(responses: &[&str]) -> String {
+        let mut body = String::new();
+        for response in responses {
+            body.push_str(&format!("data: {response}\n\n"));
+        }
+        body.push_str("data: [DONE]\n\n");
+        body
+    }
+
+    fn completion_events(responses: &[&str]) -> Vec<LanguageModelCompletionEvent> {
+        let responses = responses
+            .iter()
+            .map(|response| Ok(serde_json::from_str::<ResponseEvent>(response).unwrap()))
+            .collect::<Vec<_>>();
+        smol::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(responses).boxed(), 1)
+                .map(|event| event.unwrap())
+                .collect(),
+        )
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_over_http(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![(
+                200,
+                sse_body(&[
+                    r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":"assistant"}}],"created":0,"id":"1"}"#,
+                    r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":null}}],"created":0,"id":"1"}"#,
+                    r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+                ]),
+            )],
+            cx,
+        );
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let events = model.stream_completion(request, &cx.to_async());
+        let events = cx
+            .executor()
+            .spawn(async move { anyhow::Ok(events.await?.collect::<Vec<_>>().await) })
+            .await
+            .unwrap();
+
+        let mut events = events.into_iter().map(Result::unwrap);
+        assert!(matches!(
+            events.next(),
+            Some(LanguageModelCompletionEvent::Connected { .. })
+        ));
+        assert!(matches!(
+            events.next(),
+            Some(LanguageModelCompletionEvent::FirstToken { .. })
+        ));
+        assert_eq!(
+            events.collect::<Vec<_>>(),
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+        assert_eq!(completion_requests.load(SeqCst), 1);
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_retries_transient_errors(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![
+                (503, "Service Unavailable".into()),
+                (
+                    200,
+                    sse_body(&[
+                        r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+                    ]),
+                ),
+            ],
+            cx,
+        );
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let text = model.complete(request, &cx.to_async());
+        let text = cx.executor().spawn(text);
+
+        cx.executor().run_until_parked();
+        assert_eq!(completion_requests.load(SeqCst), 1);
+
+        cx.executor().advance_clock(Duration::from_secs(10));
+        assert_eq!(text.await.unwrap(), "Hello");
+        assert_eq!(completion_requests.load(SeqCst), 2);
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_falls_back_when_unavailable(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![
+                (503, "Service Unavailable".into()),
+                (
+                    200,
+                    sse_body(&[
+                        r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+                    ]),
+                ),
+                (400, "Bad Request".into()),
+            ],
+            cx,
+        );
+        cx.update_global::<SettingsStore, _>(|store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models":{"copilot_chat":{"max_retries":0,"fallback_model":"gpt-3.5-turbo"}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+
+        // An unavailable model falls back, and the completion says so.
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        let events = cx.executor().spawn(events).await.unwrap();
+        let events = events.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(completion_requests.load(SeqCst), 2);
+        assert_eq!(
+            events[0],
+            LanguageModelCompletionEvent::Fallback {
+                model_id: "gpt-3.5-turbo".into()
+            }
+        );
+        assert!(events.contains(&LanguageModelCompletionEvent::Text("Hello".into())));
+
+        // A rejected request doesn't.
+        let events = model.stream_completion(request, &cx.to_async());
+        assert!(cx.executor().spawn(events).await.is_err());
+        assert_eq!(completion_requests.load(SeqCst), 3);
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_after_signing_out(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(Vec::new(), cx);
+        cx.update(|cx| {
+            CopilotChat::global(cx)
+                .unwrap()
+                .update(cx, |copilot_chat, cx| {
+                    copilot_chat.set_oauth_token_for_test(None, cx)
+                });
+        });
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let events = model.stream_completion(request, &cx.to_async());
+        let error = match cx.executor().spawn(events).await {
+            Ok(_) => panic!("expected the completion to fail"),
+            Err(error) => error,
+        };
+
+        assert!(error.is::<NotSignedInError>());
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::NotAuthenticated)
+        );
+        assert_eq!(completion_requests.load(SeqCst), 0);
+    }
+
+    #[gpui::test]
+    async fn test_reset_credentials(cx: &mut TestAppContext) {
+        let provider = fake_api(|_| future::ready(Ok(response(404, ""))), cx);
+        let copilot_chat = cx.update(|cx| CopilotChat::global(cx).unwrap());
+
+        // Authenticating requests an API token to check that the OAuth token is still valid.
+        cx.update(|cx| provider.authenticate(cx)).await.unwrap();
+        assert!(copilot_chat.read_with(cx, |copilot_chat, _| copilot_chat.has_api_token()));
+
+        cx.update(|cx| provider.reset_credentials(cx))
+            .await
+            .unwrap();
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert!(!copilot_chat.read_with(cx, |copilot_chat, _| copilot_chat.has_api_token()));
+        provider.state.read_with(cx, |state, _| {
+            assert!(state.models.is_none());
+            assert!(state.verified_at.is_none());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_authenticate_without_oauth_token(cx: &mut TestAppContext) {
+        let (copilot, _lsp) = Copilot::fake(cx);
+        let provider = unavailable_provider(cx);
+        cx.update(|cx| {
+            Copilot::set_global(copilot, cx);
+            CopilotChat::global(cx)
+                .unwrap()
+                .update(cx, |copilot_chat, cx| {
+                    copilot_chat.set_oauth_token_for_test(None, cx)
+                });
+        });
+
+        // Copilot is signed in, but its OAuth token was discarded, so the user has to be
+        // prompted to sign in again.
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        let error = cx.update(|cx| provider.authenticate(cx)).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::NotAuthenticated)
+        );
+        assert!(error.to_string().contains("no valid OAuth token"));
+    }
+
+    #[gpui::test]
+    async fn test_authenticate_again_after_rejected_refresh(cx: &mut TestAppContext) {
+        // The OAuth token is rejected until the user renews their subscription.
+        let subscription_expired = Arc::new(AtomicBool::new(true));
+        let client = FakeHttpClient::create({
+            let subscription_expired = subscription_expired.clone();
+            move |request| {
+                let (status, body) = if request.uri().to_string() != COPILOT_CHAT_AUTH_URL {
+                    (404, String::new())
+                } else if subscription_expired.load(SeqCst) {
+                    (401, String::new())
+                } else {
+                    (200, api_token_body())
+                };
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(status)
+                        .body(http_client::AsyncBody::from(body))
+                        .unwrap())
+                }
+            }
+        });
+        let (copilot, _lsp) = Copilot::fake(cx);
+        cx.update(|cx| Copilot::set_global(copilot, cx));
+        let provider = fake_provider(client, cx);
+
+        // Fetching the models refreshes the API token, which is rejected.
+        cx.run_until_parked();
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+
+        // Authenticating tries the rejected token again, and fails while it's still rejected.
+        let error = cx.update(|cx| provider.authenticate(cx)).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::NotAuthenticated)
+        );
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+
+        subscription_expired.store(false, SeqCst);
+        cx.update(|cx| provider.authenticate(cx)).await.unwrap();
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_does_not_retry_unauthorized(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![(
+                403,
+                r#"{"error":{"message":"Your Copilot subscription has expired"}}"#.into(),
+            )],
+            cx,
+        );
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let events = model.stream_completion(request, &cx.to_async());
+        let error = match cx.executor().spawn(events).await {
+            Ok(_) => panic!("expected the completion to fail"),
+            Err(error) => error,
+        };
+
+        assert!(error.downcast_ref::<ApiError>().unwrap().is_unauthorized());
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::NotAuthenticated)
+        );
+        assert!(error
+            .to_string()
+            .starts_with("Copilot Chat API error (403 Forbidden)"));
+        assert!(error.to_string().contains("(request ID: "));
+        assert_eq!(completion_requests.load(SeqCst), 1);
+    }
+
+    #[gpui::test]
+    async fn test_content_filter(cx: &mut TestAppContext) {
+        let blocked = r#"{"error":{"code":"content_filter","message":"The prompt was filtered"}}"#;
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![
+                (400, blocked.into()),
+                (
+                    200,
+                    sse_body(&[
+                        r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hel","role":"assistant"}}],"created":0,"id":"1"}"#,
+                        r#"{"choices":[{"index":0,"finish_reason":"content_filter","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+                    ]),
+                ),
+                (
+                    200,
+                    sse_body(&[
+                        r#"{"choices":[{"index":1,"finish_reason":"content_filter","delta":{"content":null,"role":"assistant"}}],"created":0,"id":"1"}"#,
+                    ]),
+                ),
+            ],
+            cx,
+        );
+        cx.update_global::<SettingsStore, _>(|store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models":{"copilot_chat":{"retry_on_content_filter":true}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+
+        // A rejected request fails with a content-filter error, without being sent again.
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        let error = match cx.executor().spawn(events).await {
+            Ok(_) => panic!("expected the completion to fail"),
+            Err(error) => error,
+        };
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ContentFiltered)
+        );
+        assert_eq!(completion_requests.load(SeqCst), 1);
+
+        // A stopped response fails.
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        let events = cx.executor().spawn(events).await.unwrap();
+        let events = events.collect::<Vec<_>>().await;
+        assert_eq!(completion_requests.load(SeqCst), 2);
+        assert!(matches!(
+            events[events.len() - 2],
+            Ok(LanguageModelCompletionEvent::Text(ref text)) if text == "Hel"
+        ));
+        let error = events.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ContentFiltered)
+        );
+
+        // So does a response with a stopped candidate.
+        let events = model.stream_completion(
+            LanguageModelRequest {
+                n: Some(2),
+                ..request
+            },
+            &cx.to_async(),
+        );
+        let events = cx.executor().spawn(events).await.unwrap();
+        let events = events.collect::<Vec<_>>().await;
+        assert!(events.iter().any(|event| {
+            event.as_ref().is_err_and(|error| {
+                error.downcast_ref::<LanguageModelError>()
+                    == Some(&LanguageModelError::ContentFiltered)
+            })
+        }));
+        assert_eq!(completion_requests.load(SeqCst), 3);
+    }
+
+    #[gpui::test]
+    async fn test_strict_validation(cx: &mut TestAppContext) {
+        let (model, completion_requests) = fake_copilot_chat(
+            vec![(
+                200,
+                sse_body(&[
+                    r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":" world","role":"assistant"}}],"created":0,"id":"1"}"#,
+                ]),
+            )],
+            cx,
+        );
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "Say hello world"),
+                message(Role::Assistant, "Hello"),
+            ],
+            ..Default::default()
+        };
+
+        // By default, a request ending with an assistant message fails before it's sent.
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        assert!(cx.executor().spawn(events).await.is_err());
+        assert_eq!(completion_requests.load(SeqCst), 0);
+
+        cx.update_global::<SettingsStore, _>(|store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models":{"copilot_chat":{"strict_validation":false}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        let events = model.stream_completion(request, &cx.to_async());
+        let events = cx.executor().spawn(events).await.unwrap();
+        let events = events.collect::<Vec<_>>().await;
+        assert_eq!(completion_requests.load(SeqCst), 1);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Ok(LanguageModelCompletionEvent::Text(text)) if text == " world"
+        )));
+    }
+
+    #[gpui::test]
+    async fn test_interrupted_stream_returns_partial_text(cx: &mut TestAppContext) {
+        let provider = fake_api(
+            |_| {
+                let chunks = vec![
+                    Ok(sse_body(&[
+                        r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+                        r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":" there","role":null}}],"created":0,"id":"1"}"#,
+                    ])),
+                    Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+                ];
+                let body = http_client::AsyncBody::from_reader(
+                    futures::TryStreamExt::into_async_read(futures::stream::iter(chunks)),
+                );
+                future::ready(Ok(response(200, body)))
+            },
+            cx,
+        );
+        let model = cx.update(|cx| provider.provided_models(cx).remove(0));
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let error = cx
+            .executor()
+            .spawn(model.complete(request, &cx.to_async()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::Interrupted {
+                partial: "Hello there".into()
+            })
+        );
+        assert!(error.to_string().contains("(request ID: "));
+    }
+
+    #[gpui::test]
+    async fn test_tool_call_loop_over_http(cx: &mut TestAppContext) {
+        let responses = Mutex::new(VecDeque::from([
+            sse_body(&[
+                r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Let me check.","role":"assistant"}}],"created":0,"id":"1"}"#,
+                r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":null,"tool_calls":[{"index":0,"id":"call_1","function":{"name":"now","arguments":"{\"timezone\":\"utc\"}"}}]}}],"created":0,"id":"1"}"#,
+                r#"{"choices":[{"index":0,"finish_reason":"tool_calls","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+            ]),
+            sse_body(&[
+                r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":"It's noon.","role":"assistant"}}],"created":0,"id":"2"}"#,
+            ]),
+        ]));
+        let completion_requests = Arc::new(Mutex::new(Vec::new()));
+        let provider = fake_api(
+            {
+                let completion_requests = completion_requests.clone();
+                move |request| {
+                    let is_completion = request.uri().path().ends_with("/chat/completions");
+                    let body = if is_completion {
+                        responses.lock().pop_front()
+                    } else {
+                        None
+                    };
+                    let completion_requests = completion_requests.clone();
+                    async move {
+                        if is_completion {
+                            let mut body = String::new();
+                            request.into_body().read_to_string(&mut body).await?;
+                            completion_requests
+                                .lock()
+                                .push(serde_json::from_str::<serde_json::Value>(&body)?);
+                        }
+                        Ok(match body {
+                            Some(body) => response(200, body),
+                            None => response(404, ""),
+                        })
+                    }
+                }
+            },
+            cx,
+        );
+        let model = cx.update(|cx| provider.provided_models(cx).remove(0));
+        let tools = vec![LanguageModelRequestTool {
+            name: "now".into(),
+            description: "Returns the current time".into(),
+            input_schema: serde_json::json!({ "type": "object" }),
+        }];
+
+        // The model answers the first turn with a tool call.
+        let mut request = LanguageModelRequest {
+            messages: vec![message(Role::User, "What time is it?")],
+            tools: tools.clone(),
+            ..Default::default()
+        };
+        let events = model.stream_completion(request.clone(), &cx.to_async());
+        let events = cx
+            .executor()
+            .spawn(async move { anyhow::Ok(events.await?.collect::<Vec<_>>().await) })
+            .await
+            .unwrap();
+        let mut assistant_content = Vec::new();
+        for event in events {
+            match event.unwrap() {
+                LanguageModelCompletionEvent::Text(text) => {
+                    assistant_content.push(MessageContent::Text(text))
+                }
+                LanguageModelCompletionEvent::ToolUse(tool_use) => {
+                    assistant_content.push(MessageContent::ToolUse(tool_use))
+                }
+                LanguageModelCompletionEvent::Stop(reason) => {
+                    assert_eq!(reason, StopReason::ToolUse)
+                }
+                _ => {}
+            }
+        }
+        let Some(MessageContent::ToolUse(tool_use)) = assistant_content.last() else {
+            panic!("expected a tool use, got {assistant_content:?}");
+        };
+        assert_eq!(tool_use.id, "call_1");
+
+        // The second turn sends the tool call back along with its result.
+        let tool_use_id = tool_use.id.clone();
+        request.messages.extend([
+            LanguageModelRequestMessage {
+                role: Role::Assistant,
+                content: assistant_content,
+                cache: false,
+            },
+            LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                    tool_use_id,
+                    is_error: false,
+                    content: "12:00".into(),
+                })],
+                cache: false,
+            },
+        ]);
+        let text = cx
+            .executor()
+            .spawn(model.complete(request, &cx.to_async()))
+            .await
+            .unwrap();
+        assert_eq!(text, "It's noon.");
+
+        let completion_requests = completion_requests.lock();
+        assert_eq!(completion_requests.len(), 2);
+        assert_eq!(
+            completion_requests[1]["messages"],
+            serde_json::json!([
+                { "role": "user", "content": "What time is it?" },
+                {
+                    "role": "assistant",
+                    "content": "Let me check.",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "now", "arguments": r#"{"timezone":"utc"}"# },
+                    }],
+                },
+                { "role": "tool", "content": "12:00", "tool_call_id": "call_1" },
+            ])
+        );
+    }
+
+    #[gpui::test]
+    async fn test_cancel_all(cx: &mut TestAppContext) {
+        let provider = unavailable_provider(cx);
+        let model = cx.update(|cx| provider.provided_models(cx).remove(0));
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            ..Default::default()
+        };
+        let events = cx
+            .executor()
+            .spawn(model.stream_completion(request, &cx.to_async()));
+        cx.executor().run_until_parked();
+        cx.update(|cx| provider.cancel_all(cx));
+
+        let error = match events.await {
+            Ok(_) => panic!("expected the completion to be cancelled"),
+            Err(error) => error,
+        };
+        assert_eq!(error.to_string(), "Copilot Chat completion cancelled");
+        assert_eq!(
+            completion_failure_reason(&error),
+            CompletionFailureReason::Cancelled
+        );
+        cx.read(|cx| {
+            assert!(provider
+                .state
+                .read(cx)
+                .in_flight_completions
+                .completions
+                .lock()
+                .is_empty())
+        });
+    }
+
+    #[gpui::test]
+    async fn test_new_completion_cancels_previous_one_of_same_session(cx: &mut TestAppContext) {
+        let provider = unavailable_provider(cx);
+        let model = cx.update(|cx| provider.provided_models(cx).remove(0));
+        let request = |session_id: &str| LanguageModelRequest {
+            messages: vec![message(Role::User, "Hi")],
+            session_id: Some(session_id.into()),
+            ..Default::default()
+        };
+        let in_flight_completions = |cx: &mut TestAppContext| {
+            cx.read(|cx| {
+                provider
+                    .state
+                    .read(cx)
+                    .in_flight_completions
+                    .completions
+                    .lock()
+                    .len()
+            })
+        };
+
+        let first = cx
+            .executor()
+            .spawn(model.stream_completion(request("a"), &cx.to_async()));
+        let other = cx
+            .executor()
+            .spawn(model.stream_completion(request("b"), &cx.to_async()));
+        cx.executor().run_until_parked();
+        assert_eq!(in_flight_completions(cx), 2);
+
+        let second = cx
+            .executor()
+            .spawn(model.stream_completion(request("a"), &cx.to_async()));
+        let error = first
+            .await
+            .err()
+            .expect("expected the first completion to be cancelled");
+        assert_eq!(error.to_string(), "Copilot Chat completion cancelled");
+        assert_eq!(
+            completion_failure_reason(&error),
+            CompletionFailureReason::Cancelled
+        );
+        cx.executor().run_until_parked();
+        assert_eq!(in_flight_completions(cx), 2);
+
+        cx.update(|cx| provider.cancel_all(cx));
+        assert!(second.await.is_err());
+        assert!(other.await.is_err());
+        assert_eq!(in_flight_completions(cx), 0);
+    }
+
+    #[gpui::test]
+    async fn test_list_models(cx: &mut TestAppContext) {
+        let provider = fake_api(
+            |_| {
+                future::ready(Ok(response(
+                    200,
+                    r#"{"data":[
+                        {"id":"gpt-4o","capabilities":{"type":"chat","limits":{"max_context_window_tokens":64000}}},
+                        {"id":"o1-mini","capabilities":{"type":"chat","limits":{"max_context_window_tokens":128000}}},
+                        {"id":"o1-preview","capabilities":{"type":"chat"},"policy":{"state":"enabled"}},
+                        {"id":"claude-3.5-sonnet","capabilities":{"type":"chat"},"policy":{"state":"disabled"}},
+                        {"id":"text-embedding-3-small","capabilities":{"type":"embeddings"}}
+                    ]}"#,
+                )))
+            },
+            cx,
+        );
+
+        let models = cx.update(|cx| provider.list_models(cx)).await.unwrap();
+        let models = models
+            .iter()
+            .map(|model| (model.id().0.to_string(), model.max_token_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            models,
+            [
+                ("gpt-4o".to_string(), 64000),
+                ("o1-mini".to_string(), 128000),
+                ("o1-preview".to_string(), 8192),
+            ]
+        );
+
+        let provided_models = cx.update(|cx| provider.provided_models(cx));
+        assert_eq!(provided_models.len(), 3);
+    }
+
+    #[gpui::test]
+    async fn test_embed(cx: &mut TestAppContext) {
+        let provider = fake_api(
+            |request| {
+                assert!(request.uri().to_string().ends_with("/embeddings"));
+                // The API may list the embeddings in any order.
+                future::ready(Ok(response(
+                    200,
+                    r#"{"data":[
+                        {"index":1,"embedding":[0.0,1.0]},
+                        {"index":0,"embedding":[1.0,0.0]}
+                    ]}"#,
+                )))
+            },
+            cx,
+        );
+        let model = cx.update(|cx| provider.embedding_models(cx).remove(0));
+        assert_eq!(model.id().0.as_ref(), "text-embedding-3-small");
+
+        let texts = vec!["fn main() {}".to_string(), "struct Point;".to_string()];
+        let embeddings = model.embed(texts, &cx.to_async()).await.unwrap();
+        assert_eq!(embeddings, [vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+
+    #[gpui::test]
+    async fn test_dropping_provider_aborts_api_token_request(cx: &mut TestAppContext) {
+        // Each request that is in flight holds a clone of this, which is dropped when the
+        // request is aborted.
+        let in_flight = Arc::new(());
+        let client = FakeHttpClient::create({
+            let in_flight = in_flight.clone();
+            move |_| {
+                let in_flight = in_flight.clone();
+                async move {
+                    let _in_flight = in_flight;
+                    future::pending::<Result<http_client::Response<http_client::AsyncBody>>>().await
+                }
+            }
+        });
+        let provider = fake_provider(client, cx);
+
+        // Creating the provider fetches the models, which requests an API token first.
+        cx.run_until_parked();
+        assert_eq!(Arc::strong_count(&in_flight), 3);
+
+        drop(provider);
+        cx.update(|_| {});
+        cx.run_until_parked();
+        assert_eq!(Arc::strong_count(&in_flight), 2);
+    }
+
+    #[test]
+    fn test_finish_reason_is_emitted_as_stop_event() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"length","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::MaxTokens),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chunks_without_content_are_skipped() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":"assistant"}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":null}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":", world!","role":null}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Text(", world!".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tool_calls_are_emitted_as_tool_use_events() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":"assistant","tool_calls":[{"index":0,"id":"call_1","function":{"name":"now","arguments":""}}]}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":null,"tool_calls":[{"index":0,"function":{"arguments":"{\"timezone\":"}}]}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":null,"role":null,"tool_calls":[{"index":0,"function":{"arguments":"\"utc\"}"}}]}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"tool_calls","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+        ]);
+
+        let delta =
+            |name_delta: &str, arguments_delta: &str| LanguageModelCompletionEvent::ToolUseDelta {
+                index: 0,
+                name_delta: name_delta.into(),
+                arguments_delta: arguments_delta.into(),
+            };
+        assert_eq!(
+            events,
+            vec![
+                delta("now", ""),
+                delta("", r#"{"timezone":"#),
+                delta("", r#""utc"}"#),
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "now".into(),
+                    input: serde_json::json!({ "timezone": "utc" }),
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tool_uses_and_results_are_sent_as_tool_messages() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![
+                    message(Role::User, "What time is it?"),
+                    LanguageModelRequestMessage {
+                        role: Role::Assistant,
+                        content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                            id: "call_1".into(),
+                            name: "now".into(),
+                            input: serde_json::json!({ "timezone": "utc" }),
+                        })],
+                        cache: false,
+                    },
+                    LanguageModelRequestMessage {
+                        role: Role::User,
+                        content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                            tool_use_id: "call_1".into(),
+                            is_error: false,
+                            content: "12:00".into(),
+                        })],
+                        cache: false,
+                    },
+                ],
+                tools: vec![LanguageModelRequestTool {
+                    name: "now".into(),
+                    description: "Returns the current time".into(),
+                    input_schema: serde_json::json!({ "type": "object" }),
+                }],
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::User, "What time is it?"),
+                ChatMessage {
+                    tool_calls: vec![ToolCall {
+                        id: "call_1".into(),
+                        content: ToolCallContent::Function {
+                            function: FunctionContent {
+                                name: "now".into(),
+                                arguments: r#"{"timezone":"utc"}"#.into(),
+                            },
+                        },
+                    }],
+                    ..chat_message(CopilotChatRole::Assistant, "")
+                },
+                ChatMessage {
+                    tool_call_id: Some("call_1".into()),
+                    ..chat_message(CopilotChatRole::Tool, "12:00")
+                },
+            ]
+        );
+        assert_eq!(request.tools.len(), 1);
+        assert!(validate_copilot_chat_request(&request).is_ok());
+    }
+
+    #[test]
+    fn test_stream_closed_without_finish_reason() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::Unknown),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_response_model_is_emitted_once() {
+        let events = completion_events(&[
+            r#"{"choices":[],"created":0,"id":"1","model":""}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hi","role":"assistant"}}],"created":0,"id":"1","model":"gpt-4o-2024-05-13"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1","model":"gpt-4o-2024-05-13"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::ResponseModel {
+                    id: "gpt-4o-2024-05-13".into()
+                },
+                LanguageModelCompletionEvent::Text("Hi".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system_fingerprint_is_emitted_once() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hi","role":"assistant"}}],"created":0,"id":"1","system_fingerprint":"fp_1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1","system_fingerprint":"fp_1"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::SystemFingerprint {
+                    fingerprint: "fp_1".into()
+                },
+                LanguageModelCompletionEvent::Text("Hi".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chunks_without_choices_are_skipped() {
+        let events = completion_events(&[
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Hello","role":"assistant"}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":", world!","role":null}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":0,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+        ]);
+
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Text(", world!".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_coalesce_text_chunks(cx: &mut TestAppContext) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut events =
+            coalesce_text_chunks(rx.boxed(), Duration::from_millis(100), cx.executor());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let task = cx.executor().spawn({
+            let received = received.clone();
+            async move {
+                while let Some(event) = events.next().await {
+                    received.lock().push(event.unwrap());
+                }
+            }
+        });
+        let text = |text: &str| LanguageModelCompletionEvent::Text(text.into());
+
+        // Text is held back until the window has passed.
+        tx.unbounded_send(Ok(text("Hel"))).unwrap();
+        tx.unbounded_send(Ok(text("lo"))).unwrap();
+        cx.run_until_parked();
+        assert!(received.lock().is_empty());
+        cx.executor().advance_clock(Duration::from_millis(100));
+        cx.run_until_parked();
+        assert_eq!(*received.lock(), vec![text("Hello")]);
+
+        // Other events are emitted right away, after the text before them.
+        tx.unbounded_send(Ok(text(" world"))).unwrap();
+        tx.unbounded_send(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+            .unwrap();
+        cx.run_until_parked();
+        assert_eq!(
+            received.lock()[1..],
+            [
+                text(" world"),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn)
+            ]
+        );
+
+        // The text received before the stream ends isn't lost.
+        tx.unbounded_send(Ok(text("!"))).unwrap();
+        drop(tx);
+        task.await;
+        assert_eq!(received.lock().last(), Some(&text("!")));
+    }
+
+    #[gpui::test]
+    async fn test_buffer_first_text_chunk(cx: &mut TestAppContext) {
+        let timeout = Duration::from_millis(200);
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut events = buffer_first_text_chunk(rx.boxed(), 5, timeout, cx.executor());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let task = cx.executor().spawn({
+            let received = received.clone();
+            async move {
+                while let Some(event) = events.next().await {
+                    received.lock().push(event.unwrap());
+                }
+            }
+        });
+        let text = |text: &str| LanguageModelCompletionEvent::Text(text.into());
+        let stop = LanguageModelCompletionEvent::Stop(StopReason::EndTurn);
+
+        // Text is held back until it's long enough, and passed through after that.
+        tx.unbounded_send(Ok(text("H"))).unwrap();
+        tx.unbounded_send(Ok(text("el"))).unwrap();
+        cx.run_until_parked();
+        assert!(received.lock().is_empty());
+        tx.unbounded_send(Ok(text("lo"))).unwrap();
+        tx.unbounded_send(Ok(text(","))).unwrap();
+        cx.run_until_parked();
+        assert_eq!(*received.lock(), vec![text("Hello"), text(",")]);
+        drop(tx);
+        task.await;
+
+        // Short text is emitted once the timeout has passed.
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let events = buffer_first_text_chunk(rx.boxed(), 5, timeout, cx.executor());
+        let events = cx.executor().spawn(events.collect::<Vec<_>>());
+        tx.unbounded_send(Ok(text("Hi"))).unwrap();
+        cx.run_until_parked();
+        cx.executor().advance_clock(timeout);
+        cx.run_until_parked();
+        tx.unbounded_send(Ok(text("!"))).unwrap();
+        drop(tx);
+        let events = events
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![text("Hi"), text("!")]);
+
+        // Text held back when the response ends isn't lost, and comes before what follows it.
+        let events = buffer_first_text_chunk(
+            futures::stream::iter([Ok(text("Hi")), Ok(stop.clone())]).boxed(),
+            5,
+            timeout,
+            cx.executor(),
+        );
+        let events = events.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(events, vec![text("Hi"), stop]);
+        let events = buffer_first_text_chunk(
+            futures::stream::iter([Ok(text("Hi"))]).boxed(),
+            5,
+            timeout,
+            cx.executor(),
+        );
+        let events = events.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(events, vec![text("Hi")]);
+    }
+
+    #[gpui::test]
+    fn test_completion_usage_estimator(cx: &mut TestAppContext) {
+        let mut estimator = CompletionUsageEstimator::new(open_ai::Model::FourOmni, cx.executor());
+        let chunk = "The quick brown fox jumps over the lazy dog. ";
+        // An estimate is made every 9 chunks, once 400 bytes have been received.
+        let batch = chunk.repeat(9);
+        let batch_token_count = count_open_ai_text_tokens(&batch, &estimator.model).unwrap();
+        let batch_byte_estimate = batch.len().div_ceil(4);
+
+        // Text that's still being tokenized in the background is estimated from its length.
+        let mut estimates = Vec::new();
+        for _ in 0..9 {
+            estimates.extend(estimator.push(chunk));
+        }
+        assert_eq!(estimates, vec![batch_byte_estimate]);
+
+        cx.run_until_parked();
+        for _ in 0..9 {
+            estimates.extend(estimator.push(chunk));
+        }
+        assert_eq!(
+            estimates,
+            vec![batch_byte_estimate, batch_token_count + batch_byte_estimate]
+        );
+
+        cx.run_until_parked();
+        assert_eq!(estimator.counted.lock().tokens, 2 * batch_token_count);
+
+        // Candidates' text is counted too.
+        let candidate = LanguageModelCompletionEvent::Candidate {
+            choice_index: 1,
+            event: Box::new(LanguageModelCompletionEvent::Text(chunk.into())),
+        };
+        assert_eq!(event_text(&candidate), Some(chunk));
+    }
+
+    #[test]
+    fn test_multiple_choices() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Name a color")],
+                n: Some(2),
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(serde_json::to_value(request).unwrap()["n"], 2);
+
+        let responses = [
+            r#"{"choices":[{"index":0,"finish_reason":null,"delta":{"content":"Red","role":"assistant"}},{"index":1,"finish_reason":null,"delta":{"content":"Blue","role":"assistant"}}],"created":0,"id":"1"}"#,
+            r#"{"choices":[{"index":1,"finish_reason":"stop","delta":{"content":null,"role":null}}],"created":0,"id":"1"}"#,
+        ]
+        .iter()
+        .map(|response| Ok(serde_json::from_str::<ResponseEvent>(response).unwrap()))
+        .collect::<Vec<_>>();
+        let events = smol::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(responses).boxed(), 2)
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let candidate = |choice_index, event| LanguageModelCompletionEvent::Candidate {
+            choice_index,
+            event: Box::new(event),
+        };
+        assert_eq!(
+            events,
+            vec![
+                candidate(0, LanguageModelCompletionEvent::Text("Red".into())),
+                candidate(1, LanguageModelCompletionEvent::Text("Blue".into())),
+                candidate(1, LanguageModelCompletionEvent::Stop(StopReason::EndTurn)),
+                // The stream was closed before the first choice finished.
+                candidate(0, LanguageModelCompletionEvent::Stop(StopReason::Unknown)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_closed_without_choices() {
+        let responses = vec![Ok(serde_json::from_str::<ResponseEvent>(
+            r#"{"choices":[],"created":0,"id":"1"}"#,
+        )
+        .unwrap())];
+        let events = smol::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(responses).boxed(), 1)
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_err());
+    }
+
+    #[test]
+    fn test_images_are_sent_to_models_that_support_them() {
+        let image: LanguageModelImage = serde_json::from_value(serde_json::json!({
+            "source": "aGk=",
+            "size": { "width": 1, "height": 1 },
+        }))
+        .unwrap();
+        let request = |model| {
+            to_copilot_chat_request(
+                model,
+                LanguageModelRequest {
+                    messages: vec![LanguageModelRequestMessage {
+                        role: Role::User,
+                        content: vec![
+                            MessageContent::Text("What's this?".into()),
+                            MessageContent::Image(image.clone()),
+                        ],
+                        cache: false,
+                    }],
+                    ..Default::default()
+                },
+                None,
+                SystemMessagePolicy::default(),
+            )
+        };
+
+        assert_eq!(
+            request(CopilotChatModel::Gpt4o).messages,
+            vec![chat_message(
+                CopilotChatRole::User,
+                ChatMessageContent::Multipart(vec![
+                    ChatMessagePart::Text {
+                        text: "What's this?".into()
+                    },
+                    ChatMessagePart::ImageUrl {
+                        image_url: ImageUrl {
+                            url: "data:image/png;base64,aGk=".into()
+                        }
+                    },
+                ])
+            )]
+        );
+        assert_eq!(
+            request(CopilotChatModel::Gpt3_5Turbo).messages,
+            vec![chat_message(CopilotChatRole::User, "What's this?")]
+        );
+    }
+
+    #[test]
+    fn test_default_system_prompt() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Hi")],
+                ..Default::default()
+            },
+            Some("Be concise."),
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::System, "Be concise."),
+                chat_message(CopilotChatRole::User, "Hi"),
+            ]
+        );
+        assert!(validate_copilot_chat_request(&request).is_ok());
+
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![
+                    message(Role::User, "Hi"),
+                    message(Role::System, "You are helpful."),
+                ],
+                ..Default::default()
+            },
+            Some("Be concise."),
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message(CopilotChatRole::System, "You are helpful."),
+                chat_message(CopilotChatRole::User, "Hi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clamp_max_tokens() {
+        assert_eq!(clamp_max_tokens(1000, 500, 8192), 1000);
+        assert_eq!(clamp_max_tokens(1000, 7692, 8192), 500);
+        assert_eq!(clamp_max_tokens(1000, 9000, 8192), 1);
+    }
+
+    #[test]
+    fn test_stop_sequences_are_capped() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Count to ten.")],
+                stop: (1..=6).map(|n| n.to_string()).collect(),
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(request.stop, ["1", "2", "3", "4"]);
+
+        let request = copilot_chat_request(vec![message(Role::User, "Count to ten.")]);
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("stop")
+            .is_none());
+    }
+
+    #[test]
+    fn test_penalties_are_clamped_and_omitted_when_unset() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Hi")],
+                presence_penalty: Some(0.5),
+                frequency_penalty: Some(3.0),
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(request.presence_penalty, Some(0.5));
+        assert_eq!(request.frequency_penalty, Some(2.0));
+
+        let request =
+            serde_json::to_value(copilot_chat_request(vec![message(Role::User, "Hi")])).unwrap();
+        assert!(request.get("presence_penalty").is_none());
+        assert!(request.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn test_logit_bias_is_clamped_and_omitted_when_empty() {
+        let request = |logit_bias: Option<&[(u32, f32)]>| {
+            let request = to_copilot_chat_request(
+                CopilotChatModel::Gpt4o,
+                LanguageModelRequest {
+                    messages: vec![message(Role::User, "Hi")],
+                    logit_bias: logit_bias.map(|bias| bias.iter().copied().collect()),
+                    ..Default::default()
+                },
+                None,
+                SystemMessagePolicy::default(),
+            );
+            serde_json::to_value(request).unwrap()
+        };
+
+        assert_eq!(
+            request(Some(&[(50256, -150.0), (1234, 5.0)]))["logit_bias"],
+            serde_json::json!({ "50256": -100.0, "1234": 5.0 })
+        );
+        assert!(request(Some(&[])).get("logit_bias").is_none());
+        assert!(request(None).get("logit_bias").is_none());
+    }
+
+    #[test]
+    fn test_response_format() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" } },
+        });
+        let model = CopilotChatModel::Other("gpt-4o-2024-08-06".into());
+        let request = to_copilot_chat_request(
+            model.clone(),
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Hi")],
+                response_format: Some(ResponseFormat::JsonSchema {
+                    schema: schema.clone(),
+                }),
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["response_format"],
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema },
+            })
+        );
+
+        let json_schema = ResponseFormat::JsonSchema { schema };
+        validate_response_format(&json_schema, &model).unwrap();
+        validate_response_format(&ResponseFormat::JsonObject, &CopilotChatModel::Gpt4o).unwrap();
+        assert!(validate_response_format(&json_schema, &CopilotChatModel::Gpt4o).is_err());
+        assert!(
+            validate_response_format(&ResponseFormat::JsonObject, &CopilotChatModel::Gpt4).is_err()
+        );
+        assert!(validate_response_format(
+            &ResponseFormat::JsonSchema {
+                schema: serde_json::json!("object"),
+            },
+            &model
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_locale_instruction() {
+        let request = |messages| {
+            let request = to_copilot_chat_request(
+                CopilotChatModel::Gpt4o,
+                LanguageModelRequest {
+                    messages,
+                    locale: Some("fr-FR".into()),
+                    ..Default::default()
+                },
+                None,
+                SystemMessagePolicy::Preserve,
+            );
+            serde_json::to_value(request.messages).unwrap()
+        };
+
+        assert_eq!(
+            request(vec![
+                message(Role::System, "You are helpful."),
+                message(Role::User, "Hi"),
+            ]),
+            serde_json::json!([
+                { "role": "system", "content": "You are helpful.\nRespond in French." },
+                { "role": "user", "content": "Hi" },
+            ])
+        );
+        assert_eq!(
+            request(vec![message(Role::User, "Hi")]),
+            serde_json::json!([
+                { "role": "system", "content": "Respond in French." },
+                { "role": "user", "content": "Hi" },
+            ])
+        );
+        assert_eq!(
+            request(vec![
+                message(Role::System, "Always respond in German."),
+                message(Role::User, "Hi"),
+            ]),
+            serde_json::json!([
+                { "role": "system", "content": "Always respond in German." },
+                { "role": "user", "content": "Hi" },
+            ])
+        );
+        assert_eq!(
+            request(vec![
+                message(Role::System, "Respond in Markdown."),
+                message(Role::User, "Hi"),
+            ]),
+            serde_json::json!([
+                { "role": "system", "content": "Respond in Markdown.\nRespond in French." },
+                { "role": "user", "content": "Hi" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_seed_is_omitted_when_unset() {
+        let request = to_copilot_chat_request(
+            CopilotChatModel::Gpt4o,
+            LanguageModelRequest {
+                messages: vec![message(Role::User, "Hi")],
+                seed: Some(42),
+                ..Default::default()
+            },
+            None,
+            SystemMessagePolicy::default(),
+        );
+        assert_eq!(serde_json::to_value(request).unwrap()["seed"], 42);
+
+        let request =
+            serde_json::to_value(copilot_chat_request(vec![message(Role::User, "Hi")])).unwrap();
+        assert!(request.get("seed").is_none());
+    }
+
+    #[test]
+    fn test_logged_requests_redact_prompt_content() {
+        let request = copilot_chat_request(vec![
+            message(Role::System, "You are helpful."),
+            message(Role::User, "What's my password?"),
+        ]);
+
+        let body: serde_json::Value =
+            serde_json::from_str(&request_log_body(&request, false).unwrap()).unwrap();
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                { "role": "system", "content": "[16 characters redacted]" },
+                { "role": "user", "content": "[19 characters redacted]" },
+            ])
+        );
+        assert_eq!(body["model"], "gpt-4o-2024-05-13");
+
+        let body = request_log_body(&request, true).unwrap();
+        assert!(body.contains("What's my password?"));
+    }
+
+    #[gpui::test]
+    async fn test_context_warning_threshold(cx: &mut TestAppContext) {
+        let (model, _) = fake_copilot_chat(Vec::new(), cx);
+        let threshold =
+            |cx: &mut TestAppContext| cx.update(|cx| model.context_warning_threshold(cx));
+        assert_eq!(threshold(cx), CONTEXT_WARNING_THRESHOLD);
+
+        cx.update_global::<SettingsStore, _>(|store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models":{"copilot_chat":{"context_warning_threshold":0.75}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        assert_eq!(threshold(cx), 0.75);
+
+        // Thresholds outside of the context window are clamped to it.
+        cx.update_global::<SettingsStore, _>(|store, cx| {
+            store
+                .set_user_settings(
+                    r#"{"language_models":{"copilot_chat":{"context_warning_threshold":1.5}}}"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        assert_eq!(threshold(cx), 1.0);
+    }
+
+    #[test]
+    fn test_model_category() {
+        assert_eq!(
+            model_category(&CopilotChatModel::Gpt3_5Turbo),
+            ModelCategory::Fast
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::Gpt4),
+            ModelCategory::Balanced
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::from_api_id("gpt-4o-mini")),
+            ModelCategory::Fast
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::from_api_id("claude-3.5-sonnet")),
+            ModelCategory::Balanced
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::from_api_id("gemini-1.5-pro")),
+            ModelCategory::Balanced
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::from_api_id("o1-mini")),
+            ModelCategory::Reasoning
+        );
+        assert_eq!(
+            model_category(&CopilotChatModel::from_api_id("o1-preview")),
+            ModelCategory::Reasoning
+        );
+    }
+
+    #[test]
+    fn test_reasoning_effort_is_only_sent_to_reasoning_models() {
+        let reasoning_effort = |model: CopilotChatModel| {
+            let request = to_copilot_chat_request(
+                model,
+                LanguageModelRequest {
+                    messages: vec![message(Role::User, "Hi")],
+                    reasoning_effort: Some(ReasoningEffort::High),
+                    ..Default::default()
+                },
+                None,
+                SystemMessagePolicy::default(),
+            );
+            serde_json::to_value(&request)
+                .unwrap()
+                .get("reasoning_effort")
+                .cloned()
+        };
+
+        assert_eq!(
+            reasoning_effort(CopilotChatModel::from_api_id("o1-preview")),
+            Some(serde_json::json!("high"))
+        );
+        assert_eq!(reasoning_effort(CopilotChatModel::Gpt4o), None);
+        assert_eq!(
+            reasoning_effort(CopilotChatModel::from_api_id("gpt-4o-mini")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_drop_oldest_message() {
+        let mut messages = vec![
+            message(Role::System, "You are a helpful assistant."),
+            message(Role::User, "What time is it?"),
+            LanguageModelRequestMessage {
+                role: Role::Assistant,
+                content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "now".into(),
+                    input: serde_json::json!({}),
+                })],
+                cache: false,
+            },
+            LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                    tool_use_id: "call_1".into(),
+                    is_error: false,
+                    content: "12:00".into(),
+                })],
+                cache: false,
+            },
+            message(Role::Assistant, "It's noon."),
+            message(Role::User, "Thanks!"),
+        ];
+
+        assert!(drop_oldest_message(&mut messages));
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[1].role, Role::Assistant);
+
+        // The tool result is dropped along with the tool call it answers.
+        assert!(drop_oldest_message(&mut messages));
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.string_contents())
+                .collect::<Vec<_>>(),
+            ["You are a helpful assistant.", "It's noon.", "Thanks!"]
+        );
+
+        assert!(drop_oldest_message(&mut messages));
+        assert!(!drop_oldest_message(&mut messages));
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.string_contents())
+                .collect::<Vec<_>>(),
+            ["You are a helpful assistant.", "Thanks!"]
+        );
+    }
+
+    #[test]
+    fn test_completion_failure_reason() {
+        let reason = |error: anyhow::Error| completion_failure_reason(&error);
+        assert_eq!(
+            reason(ApiError::new(StatusCode::UNAUTHORIZED, String::new()).into()),
+            CompletionFailureReason::Auth
+        );
+        assert_eq!(
+            reason(ApiError::new(StatusCode::TOO_MANY_REQUESTS, String::new()).into()),
+            CompletionFailureReason::RateLimit
+        );
+        assert_eq!(
+            reason(ApiError::new(StatusCode::BAD_GATEWAY, String::new()).into()),
+            CompletionFailureReason::ApiError
+        );
+        assert_eq!(
+            reason(
+                CompletionTimeoutError {
+                    timeout: Duration::from_secs(30)
+                }
+                .into()
+            ),
+            CompletionFailureReason::Timeout
+        );
+        assert_eq!(
+            reason(
+                serde_json::from_str::<serde_json::Value>("{")
+                    .unwrap_err()
+                    .into()
+            ),
+            CompletionFailureReason::ApiError
+        );
+        assert_eq!(
+            reason(CompletionCancelledError.into()),
+            CompletionFailureReason::Cancelled
+        );
+        assert_eq!(
+            reason(anyhow!("connection reset")),
+            CompletionFailureReason::Network
+        );
+    }
+
+    #[test]
+    fn test_estimate_token_count() {
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hello, world!")],
+            ..Default::default()
+        };
+        assert_eq!(estimate_token_count(&request), Some(4));
+
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, &"a".repeat(TOKEN_COUNT_FAST_PATH_MAX_CHARS / 2)),
+                message(
+                    Role::Assistant,
+                    &"b".repeat(TOKEN_COUNT_FAST_PATH_MAX_CHARS / 2),
+                ),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(estimate_token_count(&request), None);
+        assert_eq!(
+            approximate_token_count(&request),
+            TOKEN_COUNT_FAST_PATH_MAX_CHARS / 4
+        );
     }
 }
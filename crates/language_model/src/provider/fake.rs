@@ -158,6 +158,10 @@ impl LanguageModel for FakeLanguageModel {
         1000000
     }
 
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
     fn count_tokens(
         &self,
         _: LanguageModelRequest,
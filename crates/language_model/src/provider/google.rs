@@ -271,7 +271,16 @@ impl LanguageModel for GoogleLanguageModel {
                 &api_url,
                 &api_key,
                 google_ai::CountTokensRequest {
-                    contents: request.contents,
+                    // The countTokens endpoint has no system instruction, so count it as a user turn.
+                    contents: request
+                        .system_instruction
+                        .map(|instruction| google_ai::Content {
+                            parts: instruction.parts,
+                            role: google_ai::Role::User,
+                        })
+                        .into_iter()
+                        .chain(request.contents)
+                        .collect(),
                 },
                 low_speed_timeout,
             )
@@ -289,6 +298,12 @@ impl LanguageModel for GoogleLanguageModel {
         'static,
         Result<futures::stream::BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
     > {
+        if let Err(error) = request.reject_tools(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
+        if let Err(error) = request.reject_response_format(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
         let request = request.into_google(self.model.id().to_string());
 
         let http_client = self.http_client.clone();
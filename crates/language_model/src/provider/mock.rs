@@ -0,0 +1,387 @@
+use anyhow::{anyhow, Result};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use gpui::{AnyView, AppContext, AsyncAppContext, Task};
+use std::{sync::Arc, time::Duration};
+use ui::prelude::*;
+
+use crate::{
+    LanguageModel, LanguageModelCompletionEvent, LanguageModelError, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelProviderState, LanguageModelRequest, Role, StopReason,
+};
+
+pub const PROVIDER_ID: &str = "mock";
+const PROVIDER_NAME: &str = "Mock";
+const MODEL_ID: &str = "mock";
+
+/// Provides a [`MockLanguageModel`], so that the assistant can be developed and tested
+/// without credentials or a network connection.
+///
+/// It's registered when the `mock-provider` feature is enabled, and can be selected with
+/// `"default_model": { "provider": "mock", "model": "mock" }` in the assistant's settings.
+#[derive(Clone, Default)]
+pub struct MockLanguageModelProvider {
+    model: Arc<MockLanguageModel>,
+}
+
+impl MockLanguageModelProvider {
+    pub fn new(model: MockLanguageModel) -> Self {
+        Self {
+            model: Arc::new(model),
+        }
+    }
+}
+
+impl LanguageModelProviderState for MockLanguageModelProvider {
+    type ObservableEntity = ();
+
+    fn observable_entity(&self) -> Option<gpui::Model<Self::ObservableEntity>> {
+        None
+    }
+}
+
+impl LanguageModelProvider for MockLanguageModelProvider {
+    fn id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId::from(PROVIDER_ID.to_string())
+    }
+
+    fn name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName::from(PROVIDER_NAME.to_string())
+    }
+
+    fn provided_models(&self, _: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+        vec![self.model.clone()]
+    }
+
+    fn is_authenticated(&self, _: &AppContext) -> bool {
+        true
+    }
+
+    fn authenticate(&self, _: &mut AppContext) -> Task<Result<()>> {
+        Task::ready(Ok(()))
+    }
+
+    fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
+        cx.new_view(|_| ConfigurationView).into()
+    }
+
+    fn reset_credentials(&self, _: &mut AppContext) -> Task<Result<()>> {
+        Task::ready(Ok(()))
+    }
+}
+
+/// What a [`MockLanguageModel`] responds with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MockResponse {
+    /// Repeats the request's last user message.
+    #[default]
+    Echo,
+    Text(String),
+}
+
+/// A language model that streams a canned or echoed response, one word at a time.
+///
+/// Unlike [`FakeLanguageModel`](super::fake::FakeLanguageModel), whose responses are driven
+/// by the test, it responds on its own, so it can stand in for a real model in the UI.
+#[derive(Clone, Debug)]
+pub struct MockLanguageModel {
+    response: MockResponse,
+    chunk_delay: Duration,
+    /// The error to fail with, and the number of chunks to stream before failing.
+    error: Option<(usize, LanguageModelError)>,
+}
+
+impl Default for MockLanguageModel {
+    fn default() -> Self {
+        Self {
+            response: MockResponse::Echo,
+            chunk_delay: Duration::from_millis(50),
+            error: None,
+        }
+    }
+}
+
+impl MockLanguageModel {
+    pub fn with_response(mut self, response: MockResponse) -> Self {
+        self.response = response;
+        self
+    }
+
+    /// Sets how long to wait before streaming each chunk of the response.
+    pub fn with_chunk_delay(mut self, chunk_delay: Duration) -> Self {
+        self.chunk_delay = chunk_delay;
+        self
+    }
+
+    /// Fails completions with `error` after streaming `chunk_count` chunks of the response.
+    /// With a count of 0, the request itself fails.
+    pub fn with_error(mut self, chunk_count: usize, error: LanguageModelError) -> Self {
+        self.error = Some((chunk_count, error));
+        self
+    }
+
+    fn response_text(&self, request: &LanguageModelRequest) -> String {
+        match &self.response {
+            MockResponse::Echo => request
+                .messages
+                .iter()
+                .rev()
+                .find(|message| message.role == Role::User)
+                .map(|message| message.string_contents())
+                .unwrap_or_default(),
+            MockResponse::Text(text) => text.clone(),
+        }
+    }
+
+    /// Streams the response's chunks, ending in the injected error if there is one.
+    fn stream_chunks(
+        &self,
+        request: &LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        let text = self.response_text(request);
+        let mut chunks = text
+            .split_inclusive(' ')
+            .map(|chunk| Ok(chunk.to_string()))
+            .collect::<Vec<_>>();
+        if let Some((chunk_count, error)) = self.error.clone() {
+            let message = error.to_string();
+            let error = error.attach(anyhow!(message));
+            if chunk_count == 0 {
+                return Err(error);
+            }
+            chunks.truncate(chunk_count);
+            chunks.push(Err(error));
+        }
+
+        let executor = cx.background_executor().clone();
+        let chunk_delay = self.chunk_delay;
+        Ok(futures::stream::iter(chunks)
+            .then(move |chunk| {
+                let executor = executor.clone();
+                async move {
+                    if !chunk_delay.is_zero() {
+                        executor.timer(chunk_delay).await;
+                    }
+                    chunk
+                }
+            })
+            .boxed())
+    }
+}
+
+impl LanguageModel for MockLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        LanguageModelId::from(MODEL_ID.to_string())
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(PROVIDER_NAME.to_string())
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId::from(PROVIDER_ID.to_string())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName::from(PROVIDER_NAME.to_string())
+    }
+
+    fn telemetry_id(&self) -> String {
+        format!("{PROVIDER_ID}/{MODEL_ID}")
+    }
+
+    fn max_token_count(&self) -> usize {
+        128_000
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        _: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        // About four characters per token, like most tokenizers.
+        let char_count = request
+            .messages
+            .iter()
+            .map(|message| message.string_contents().chars().count())
+            .sum::<usize>();
+        futures::future::ready(Ok(char_count.div_ceil(4))).boxed()
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let chunks = match self.stream_chunks(&request, cx) {
+            Ok(chunks) => chunks,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+        let events = chunks
+            .map(|chunk| chunk.map(LanguageModelCompletionEvent::Text))
+            .chain(futures::stream::once(async {
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn))
+            }))
+            // Nothing follows an error.
+            .scan(false, |failed, event| {
+                let event = (!*failed).then_some(event);
+                *failed = matches!(event, Some(Err(_)));
+                futures::future::ready(event)
+            })
+            .boxed();
+        futures::future::ready(Ok(events)).boxed()
+    }
+
+    /// Streams the response as the tool's input, so it should be set to JSON.
+    fn use_any_tool(
+        &self,
+        request: LanguageModelRequest,
+        _name: String,
+        _description: String,
+        _schema: serde_json::Value,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        futures::future::ready(self.stream_chunks(&request, cx)).boxed()
+    }
+}
+
+struct ConfigurationView;
+
+impl Render for ConfigurationView {
+    fn render(&mut self, _: &mut ViewContext<Self>) -> impl IntoElement {
+        Label::new(
+            "The mock provider responds without a network connection and needs no configuration.",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ContextFit, LanguageModelRequestMessage, CONTEXT_WARNING_THRESHOLD};
+    use gpui::TestAppContext;
+
+    fn request(text: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![text.into()],
+                cache: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[gpui::test]
+    async fn test_mock_language_model(cx: &mut TestAppContext) {
+        let model = MockLanguageModel::default().with_chunk_delay(Duration::from_millis(10));
+        let text = model.complete(request("Hello there, world"), &cx.to_async());
+        let text = cx.executor().spawn(text);
+        for _ in 0..3 {
+            cx.executor().advance_clock(Duration::from_millis(10));
+        }
+        assert_eq!(text.await.unwrap(), "Hello there, world");
+
+        let model = MockLanguageModel::default()
+            .with_response(MockResponse::Text("Sorry, I can't".into()))
+            .with_chunk_delay(Duration::ZERO)
+            .with_error(2, LanguageModelError::Network);
+        let events = model
+            .stream_completion(request("Hi"), &cx.to_async())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[1].as_ref().unwrap(),
+            &LanguageModelCompletionEvent::Text("I ".into())
+        );
+        assert_eq!(
+            events[2].as_ref().unwrap_err().downcast_ref(),
+            Some(&LanguageModelError::Network)
+        );
+
+        let model = MockLanguageModel::default().with_error(0, LanguageModelError::Timeout);
+        let error = model
+            .stream_completion(request("Hi"), &cx.to_async())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref(), Some(&LanguageModelError::Timeout));
+    }
+
+    #[gpui::test]
+    async fn test_compact_conversation(cx: &mut TestAppContext) {
+        let model: Arc<dyn LanguageModel> = Arc::new(
+            MockLanguageModel::default()
+                .with_response(MockResponse::Text("We said hello.".into()))
+                .with_chunk_delay(Duration::ZERO),
+        );
+        let request = crate::LanguageModelRequestBuilder::new()
+            .user("Hello")
+            .assistant("Hi!")
+            .user("How are you?")
+            .build();
+
+        let compacted = model
+            .compact_conversation(request, 1, &cx.to_async())
+            .await
+            .unwrap();
+        assert_eq!(
+            compacted
+                .messages
+                .iter()
+                .map(|message| (message.role, message.string_contents()))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    Role::System,
+                    "A summary of the earlier conversation:\n\nWe said hello.".to_string()
+                ),
+                (Role::User, "How are you?".to_string()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens_for_message(cx: &mut TestAppContext) {
+        let model = MockLanguageModel::default();
+        let message = request("abcdefgh").messages.remove(0);
+        // Without a per-message overhead to leave out, a message counts as a prompt of its own.
+        let token_count = cx
+            .update(|cx| model.count_tokens_for_message(message, cx))
+            .await
+            .unwrap();
+        assert_eq!(token_count, 2);
+    }
+
+    #[gpui::test]
+    async fn test_would_exceed_context(cx: &mut TestAppContext) {
+        let model = MockLanguageModel::default();
+        // The mock model counts four characters per token, and its context window holds 128,000.
+        let fit = |token_count: usize| {
+            let request = request(&"abcd".repeat(token_count));
+            cx.update(|cx| model.would_exceed_context(request, cx))
+        };
+
+        let small = fit(1_000).await.unwrap();
+        assert_eq!(
+            small,
+            ContextFit {
+                token_count: 1_000,
+                max_token_count: 128_000,
+                fits: true,
+            }
+        );
+        assert!(!small.exceeds(CONTEXT_WARNING_THRESHOLD));
+
+        let large = fit(120_000).await.unwrap();
+        assert!(large.fits);
+        assert!(large.exceeds(CONTEXT_WARNING_THRESHOLD));
+
+        let too_large = fit(130_000).await.unwrap();
+        assert!(!too_large.fits);
+    }
+}
@@ -198,6 +198,10 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
         cx.new_view(|cx| ConfigurationView::new(state, cx)).into()
     }
 
+    fn requires_external_dependency(&self) -> Option<&str> {
+        Some("Ollama")
+    }
+
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.fetch_models(cx))
     }
@@ -306,6 +310,12 @@ impl LanguageModel for OllamaLanguageModel {
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        if let Err(error) = request.reject_tools(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
+        if let Err(error) = request.reject_response_format(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
         let request = self.to_ollama_request(request);
 
         let http_client = self.http_client.clone();
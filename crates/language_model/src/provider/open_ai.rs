@@ -1,7 +1,7 @@
 use anyhow::{anyhow, Result};
 use collections::BTreeMap;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, FutureExt, StreamExt};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, Stream, StreamExt};
 use gpui::{
     AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
     View, WhiteSpace,
@@ -13,17 +13,24 @@ use open_ai::{
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use settings::{Settings, SettingsStore};
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 use strum::IntoEnumIterator;
 use theme::ThemeSettings;
+use tiktoken_rs::{tokenizer::Tokenizer, ChatCompletionRequestMessage, CoreBPE};
 use ui::{prelude::*, Icon, IconName, Tooltip};
 use util::ResultExt;
 
-use crate::LanguageModelCompletionEvent;
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    settings::AllLanguageModelSettings, EmbeddingModel, LanguageModel, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelProviderState, LanguageModelRequest, LanguageModelRequestMessage, RateLimiter,
+    Role,
+};
+use crate::{
+    LanguageModelCompletionEvent, LanguageModelToolUse, StopReason, MESSAGE_TOKEN_OVERHEAD,
 };
 
 const PROVIDER_ID: &str = "openai";
@@ -195,6 +202,22 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
             .collect()
     }
 
+    fn embedding_models(&self, _cx: &AppContext) -> Vec<Arc<dyn EmbeddingModel>> {
+        [
+            open_ai::OpenAiEmbeddingModel::TextEmbedding3Small,
+            open_ai::OpenAiEmbeddingModel::TextEmbedding3Large,
+        ]
+        .into_iter()
+        .map(|model| {
+            Arc::new(OpenAiEmbeddingModel {
+                model,
+                state: self.state.clone(),
+                http_client: self.http_client.clone(),
+            }) as Arc<dyn EmbeddingModel>
+        })
+        .collect()
+    }
+
     fn is_authenticated(&self, cx: &AppContext) -> bool {
         self.state.read(cx).is_authenticated()
     }
@@ -211,6 +234,10 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.reset_api_key(cx))
     }
+
+    fn warm_up(&self, cx: &mut AppContext) -> Task<()> {
+        warm_up_open_ai_tokenizers(cx)
+    }
 }
 
 pub struct OpenAiLanguageModel {
@@ -286,6 +313,18 @@ impl LanguageModel for OpenAiLanguageModel {
         self.model.max_output_tokens()
     }
 
+    fn input_price_per_million(&self) -> Option<f64> {
+        self.model.input_price_per_million()
+    }
+
+    fn output_price_per_million(&self) -> Option<f64> {
+        self.model.output_price_per_million()
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -294,6 +333,14 @@ impl LanguageModel for OpenAiLanguageModel {
         count_open_ai_tokens(request, self.model.clone(), cx)
     }
 
+    fn count_tokens_for_message(
+        &self,
+        message: LanguageModelRequestMessage,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        count_open_ai_message_tokens(message, self.model.clone(), cx)
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
@@ -302,12 +349,14 @@ impl LanguageModel for OpenAiLanguageModel {
         'static,
         Result<futures::stream::BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
     > {
+        if let Err(error) = request.reject_response_format(self.model.display_name()) {
+            return futures::future::ready(Err(error)).boxed();
+        }
         let request = request.into_open_ai(self.model.id().into(), self.max_output_tokens());
+        let choice_count = request.n.unwrap_or(1);
         let completions = self.stream_completion(request, cx);
         async move {
-            Ok(open_ai::extract_text_from_events(completions.await?)
-                .map(|result| result.map(LanguageModelCompletionEvent::Text))
-                .boxed())
+            Ok(map_to_language_model_completion_events(completions.await?, choice_count).boxed())
         }
         .boxed()
     }
@@ -350,6 +399,200 @@ impl LanguageModel for OpenAiLanguageModel {
     }
 }
 
+pub struct OpenAiEmbeddingModel {
+    model: open_ai::OpenAiEmbeddingModel,
+    state: gpui::Model<State>,
+    http_client: Arc<dyn HttpClient>,
+}
+
+impl EmbeddingModel for OpenAiEmbeddingModel {
+    fn id(&self) -> LanguageModelId {
+        LanguageModelId::from(self.model.id().to_string())
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(self.model.id().to_string())
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn max_batch_size(&self) -> usize {
+        // From https://platform.openai.com/docs/api-reference/embeddings/create
+        2048
+    }
+
+    fn embed_batch(
+        &self,
+        texts: Vec<String>,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<Vec<Vec<f32>>>> {
+        let http_client = self.http_client.clone();
+        let model = self.model;
+        let Ok((api_key, api_url)) = cx.read_model(&self.state, |state, cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).openai;
+            (state.api_key.clone(), settings.api_url.clone())
+        }) else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+
+        async move {
+            let api_key = api_key.ok_or_else(|| anyhow!("Missing OpenAI API Key"))?;
+            let response = open_ai::embed(
+                http_client.as_ref(),
+                &api_url,
+                &api_key,
+                model,
+                texts.iter().map(String::as_str),
+            )
+            .await?;
+            Ok(response
+                .data
+                .into_iter()
+                .map(|data| data.embedding)
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+/// A tool call whose fragments are still being streamed in.
+#[derive(Default)]
+pub(crate) struct RawToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl RawToolCall {
+    pub(crate) fn push(&mut self, id: Option<&str>, name: Option<&str>, arguments: Option<&str>) {
+        if let Some(id) = id {
+            self.id = id.to_string();
+        }
+        if let Some(name) = name {
+            self.name.push_str(name);
+        }
+        if let Some(arguments) = arguments {
+            self.arguments.push_str(arguments);
+        }
+    }
+
+    pub(crate) fn into_tool_use(self) -> Result<LanguageModelToolUse> {
+        let input = if self.arguments.trim().is_empty() {
+            serde_json::Value::Object(Default::default())
+        } else {
+            serde_json::from_str(&self.arguments).map_err(|error| {
+                anyhow!("Invalid arguments for tool call \"{}\": {error}", self.name)
+            })?
+        };
+        Ok(LanguageModelToolUse {
+            id: self.id,
+            name: self.name,
+            input,
+        })
+    }
+}
+
+/// Maps a response's chunks to completion events. When `choice_count` candidates were
+/// requested, each candidate's events are wrapped in a
+/// [`LanguageModelCompletionEvent::Candidate`].
+pub fn map_to_language_model_completion_events(
+    events: BoxStream<'static, Result<ResponseStreamEvent>>,
+    choice_count: usize,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: BoxStream<'static, Result<ResponseStreamEvent>>,
+        /// Keyed by the index of the choice, then by that of the tool call.
+        tool_calls_by_index: BTreeMap<(usize, usize), RawToolCall>,
+    }
+
+    futures::stream::unfold(
+        State {
+            events,
+            tool_calls_by_index: BTreeMap::default(),
+        },
+        |mut state| async move {
+            let Some(event) = state.events.next().await else {
+                // Emit any tool calls from a stream that was closed without a finish reason.
+                if state.tool_calls_by_index.is_empty() {
+                    return None;
+                }
+                let events = std::mem::take(&mut state.tool_calls_by_index)
+                    .into_iter()
+                    .map(|((choice_index, _), tool_call)| {
+                        tool_call.into_tool_use().map(|tool_use| {
+                            LanguageModelCompletionEvent::ToolUse(tool_use)
+                                .for_choice(choice_index, choice_count)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                return Some((events, state));
+            };
+
+            let mut events = Vec::new();
+            match event {
+                Ok(response) => {
+                    for choice in response.choices {
+                        let choice_index = choice.index as usize;
+                        if let Some(content) = choice.delta.content {
+                            events.push(Ok(LanguageModelCompletionEvent::Text(content)
+                                .for_choice(choice_index, choice_count)));
+                        }
+                        for chunk in choice.delta.tool_calls.into_iter().flatten() {
+                            let function = chunk.function.as_ref();
+                            let name = function.and_then(|function| function.name.as_deref());
+                            let arguments =
+                                function.and_then(|function| function.arguments.as_deref());
+                            state
+                                .tool_calls_by_index
+                                .entry((choice_index, chunk.index))
+                                .or_default()
+                                .push(chunk.id.as_deref(), name, arguments);
+                            if let Some(delta) = LanguageModelCompletionEvent::tool_use_delta(
+                                chunk.index,
+                                name,
+                                arguments,
+                            ) {
+                                events.push(Ok(delta.for_choice(choice_index, choice_count)));
+                            }
+                        }
+                        if let Some(finish_reason) = choice.finish_reason.as_deref() {
+                            let (finished_tool_calls, pending_tool_calls): (BTreeMap<_, _>, _) =
+                                std::mem::take(&mut state.tool_calls_by_index)
+                                    .into_iter()
+                                    .partition(|((index, _), _)| *index == choice_index);
+                            state.tool_calls_by_index = pending_tool_calls;
+                            for tool_call in finished_tool_calls.into_values() {
+                                events.push(tool_call.into_tool_use().map(|tool_use| {
+                                    LanguageModelCompletionEvent::ToolUse(tool_use)
+                                        .for_choice(choice_index, choice_count)
+                                }));
+                            }
+                            let stop_reason = match finish_reason {
+                                "stop" => StopReason::EndTurn,
+                                "length" => StopReason::MaxTokens,
+                                "content_filter" => StopReason::ContentFilter,
+                                "tool_calls" => StopReason::ToolUse,
+                                _ => StopReason::Unknown,
+                            };
+                            events.push(Ok(LanguageModelCompletionEvent::Stop(stop_reason)
+                                .for_choice(choice_index, choice_count)));
+                        }
+                    }
+                }
+                Err(error) => events.push(Err(error)),
+            }
+            Some((events, state))
+        },
+    )
+    .flat_map(futures::stream::iter)
+}
+
 pub fn count_open_ai_tokens(
     request: LanguageModelRequest,
     model: open_ai::Model,
@@ -360,30 +603,129 @@ pub fn count_open_ai_tokens(
             let messages = request
                 .messages
                 .into_iter()
-                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
-                    role: match message.role {
-                        Role::User => "user".into(),
-                        Role::Assistant => "assistant".into(),
-                        Role::System => "system".into(),
-                    },
-                    content: Some(message.string_contents()),
-                    name: None,
-                    function_call: None,
-                })
+                .map(tiktoken_message)
                 .collect::<Vec<_>>();
+            Ok(num_tokens_from_messages(model_tokenizer(&model)?, &messages).await)
+        })
+        .boxed()
+}
 
-            match model {
-                open_ai::Model::Custom { .. }
-                | open_ai::Model::O1Mini
-                | open_ai::Model::O1Preview => {
-                    tiktoken_rs::num_tokens_from_messages("gpt-4", &messages)
-                }
-                _ => tiktoken_rs::num_tokens_from_messages(model.id(), &messages),
-            }
+/// Counts the tokens in a single message for
+/// [`LanguageModel::count_tokens_for_message`], the way [`count_open_ai_tokens`] counts them.
+pub fn count_open_ai_message_tokens(
+    message: LanguageModelRequestMessage,
+    model: open_ai::Model,
+    cx: &AppContext,
+) -> BoxFuture<'static, Result<usize>> {
+    cx.background_executor()
+        .spawn(async move {
+            Ok(num_tokens_from_message(
+                model_tokenizer(&model)?,
+                &tiktoken_message(message),
+            ))
         })
         .boxed()
 }
 
+/// Counts the tokens in a piece of text, such as part of a response.
+pub fn count_open_ai_text_tokens(text: &str, model: &open_ai::Model) -> Result<usize> {
+    Ok(model_tokenizer(model)?
+        .encode_with_special_tokens(text)
+        .len())
+}
+
+fn tiktoken_message(message: LanguageModelRequestMessage) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage {
+        role: match message.role {
+            Role::User => "user".into(),
+            Role::Assistant => "assistant".into(),
+            Role::System => "system".into(),
+        },
+        content: Some(message.string_contents()),
+        name: None,
+        function_call: None,
+    }
+}
+
+fn model_tokenizer(model: &open_ai::Model) -> Result<&'static CoreBPE> {
+    match model {
+        open_ai::Model::Custom { .. } | open_ai::Model::O1Mini | open_ai::Model::O1Preview => {
+            open_ai_tokenizer("gpt-4")
+        }
+        _ => open_ai_tokenizer(model.id()),
+    }
+}
+
+/// Loads the tokenizers that [`count_open_ai_tokens`] uses on a background thread, so that
+/// the first count doesn't have to wait for them.
+pub fn warm_up_open_ai_tokenizers(cx: &AppContext) -> Task<()> {
+    cx.background_executor().spawn(async move {
+        cl100k_base();
+        o200k_base();
+    })
+}
+
+// Building a tokenizer takes hundreds of milliseconds, so each one is built once and shared.
+// A tokenizer that is being built by one thread is waited for by the others.
+static CL100K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+static O200K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+
+fn cl100k_base() -> &'static CoreBPE {
+    CL100K_BASE.get_or_init(|| {
+        tiktoken_rs::cl100k_base().expect("the cl100k_base tokenizer is bundled with tiktoken-rs")
+    })
+}
+
+fn o200k_base() -> &'static CoreBPE {
+    O200K_BASE.get_or_init(|| {
+        tiktoken_rs::o200k_base().expect("the o200k_base tokenizer is bundled with tiktoken-rs")
+    })
+}
+
+fn open_ai_tokenizer(model_id: &str) -> Result<&'static CoreBPE> {
+    match tiktoken_rs::tokenizer::get_tokenizer(model_id) {
+        Some(Tokenizer::Cl100kBase) => Ok(cl100k_base()),
+        Some(Tokenizer::O200kBase) => Ok(o200k_base()),
+        _ => Err(anyhow!("no chat tokenizer is known for model {model_id}")),
+    }
+}
+
+/// Counts the tokens in a chat prompt the way `tiktoken_rs::num_tokens_from_messages` does,
+/// but with a tokenizer that has already been built.
+///
+/// Long conversations take a while to tokenize, so this yields after each message. That way
+/// dropping the task it runs on stops the count partway through, rather than tying up a
+/// background thread until the whole prompt has been encoded.
+async fn num_tokens_from_messages(
+    tokenizer: &CoreBPE,
+    messages: &[ChatCompletionRequestMessage],
+) -> usize {
+    // Every reply is primed with `<|start|>assistant<|message|>`.
+    const TOKENS_PER_REPLY: usize = 3;
+
+    let mut token_count = TOKENS_PER_REPLY;
+    for message in messages {
+        token_count += MESSAGE_TOKEN_OVERHEAD + num_tokens_from_message(tokenizer, message);
+        smol::future::yield_now().await;
+    }
+    token_count
+}
+
+/// Counts the tokens in a message's role, content and name, without its
+/// [`MESSAGE_TOKEN_OVERHEAD`].
+fn num_tokens_from_message(tokenizer: &CoreBPE, message: &ChatCompletionRequestMessage) -> usize {
+    const TOKENS_PER_NAME: usize = 1;
+
+    let mut token_count = tokenizer.encode_with_special_tokens(&message.role).len();
+    if let Some(content) = &message.content {
+        token_count += tokenizer.encode_with_special_tokens(content).len();
+    }
+    if let Some(name) = &message.name {
+        token_count += tokenizer.encode_with_special_tokens(name).len() + TOKENS_PER_NAME;
+    }
+    token_count
+}
+
 struct ConfigurationView {
     api_key_editor: View<Editor>,
     state: gpui::Model<State>,
@@ -577,3 +919,91 @@ impl Render for ConfigurationView {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+
+    #[test]
+    fn test_num_tokens_from_messages_matches_tiktoken() {
+        let messages = vec![
+            ChatCompletionRequestMessage {
+                role: "system".into(),
+                content: Some("You are a helpful assistant.".into()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: "user".into(),
+                content: Some("Explain what a borrow checker does, briefly.".into()),
+                name: Some("example_user".into()),
+                function_call: None,
+            },
+        ];
+        for model_id in ["gpt-4", "gpt-4o"] {
+            assert_eq!(
+                smol::block_on(num_tokens_from_messages(
+                    open_ai_tokenizer(model_id).unwrap(),
+                    &messages
+                )),
+                tiktoken_rs::num_tokens_from_messages(model_id, &messages).unwrap(),
+                "{model_id}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_token_counts_sum_to_prompt_token_count() {
+        let messages = [
+            ChatCompletionRequestMessage {
+                role: "system".into(),
+                content: Some("You are a helpful assistant.".into()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: "user".into(),
+                content: Some("Explain what a borrow checker does, briefly.".into()),
+                name: None,
+                function_call: None,
+            },
+        ];
+        let tokenizer = open_ai_tokenizer("gpt-4o").unwrap();
+        let message_token_count = messages
+            .iter()
+            .map(|message| num_tokens_from_message(tokenizer, message) + MESSAGE_TOKEN_OVERHEAD)
+            .sum::<usize>();
+        assert_eq!(
+            message_token_count + 3,
+            smol::block_on(num_tokens_from_messages(tokenizer, &messages))
+        );
+    }
+
+    #[gpui::test]
+    fn test_dropping_a_token_count_stops_it(cx: &mut TestAppContext) {
+        let message = LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec!["All work and no play makes Jack a dull boy. "
+                .repeat(500)
+                .into()],
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![message; 200],
+            ..Default::default()
+        };
+
+        // The count runs on the background executor, so nothing is tokenized until it's polled.
+        let mut token_count =
+            cx.update(|cx| count_open_ai_tokens(request, open_ai::Model::FourOmni, cx));
+        assert!((&mut token_count).now_or_never().is_none());
+
+        // Each message is encoded separately, so the count can be interrupted between them.
+        cx.executor().tick();
+        assert!((&mut token_count).now_or_never().is_none());
+
+        drop(token_count);
+        cx.executor().run_until_parked();
+    }
+}
@@ -3,13 +3,16 @@ use crate::{
         anthropic::AnthropicLanguageModelProvider, cloud::CloudLanguageModelProvider,
         copilot_chat::CopilotChatLanguageModelProvider, google::GoogleLanguageModelProvider,
         ollama::OllamaLanguageModelProvider, open_ai::OpenAiLanguageModelProvider,
+        openai_compatible::OpenAiCompatibleLanguageModelProvider,
     },
+    settings::AllLanguageModelSettings,
     LanguageModel, LanguageModelId, LanguageModelProvider, LanguageModelProviderId,
     LanguageModelProviderState,
 };
 use client::{Client, UserStore};
-use collections::BTreeMap;
+use collections::{BTreeMap, HashSet};
 use gpui::{AppContext, EventEmitter, Global, Model, ModelContext};
+use settings::{Settings, SettingsStore};
 use std::sync::Arc;
 use ui::Context;
 
@@ -20,6 +23,22 @@ pub fn init(user_store: Model<UserStore>, client: Arc<Client>, cx: &mut AppConte
         registry
     });
     cx.set_global(GlobalLanguageModelRegistry(registry));
+
+    update_disabled_providers_from_settings(cx);
+    cx.observe_global::<SettingsStore>(update_disabled_providers_from_settings)
+        .detach();
+}
+
+fn update_disabled_providers_from_settings(cx: &mut AppContext) {
+    let disabled_providers = AllLanguageModelSettings::get_global(cx)
+        .disabled_providers
+        .iter()
+        .cloned()
+        .map(LanguageModelProviderId::from)
+        .collect::<Vec<_>>();
+    LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
+        registry.set_disabled_providers(disabled_providers, cx);
+    });
 }
 
 fn register_language_model_providers(
@@ -38,6 +57,10 @@ fn register_language_model_providers(
         OpenAiLanguageModelProvider::new(client.http_client(), cx),
         cx,
     );
+    registry.register_provider(
+        OpenAiCompatibleLanguageModelProvider::new(client.http_client(), cx),
+        cx,
+    );
     registry.register_provider(
         OllamaLanguageModelProvider::new(client.http_client(), cx),
         cx,
@@ -46,7 +69,15 @@ fn register_language_model_providers(
         GoogleLanguageModelProvider::new(client.http_client(), cx),
         cx,
     );
-    registry.register_provider(CopilotChatLanguageModelProvider::new(cx), cx);
+    registry.register_provider(
+        CopilotChatLanguageModelProvider::new(client.telemetry().clone(), cx),
+        cx,
+    );
+    #[cfg(feature = "mock-provider")]
+    registry.register_provider(
+        crate::provider::mock::MockLanguageModelProvider::default(),
+        cx,
+    );
 
     cx.observe_flag::<feature_flags::LanguageModels, _>(move |enabled, cx| {
         let user_store = user_store.clone();
@@ -76,6 +107,8 @@ impl Global for GlobalLanguageModelRegistry {}
 pub struct LanguageModelRegistry {
     active_model: Option<ActiveModel>,
     providers: BTreeMap<LanguageModelProviderId, Arc<dyn LanguageModelProvider>>,
+    /// Registered providers whose models can't be selected, as configured in settings.
+    disabled_providers: HashSet<LanguageModelProviderId>,
     inline_alternatives: Vec<Arc<dyn LanguageModel>>,
 }
 
@@ -122,6 +155,7 @@ impl LanguageModelRegistry {
         cx: &mut ModelContext<Self>,
     ) {
         let id = provider.id();
+        provider.warm_up(cx).detach();
 
         let subscription = provider.subscribe(cx, |_, cx| {
             cx.emit(Event::ProviderStateChanged);
@@ -160,13 +194,69 @@ impl LanguageModelRegistry {
         providers
     }
 
+    /// Returns the providers whose models can be selected, in the same order as
+    /// [`Self::providers`].
+    pub fn enabled_providers(&self) -> Vec<Arc<dyn LanguageModelProvider>> {
+        self.providers()
+            .into_iter()
+            .filter(|provider| self.is_provider_enabled(&provider.id()))
+            .collect()
+    }
+
+    pub fn is_provider_enabled(&self, id: &LanguageModelProviderId) -> bool {
+        self.providers.contains_key(id) && !self.disabled_providers.contains(id)
+    }
+
+    /// Disables the providers with the given IDs, and enables all others. Disabled providers
+    /// stay registered, but their models are no longer available or selectable.
+    pub fn set_disabled_providers(
+        &mut self,
+        disabled_providers: impl IntoIterator<Item = LanguageModelProviderId>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        let disabled_providers = disabled_providers.into_iter().collect::<HashSet<_>>();
+        if disabled_providers == self.disabled_providers {
+            return;
+        }
+        self.disabled_providers = disabled_providers;
+
+        if self
+            .active_provider()
+            .map_or(false, |provider| !self.is_provider_enabled(&provider.id()))
+        {
+            self.set_active_model(None, cx);
+        }
+        let disabled_providers = &self.disabled_providers;
+        self.inline_alternatives
+            .retain(|model| !disabled_providers.contains(&model.provider_id()));
+        cx.emit(Event::ProviderStateChanged);
+    }
+
     pub fn available_models<'a>(
         &'a self,
         cx: &'a AppContext,
     ) -> impl Iterator<Item = Arc<dyn LanguageModel>> + 'a {
         self.providers
-            .values()
-            .flat_map(|provider| provider.provided_models(cx))
+            .iter()
+            .filter(|(id, _)| !self.disabled_providers.contains(*id))
+            .flat_map(|(_, provider)| provider.provided_models(cx))
+    }
+
+    /// Returns the model with the given ID, if its provider is enabled and provides it.
+    pub fn model_by_id(
+        &self,
+        provider_id: &LanguageModelProviderId,
+        model_id: &LanguageModelId,
+        cx: &AppContext,
+    ) -> Option<Arc<dyn LanguageModel>> {
+        if !self.is_provider_enabled(provider_id) {
+            return None;
+        }
+        self.providers
+            .get(provider_id)?
+            .provided_models(cx)
+            .into_iter()
+            .find(|model| &model.id() == model_id)
     }
 
     pub fn provider(&self, id: &LanguageModelProviderId) -> Option<Arc<dyn LanguageModelProvider>> {
@@ -179,12 +269,7 @@ impl LanguageModelRegistry {
         model_id: &LanguageModelId,
         cx: &mut ModelContext<Self>,
     ) {
-        let Some(provider) = self.provider(provider) else {
-            return;
-        };
-
-        let models = provider.provided_models(cx);
-        if let Some(model) = models.iter().find(|model| &model.id() == model_id).cloned() {
+        if let Some(model) = self.model_by_id(provider, model_id, cx) {
             self.set_active_model(Some(model), cx);
         }
     }
@@ -238,21 +323,10 @@ impl LanguageModelRegistry {
         alternatives: impl IntoIterator<Item = (LanguageModelProviderId, LanguageModelId)>,
         cx: &mut ModelContext<Self>,
     ) {
-        let mut selected_alternatives = Vec::new();
-
-        for (provider_id, model_id) in alternatives {
-            if let Some(provider) = self.providers.get(&provider_id) {
-                if let Some(model) = provider
-                    .provided_models(cx)
-                    .iter()
-                    .find(|m| m.id() == model_id)
-                {
-                    selected_alternatives.push(model.clone());
-                }
-            }
-        }
-
-        self.inline_alternatives = selected_alternatives;
+        self.inline_alternatives = alternatives
+            .into_iter()
+            .filter_map(|(provider_id, model_id)| self.model_by_id(&provider_id, &model_id, cx))
+            .collect();
     }
 
     /// The models to use for inline assists. Returns the union of the active
@@ -287,4 +361,40 @@ mod tests {
         let providers = registry.read(cx).providers();
         assert!(providers.is_empty());
     }
+
+    #[gpui::test]
+    fn test_disabled_providers(cx: &mut AppContext) {
+        let registry = cx.new_model(|_| LanguageModelRegistry::default());
+        let provider_id = crate::provider::fake::provider_id();
+        let model_id = crate::provider::fake::language_model_id();
+
+        registry.update(cx, |registry, cx| {
+            registry.register_provider(FakeLanguageModelProvider, cx);
+            registry.select_active_model(&provider_id, &model_id, cx);
+        });
+        let registry_state = registry.read(cx);
+        assert_eq!(registry_state.enabled_providers().len(), 1);
+        assert!(registry_state
+            .model_by_id(&provider_id, &model_id, cx)
+            .is_some());
+        assert!(registry_state.active_model().is_some());
+
+        registry.update(cx, |registry, cx| {
+            registry.set_disabled_providers([provider_id.clone()], cx);
+        });
+        let registry_state = registry.read(cx);
+        assert_eq!(registry_state.providers().len(), 1);
+        assert!(registry_state.enabled_providers().is_empty());
+        assert!(registry_state
+            .model_by_id(&provider_id, &model_id, cx)
+            .is_none());
+        assert_eq!(registry_state.available_models(cx).count(), 0);
+        assert!(registry_state.active_model().is_none());
+
+        registry.update(cx, |registry, cx| {
+            registry.set_disabled_providers(Vec::<LanguageModelProviderId>::new(), cx);
+            registry.select_active_model(&provider_id, &model_id, cx);
+        });
+        assert!(registry.read(cx).active_model().is_some());
+    }
 }
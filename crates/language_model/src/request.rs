@@ -1,8 +1,13 @@
+use std::fmt::Write as _;
 use std::io::{Cursor, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
 
-use crate::role::Role;
+use crate::role::{NoSystemRoleError, Role};
 use crate::LanguageModelToolUse;
+use anyhow::{anyhow, Result};
 use base64::write::EncoderWriter;
+use collections::HashMap;
 use gpui::{point, size, AppContext, DevicePixels, Image, ObjectFit, RenderImage, Size, Task};
 use image::{codecs::png::PngEncoder, imageops::resize, DynamicImage, ImageDecoder};
 use serde::{Deserialize, Serialize};
@@ -208,6 +213,17 @@ impl LanguageModelRequestMessage {
         string_buffer
     }
 
+    /// Returns the message's text, excluding the content of any tool results.
+    pub fn text_contents(&self) -> String {
+        let mut string_buffer = String::new();
+        for content in &self.content {
+            if let MessageContent::Text(text) = content {
+                string_buffer.push_str(text);
+            }
+        }
+        string_buffer
+    }
+
     pub fn contents_empty(&self) -> bool {
         self.content.is_empty()
             || self
@@ -237,55 +253,506 @@ pub struct LanguageModelRequest {
     pub tools: Vec<LanguageModelRequestTool>,
     pub stop: Vec<String>,
     pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Penalizes tokens that have already appeared in the response, encouraging new topics.
+    /// Only supported by OpenAI-compatible APIs.
+    pub presence_penalty: Option<f32>,
+    /// Penalizes tokens by how often they have already appeared in the response, discouraging
+    /// repetition. Only supported by OpenAI-compatible APIs.
+    pub frequency_penalty: Option<f32>,
+    /// Biases, from -100 to 100, added to the likelihood of the tokens with the given IDs,
+    /// e.g. -100 to ban a token. Token IDs are specific to a model's tokenizer, so they must
+    /// be derived with the tokenizer of the model the request is sent to. Only supported by
+    /// OpenAI-compatible APIs.
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// Asks the provider to sample deterministically, so that repeating the request yields
+    /// the same response as far as the provider allows. Only supported by OpenAI-compatible APIs.
+    pub seed: Option<u64>,
+    /// The number of candidate completions to generate, e.g. to pick the best of several.
+    /// When it's more than 1, the completion's events are wrapped in
+    /// [`LanguageModelCompletionEvent::Candidate`]s. Only supported by OpenAI-compatible APIs;
+    /// other providers generate a single completion.
+    pub n: Option<usize>,
+    /// The maximum number of tokens to generate, if lower than the model's own limit.
+    pub max_tokens: Option<usize>,
+    /// How much effort reasoning models should spend thinking before they respond.
+    /// Ignored by other models.
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// Identifies the conversation or session the request belongs to. Providers that support
+    /// it cancel a completion that is still in flight when a newer one with the same
+    /// session starts, so that only the latest completion is streamed.
+    pub session_id: Option<String>,
+    /// Constrains the format of the response. Providers that can't enforce the requested
+    /// format return an error rather than ignoring it.
+    pub response_format: Option<ResponseFormat>,
+    /// The locale of the language the model should respond in, e.g. `fr` or `pt-BR`.
+    /// Providers that support it add an instruction saying so, unless the request already
+    /// contains one.
+    pub locale: Option<String>,
+    /// Describes where the request comes from, e.g. `"feature": "inline_assist"` or a
+    /// conversation id, so that completion telemetry can be broken down by it. It's never sent
+    /// to the model's API.
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Plain text, the default for every model.
+    Text,
+    /// Any valid JSON object.
+    JsonObject,
+    /// A JSON value matching the given JSON schema.
+    JsonSchema { schema: serde_json::Value },
+}
+
+impl ResponseFormat {
+    /// Returns an error if the format can't be sent as is, i.e. if its schema isn't a
+    /// JSON object.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::Text | Self::JsonObject => Ok(()),
+            Self::JsonSchema { schema } => {
+                let schema = schema
+                    .as_object()
+                    .ok_or_else(|| anyhow!("The response's JSON schema must be an object"))?;
+                if let Some(ty) = schema.get("type") {
+                    if !(ty.is_string() || ty.is_array()) {
+                        return Err(anyhow!(
+                            "The response's JSON schema has an invalid type: {ty}"
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The number of characters assumed to make up a token when truncating file context.
+const FILE_CONTEXT_CHARS_PER_TOKEN: usize = 4;
+
+/// Follows the transcript of a conversation, to ask the model to summarize it.
+const CONVERSATION_SUMMARY_PROMPT: &str = "Summarize the conversation above, so that it can be \
+    continued without it. Keep the facts, decisions, code and open questions that later \
+    messages may depend on. Respond with the summary only.";
+
+/// The user message that asks the model to continue a response cut off by the token limit.
+const CONTINUATION_PROMPT: &str =
+    "Continue exactly where you left off, without repeating anything you've already written.";
+
+/// The English names of the languages that locales ask for, by language code.
+const LANGUAGE_NAMES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("cs", "Czech"),
+    ("da", "Danish"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fi", "Finnish"),
+    ("fr", "French"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("hu", "Hungarian"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nb", "Norwegian"),
+    ("no", "Norwegian"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ro", "Romanian"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("th", "Thai"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("vi", "Vietnamese"),
+    ("zh", "Chinese"),
+];
+
+/// Whether `text` tells the model which language to respond in, like "Respond in German.",
+/// as opposed to a format, like "Respond in Markdown." Besides the languages locales are
+/// known to ask for, `language` is recognized, e.g. one the locale gave by name.
+pub fn is_language_instruction(text: &str, language: Option<&str>) -> bool {
+    let text = text.to_lowercase();
+    let names_language = |rest: &str, name: &str| {
+        rest.strip_prefix(&name.to_lowercase())
+            .map_or(false, |rest| !rest.starts_with(char::is_alphanumeric))
+    };
+    text.match_indices("respond in ").any(|(index, phrase)| {
+        let rest = &text[index + phrase.len()..];
+        LANGUAGE_NAMES
+            .iter()
+            .any(|(_, name)| names_language(rest, name))
+            || language.map_or(false, |language| names_language(rest, language))
+    })
+}
+
+/// The range of temperatures accepted by OpenAI-compatible APIs.
+const OPEN_AI_TEMPERATURE_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+/// The range of `top_p` values accepted by OpenAI-compatible APIs.
+const OPEN_AI_TOP_P_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+/// The range of presence and frequency penalties accepted by OpenAI-compatible APIs.
+const OPEN_AI_PENALTY_RANGE: RangeInclusive<f32> = -2.0..=2.0;
+/// The range of logit biases accepted by OpenAI-compatible APIs.
+const OPEN_AI_LOGIT_BIAS_RANGE: RangeInclusive<f32> = -100.0..=100.0;
+
+fn clamp_sampling_parameter(
+    name: &str,
+    value: Option<f32>,
+    range: RangeInclusive<f32>,
+) -> Option<f32> {
+    let value = value?;
+    let clamped = value.clamp(*range.start(), *range.end());
+    if clamped != value {
+        log::warn!(
+            "{name} of {value} is outside of the supported range {}..={}, clamping to {clamped}",
+            range.start(),
+            range.end()
+        );
+    }
+    Some(clamped)
 }
 
 impl LanguageModelRequest {
+    /// Returns the requested temperature, clamped to the range accepted by OpenAI-compatible APIs.
+    pub fn open_ai_temperature(&self) -> Option<f32> {
+        clamp_sampling_parameter("temperature", self.temperature, OPEN_AI_TEMPERATURE_RANGE)
+    }
+
+    /// Returns the requested `top_p`, clamped to the range accepted by OpenAI-compatible APIs.
+    pub fn open_ai_top_p(&self) -> Option<f32> {
+        clamp_sampling_parameter("top_p", self.top_p, OPEN_AI_TOP_P_RANGE)
+    }
+
+    /// Returns the requested `presence_penalty`, clamped to the range accepted by
+    /// OpenAI-compatible APIs.
+    pub fn open_ai_presence_penalty(&self) -> Option<f32> {
+        clamp_sampling_parameter(
+            "presence_penalty",
+            self.presence_penalty,
+            OPEN_AI_PENALTY_RANGE,
+        )
+    }
+
+    /// Returns the requested `frequency_penalty`, clamped to the range accepted by
+    /// OpenAI-compatible APIs.
+    pub fn open_ai_frequency_penalty(&self) -> Option<f32> {
+        clamp_sampling_parameter(
+            "frequency_penalty",
+            self.frequency_penalty,
+            OPEN_AI_PENALTY_RANGE,
+        )
+    }
+
+    /// Returns the requested `logit_bias`, with each bias clamped to the range accepted by
+    /// OpenAI-compatible APIs, or `None` if no token is biased.
+    pub fn open_ai_logit_bias(&self) -> Option<HashMap<u32, f32>> {
+        let logit_bias = self.logit_bias.as_ref().filter(|bias| !bias.is_empty())?;
+        Some(
+            logit_bias
+                .iter()
+                .filter_map(|(&token, &bias)| {
+                    let name = format!("logit_bias of token {token}");
+                    let bias =
+                        clamp_sampling_parameter(&name, Some(bias), OPEN_AI_LOGIT_BIAS_RANGE)?;
+                    Some((token, bias))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the name of the language of the request's locale, if it has one.
+    pub fn locale_language(&self) -> Option<&str> {
+        let locale = self.locale.as_deref()?.trim();
+        if locale.is_empty() {
+            return None;
+        }
+        let code = locale.split(['-', '_']).next().unwrap_or(locale);
+        let language = LANGUAGE_NAMES
+            .iter()
+            .find(|(language_code, _)| language_code.eq_ignore_ascii_case(code))
+            .map(|(_, name)| *name);
+        // Leave unknown locales, or languages given by name, for the model to interpret.
+        Some(language.unwrap_or(locale))
+    }
+
+    /// Returns an instruction to respond in the language of the request's locale, if it has one.
+    pub fn locale_instruction(&self) -> Option<String> {
+        Some(format!("Respond in {}.", self.locale_language()?))
+    }
+
+    /// Returns an error if the request defines tools, for models that can't call them.
+    pub fn reject_tools(&self, model_name: &str) -> Result<()> {
+        if self.tools.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{model_name} does not support tool calls"))
+        }
+    }
+
+    /// Returns an error if the request constrains the format of the response, for models
+    /// that can't enforce it.
+    pub fn reject_response_format(&self, model_name: &str) -> Result<()> {
+        match self.response_format {
+            None | Some(ResponseFormat::Text) => Ok(()),
+            Some(_) => Err(anyhow!("{model_name} does not support structured output")),
+        }
+    }
+
+    /// Appends a user message containing `contents`, the text of the file at `path`, with its
+    /// lines numbered. They're fenced like the output of the `/file` command, under a header
+    /// naming the file's extension and path and the range of lines included, e.g.
+    /// `rs src/main.rs:1-120`.
+    ///
+    /// Files that would take more than `max_tokens` tokens, estimated at four characters per
+    /// token, are truncated after the last line that fits, and the omitted lines are noted
+    /// after the fence.
+    pub fn add_file_context(&mut self, path: &Path, contents: &str, max_tokens: usize) {
+        self.messages.push(LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text(format_file_context(
+                path, contents, max_tokens,
+            ))],
+            cache: false,
+        });
+    }
+
+    /// Returns the request to send to continue `prior_output`, the response to this request,
+    /// after it was cut off by the token limit.
+    ///
+    /// The response is added as an assistant message, followed by a user message asking the
+    /// model to continue, since most providers reject requests that don't end with a user
+    /// message. If the response is empty there's nothing to continue from, and the request is
+    /// returned unchanged, because some providers also reject empty messages.
+    pub fn into_continuation(mut self, prior_output: &str) -> Self {
+        if prior_output.is_empty() {
+            return self;
+        }
+
+        self.messages.push(LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: vec![MessageContent::Text(prior_output.to_string())],
+            cache: false,
+        });
+        self.messages.push(LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text(CONTINUATION_PROMPT.to_string())],
+            cache: false,
+        });
+        self
+    }
+
+    /// Returns the request that asks the model to summarize the conversation before its last
+    /// `recent_turn_count` turns, or `None` if there's nothing before them to summarize. A turn
+    /// starts with a user message, other than one that returns the results of tool uses.
+    ///
+    /// The messages are sent as a transcript in a single user message, so that tool uses
+    /// don't need their tools to be defined. System messages are kept rather than summarized.
+    pub fn conversation_summary_request(&self, recent_turn_count: usize) -> Option<Self> {
+        let older_messages = &self.messages[..self.recent_turns_start(recent_turn_count)];
+        let mut transcript = String::new();
+        for message in older_messages {
+            let role = match message.role {
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+                Role::System => continue,
+            };
+            write!(transcript, "{role}: {}\n\n", message.string_contents()).unwrap();
+        }
+        if transcript.is_empty() {
+            return None;
+        }
+
+        transcript.push_str(CONVERSATION_SUMMARY_PROMPT);
+        Some(Self {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text(transcript)],
+                cache: false,
+            }],
+            locale: self.locale.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Replaces the messages that [`Self::conversation_summary_request`] summarized with
+    /// `summary`, in a system message following the conversation's other system messages.
+    pub fn into_compacted(mut self, recent_turn_count: usize, summary: &str) -> Self {
+        let recent_messages = self
+            .messages
+            .split_off(self.recent_turns_start(recent_turn_count));
+        self.messages.retain(|message| message.role == Role::System);
+        self.messages.push(LanguageModelRequestMessage {
+            role: Role::System,
+            content: vec![MessageContent::Text(format!(
+                "A summary of the earlier conversation:\n\n{summary}"
+            ))],
+            cache: false,
+        });
+        self.messages.extend(recent_messages);
+        self
+    }
+
+    /// Returns the index of the first message of the last `turn_count` turns.
+    fn recent_turns_start(&self, turn_count: usize) -> usize {
+        if turn_count == 0 {
+            return self.messages.len();
+        }
+        self.messages
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, message)| {
+                message.role == Role::User
+                    && !message
+                        .content
+                        .iter()
+                        .any(|content| matches!(content, MessageContent::ToolResult(_)))
+            })
+            .nth(turn_count - 1)
+            .map_or(0, |(ix, _)| ix)
+    }
+
+    /// Returns the requested `max_tokens`, saturated to the `u32` that provider APIs accept.
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.max_tokens
+            .map(|max_tokens| u32::try_from(max_tokens).unwrap_or(u32::MAX))
+    }
+
     pub fn into_open_ai(self, model: String, max_output_tokens: Option<u32>) -> open_ai::Request {
         let stream = !model.starts_with("o1-");
+        let temperature = self.open_ai_temperature().unwrap_or(1.0);
+        let top_p = self.open_ai_top_p();
+        let presence_penalty = self.open_ai_presence_penalty();
+        let frequency_penalty = self.open_ai_frequency_penalty();
+        let logit_bias = self
+            .open_ai_logit_bias()
+            .map(|logit_bias| logit_bias.into_iter().collect());
+        let max_tokens = match (self.max_output_tokens(), max_output_tokens) {
+            (Some(max_tokens), Some(max_output_tokens)) => Some(max_tokens.min(max_output_tokens)),
+            (max_tokens, max_output_tokens) => max_tokens.or(max_output_tokens),
+        };
+
+        let mut messages = Vec::new();
+        for msg in self.messages {
+            match msg.role {
+                Role::User => {
+                    // OpenAI expects each tool result in its own message, following the
+                    // assistant message that requested it.
+                    let mut has_tool_results = false;
+                    for content in &msg.content {
+                        if let MessageContent::ToolResult(tool_result) = content {
+                            has_tool_results = true;
+                            messages.push(open_ai::RequestMessage::Tool {
+                                content: tool_result.content.clone(),
+                                tool_call_id: tool_result.tool_use_id.clone(),
+                            });
+                        }
+                    }
+                    let content = msg.text_contents();
+                    if !has_tool_results || !content.is_empty() {
+                        messages.push(open_ai::RequestMessage::User { content });
+                    }
+                }
+                Role::Assistant => {
+                    let tool_calls = msg
+                        .content
+                        .iter()
+                        .filter_map(|content| match content {
+                            MessageContent::ToolUse(tool_use) => Some(open_ai::ToolCall {
+                                id: tool_use.id.clone(),
+                                content: open_ai::ToolCallContent::Function {
+                                    function: open_ai::FunctionContent {
+                                        name: tool_use.name.clone(),
+                                        arguments: tool_use.input.to_string(),
+                                    },
+                                },
+                            }),
+                            _ => None,
+                        })
+                        .collect();
+                    messages.push(open_ai::RequestMessage::Assistant {
+                        content: Some(msg.text_contents()),
+                        tool_calls,
+                    });
+                }
+                Role::System => messages.push(open_ai::RequestMessage::System {
+                    content: msg.string_contents(),
+                }),
+            }
+        }
+
         open_ai::Request {
             model,
-            messages: self
-                .messages
+            messages,
+            stream,
+            stop: self.stop,
+            temperature,
+            top_p,
+            presence_penalty,
+            frequency_penalty,
+            logit_bias,
+            seed: self.seed,
+            n: self.n,
+            max_tokens,
+            tools: self
+                .tools
                 .into_iter()
-                .map(|msg| match msg.role {
-                    Role::User => open_ai::RequestMessage::User {
-                        content: msg.string_contents(),
-                    },
-                    Role::Assistant => open_ai::RequestMessage::Assistant {
-                        content: Some(msg.string_contents()),
-                        tool_calls: Vec::new(),
-                    },
-                    Role::System => open_ai::RequestMessage::System {
-                        content: msg.string_contents(),
+                .map(|tool| open_ai::ToolDefinition::Function {
+                    function: open_ai::FunctionDefinition {
+                        name: tool.name,
+                        description: Some(tool.description),
+                        parameters: Some(tool.input_schema),
                     },
                 })
                 .collect(),
-            stream,
-            stop: self.stop,
-            temperature: self.temperature.unwrap_or(1.0),
-            max_tokens: max_output_tokens,
-            tools: Vec::new(),
             tool_choice: None,
         }
     }
 
     pub fn into_google(self, model: String) -> google_ai::GenerateContentRequest {
+        // Google AI doesn't have a system role, so system messages are sent as its system instruction.
+        let mut system_instruction = String::new();
+        let mut contents = Vec::new();
+        for msg in self.messages {
+            let Ok(role) = google_ai::Role::try_from(msg.role) else {
+                if !system_instruction.is_empty() {
+                    system_instruction.push_str("\n\n");
+                }
+                system_instruction.push_str(&msg.string_contents());
+                continue;
+            };
+            contents.push(google_ai::Content {
+                parts: vec![google_ai::Part::TextPart(google_ai::TextPart {
+                    text: msg.string_contents(),
+                })],
+                role,
+            });
+        }
+
         google_ai::GenerateContentRequest {
             model,
-            contents: self
-                .messages
-                .into_iter()
-                .map(|msg| google_ai::Content {
+            contents,
+            system_instruction: (!system_instruction.is_empty()).then(|| {
+                google_ai::SystemInstruction {
                     parts: vec![google_ai::Part::TextPart(google_ai::TextPart {
-                        text: msg.string_contents(),
+                        text: system_instruction,
                     })],
-                    role: match msg.role {
-                        Role::User => google_ai::Role::User,
-                        Role::Assistant => google_ai::Role::Model,
-                        Role::System => google_ai::Role::User, // Google AI doesn't have a system role
-                    },
-                })
-                .collect(),
+                }
+            }),
             generation_config: Some(google_ai::GenerationConfig {
                 candidate_count: Some(1),
                 stop_sequences: Some(self.stop),
@@ -304,16 +771,21 @@ impl LanguageModelRequest {
         default_temperature: f32,
         max_output_tokens: u32,
     ) -> anthropic::Request {
+        let max_tokens = self
+            .max_output_tokens()
+            .map_or(max_output_tokens, |max_tokens| {
+                max_tokens.min(max_output_tokens)
+            });
         let mut new_messages: Vec<anthropic::Message> = Vec::new();
-        let mut system_message = String::new();
+        let mut system_messages = Vec::new();
 
         for message in self.messages {
             if message.contents_empty() {
                 continue;
             }
 
-            match message.role {
-                Role::User | Role::Assistant => {
+            match anthropic::Role::try_from(message.role) {
+                Ok(anthropic_role) => {
                     let cache_control = if message.cache {
                         Some(anthropic::CacheControl {
                             cache_type: anthropic::CacheControlType::Ephemeral,
@@ -363,11 +835,6 @@ impl LanguageModelRequest {
                             }
                         })
                         .collect();
-                    let anthropic_role = match message.role {
-                        Role::User => anthropic::Role::User,
-                        Role::Assistant => anthropic::Role::Assistant,
-                        Role::System => unreachable!("System role should never occur here"),
-                    };
                     if let Some(last_message) = new_messages.last_mut() {
                         if last_message.role == anthropic_role {
                             last_message.content.extend(anthropic_message_content);
@@ -379,20 +846,41 @@ impl LanguageModelRequest {
                         content: anthropic_message_content,
                     });
                 }
-                Role::System => {
-                    if !system_message.is_empty() {
-                        system_message.push_str("\n\n");
-                    }
-                    system_message.push_str(&message.string_contents());
+                Err(NoSystemRoleError) => {
+                    system_messages.push((message.string_contents(), message.cache));
                 }
             }
         }
 
+        // Cache breakpoints can only be set on blocks, so the system prompt is only split into
+        // blocks when one of its messages should be cached.
+        let system = if system_messages.is_empty() {
+            None
+        } else if system_messages.iter().any(|(_, cache)| *cache) {
+            Some(anthropic::SystemPrompt::Content(
+                system_messages
+                    .into_iter()
+                    .map(|(text, cache)| anthropic::RequestContent::Text {
+                        text,
+                        cache_control: cache.then_some(anthropic::CacheControl {
+                            cache_type: anthropic::CacheControlType::Ephemeral,
+                        }),
+                    })
+                    .collect(),
+            ))
+        } else {
+            let texts = system_messages
+                .into_iter()
+                .map(|(text, _)| text)
+                .collect::<Vec<_>>();
+            Some(anthropic::SystemPrompt::Text(texts.join("\n\n")))
+        };
+
         anthropic::Request {
             model,
             messages: new_messages,
-            max_tokens: max_output_tokens,
-            system: Some(system_message),
+            max_tokens,
+            system,
             tools: self
                 .tools
                 .into_iter()
@@ -412,8 +900,302 @@ impl LanguageModelRequest {
     }
 }
 
+/// Assembles a [`LanguageModelRequest`] one message at a time, e.g.
+/// `LanguageModelRequestBuilder::new().system("Be brief.").user("Hi").build()`.
+#[derive(Clone, Debug, Default)]
+pub struct LanguageModelRequestBuilder {
+    request: LanguageModelRequest,
+}
+
+impl LanguageModelRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn system(self, text: impl Into<String>) -> Self {
+        self.message(Role::System, text.into())
+    }
+
+    pub fn user(self, text: impl Into<String>) -> Self {
+        self.message(Role::User, text.into())
+    }
+
+    pub fn assistant(self, text: impl Into<String>) -> Self {
+        self.message(Role::Assistant, text.into())
+    }
+
+    fn message(mut self, role: Role, text: String) -> Self {
+        self.request.messages.push(LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text)],
+            cache: false,
+        });
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.request.temperature = Some(temperature);
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.request.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Returns the request, warning if it doesn't end with a user message, since most
+    /// providers reject those.
+    pub fn build(self) -> LanguageModelRequest {
+        match self.request.messages.last() {
+            Some(message) if message.role == Role::User => {}
+            Some(message) => log::warn!(
+                "Building a language model request whose last message is from the {:?} role",
+                message.role
+            ),
+            None => log::warn!("Building a language model request without any messages"),
+        }
+        self.request
+    }
+}
+
+fn format_file_context(path: &Path, contents: &str, max_tokens: usize) -> String {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let number_width = lines.len().to_string().len();
+    let max_chars = max_tokens.saturating_mul(FILE_CONTEXT_CHARS_PER_TOKEN);
+
+    let mut numbered_lines = String::new();
+    let mut included_lines = 0;
+    for (ix, line) in lines.iter().enumerate() {
+        let numbered_line = format!("{:>number_width$} {line}\n", ix + 1);
+        if numbered_lines.len() + numbered_line.len() > max_chars {
+            break;
+        }
+        numbered_lines.push_str(&numbered_line);
+        included_lines += 1;
+    }
+
+    let mut text = String::from("```");
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        write!(text, "{extension} ").unwrap();
+    }
+    write!(text, "{}", path.display()).unwrap();
+    if included_lines > 0 {
+        write!(text, ":1-{included_lines}").unwrap();
+    }
+    text.push('\n');
+    text.push_str(&numbered_lines);
+    text.push_str("```\n");
+    if included_lines < lines.len() {
+        writeln!(
+            text,
+            "Lines {}-{} of {} were omitted to fit the context window.",
+            included_lines + 1,
+            lines.len(),
+            path.display()
+        )
+        .unwrap();
+    }
+    text
+}
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct LanguageModelResponseMessage {
     pub role: Option<Role>,
     pub content: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_compaction() {
+        let request = LanguageModelRequestBuilder::new()
+            .system("Be brief.")
+            .user("What's 2 + 2?")
+            .assistant("4")
+            .user("And times 3?")
+            .assistant("12")
+            .user("Minus 5?")
+            .build();
+
+        let summary_request = request.conversation_summary_request(2).unwrap();
+        assert_eq!(summary_request.messages.len(), 1);
+        assert_eq!(
+            summary_request.messages[0].string_contents(),
+            format!("User: What's 2 + 2?\n\nAssistant: 4\n\n{CONVERSATION_SUMMARY_PROMPT}")
+        );
+
+        let compacted = request
+            .clone()
+            .into_compacted(2, "The user asked for 2 + 2, which is 4.");
+        assert_eq!(
+            compacted
+                .messages
+                .iter()
+                .map(|message| (message.role, message.string_contents()))
+                .collect::<Vec<_>>(),
+            [
+                (Role::System, "Be brief.".to_string()),
+                (
+                    Role::System,
+                    "A summary of the earlier conversation:\n\n\
+                     The user asked for 2 + 2, which is 4."
+                        .to_string()
+                ),
+                (Role::User, "And times 3?".to_string()),
+                (Role::Assistant, "12".to_string()),
+                (Role::User, "Minus 5?".to_string()),
+            ]
+        );
+
+        // There's nothing to summarize when every turn is kept.
+        assert_eq!(request.conversation_summary_request(3), None);
+        assert_eq!(request.conversation_summary_request(4), None);
+    }
+
+    #[test]
+    fn test_into_continuation() {
+        let request = LanguageModelRequestBuilder::new()
+            .system("Be brief.")
+            .user("Count to ten.")
+            .build();
+
+        let continuation = request.clone().into_continuation("1, 2, 3,");
+        assert_eq!(
+            continuation
+                .messages
+                .iter()
+                .map(|message| (message.role, message.string_contents()))
+                .collect::<Vec<_>>(),
+            [
+                (Role::System, "Be brief.".to_string()),
+                (Role::User, "Count to ten.".to_string()),
+                (Role::Assistant, "1, 2, 3,".to_string()),
+                (Role::User, CONTINUATION_PROMPT.to_string()),
+            ]
+        );
+
+        assert_eq!(request.clone().into_continuation(""), request);
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let request = LanguageModelRequestBuilder::new()
+            .system("Be brief.")
+            .user("Hi")
+            .assistant("Hello!")
+            .user("How are you?")
+            .temperature(0.5)
+            .max_tokens(100)
+            .build();
+
+        assert_eq!(
+            request
+                .messages
+                .iter()
+                .map(|message| (message.role, message.string_contents()))
+                .collect::<Vec<_>>(),
+            [
+                (Role::System, "Be brief.".to_string()),
+                (Role::User, "Hi".to_string()),
+                (Role::Assistant, "Hello!".to_string()),
+                (Role::User, "How are you?".to_string()),
+            ]
+        );
+        assert_eq!(request.temperature, Some(0.5));
+        assert_eq!(request.max_tokens, Some(100));
+    }
+
+    #[test]
+    fn test_locale_instruction() {
+        let instruction = |locale: Option<&str>| {
+            LanguageModelRequest {
+                locale: locale.map(Into::into),
+                ..Default::default()
+            }
+            .locale_instruction()
+        };
+        assert_eq!(instruction(None), None);
+        assert_eq!(instruction(Some(" ")), None);
+        assert_eq!(instruction(Some("fr")), Some("Respond in French.".into()));
+        assert_eq!(
+            instruction(Some("pt-BR")),
+            Some("Respond in Portuguese.".into())
+        );
+        assert_eq!(
+            instruction(Some("zh_TW")),
+            Some("Respond in Chinese.".into())
+        );
+        assert_eq!(
+            instruction(Some("Esperanto")),
+            Some("Respond in Esperanto.".into())
+        );
+    }
+
+    #[test]
+    fn test_is_language_instruction() {
+        assert!(is_language_instruction("Always respond in German.", None));
+        assert!(is_language_instruction("RESPOND IN JAPANESE", None));
+        assert!(is_language_instruction(
+            "Respond in Esperanto.",
+            Some("Esperanto")
+        ));
+        assert!(!is_language_instruction("Respond in Markdown.", None));
+        assert!(!is_language_instruction("Respond in bullet points.", None));
+        assert!(!is_language_instruction("Respond in Englishness.", None));
+    }
+
+    #[test]
+    fn test_add_file_context() {
+        let mut request = LanguageModelRequest::default();
+        let contents = (1..=10)
+            .map(|line| format!("line {line}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        request.add_file_context(Path::new("src/lib.rs"), &contents, 1000);
+
+        assert_eq!(request.messages.len(), 1);
+        assert_eq!(request.messages[0].role, Role::User);
+        assert_eq!(
+            request.messages[0].string_contents(),
+            concat!(
+                "```rs src/lib.rs:1-10\n",
+                " 1 line 1\n",
+                " 2 line 2\n",
+                " 3 line 3\n",
+                " 4 line 4\n",
+                " 5 line 5\n",
+                " 6 line 6\n",
+                " 7 line 7\n",
+                " 8 line 8\n",
+                " 9 line 9\n",
+                "10 line 10\n",
+                "```\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_file_context_is_truncated_to_fit() {
+        let contents = "first line\nsecond line\nthird line\n";
+        assert_eq!(
+            format_file_context(Path::new("notes"), contents, 7),
+            concat!(
+                "```notes:1-2\n",
+                "1 first line\n",
+                "2 second line\n",
+                "```\n",
+                "Lines 3-3 of notes were omitted to fit the context window.\n",
+            )
+        );
+        assert_eq!(
+            format_file_context(Path::new("notes"), contents, 0),
+            concat!(
+                "```notes\n",
+                "```\n",
+                "Lines 1-3 of notes were omitted to fit the context window.\n",
+            )
+        );
+    }
+}
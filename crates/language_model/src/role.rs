@@ -65,3 +65,50 @@ impl From<Role> for open_ai::Role {
         }
     }
 }
+
+impl From<Role> for copilot::copilot_chat::Role {
+    fn from(val: Role) -> Self {
+        match val {
+            Role::User => copilot::copilot_chat::Role::User,
+            Role::Assistant => copilot::copilot_chat::Role::Assistant,
+            Role::System => copilot::copilot_chat::Role::System,
+        }
+    }
+}
+
+/// The error converting [`Role::System`] to the role of an API that has none, and takes the
+/// system prompt separately from the messages instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoSystemRoleError;
+
+impl Display for NoSystemRoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the API has no system role")
+    }
+}
+
+impl std::error::Error for NoSystemRoleError {}
+
+impl TryFrom<Role> for google_ai::Role {
+    type Error = NoSystemRoleError;
+
+    fn try_from(val: Role) -> Result<Self, Self::Error> {
+        match val {
+            Role::User => Ok(google_ai::Role::User),
+            Role::Assistant => Ok(google_ai::Role::Model),
+            Role::System => Err(NoSystemRoleError),
+        }
+    }
+}
+
+impl TryFrom<Role> for anthropic::Role {
+    type Error = NoSystemRoleError;
+
+    fn try_from(val: Role) -> Result<Self, Self::Error> {
+        match val {
+            Role::User => Ok(anthropic::Role::User),
+            Role::Assistant => Ok(anthropic::Role::Assistant),
+            Role::System => Err(NoSystemRoleError),
+        }
+    }
+}
@@ -12,10 +12,11 @@ use crate::{
         self,
         anthropic::AnthropicSettings,
         cloud::{self, ZedDotDevSettings},
-        copilot_chat::CopilotChatSettings,
+        copilot_chat::{CopilotChatSettings, SystemMessagePolicy},
         google::GoogleSettings,
         ollama::OllamaSettings,
         open_ai::OpenAiSettings,
+        openai_compatible::OpenAiCompatibleSettings,
     },
     LanguageModelCacheConfiguration,
 };
@@ -58,9 +59,12 @@ pub struct AllLanguageModelSettings {
     pub anthropic: AnthropicSettings,
     pub ollama: OllamaSettings,
     pub openai: OpenAiSettings,
+    pub openai_compatible: OpenAiCompatibleSettings,
     pub zed_dot_dev: ZedDotDevSettings,
     pub google: GoogleSettings,
     pub copilot_chat: CopilotChatSettings,
+    /// The IDs of providers whose models can't be selected, e.g. `openai`.
+    pub disabled_providers: Vec<String>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -68,10 +72,13 @@ pub struct AllLanguageModelSettingsContent {
     pub anthropic: Option<AnthropicSettingsContent>,
     pub ollama: Option<OllamaSettingsContent>,
     pub openai: Option<OpenAiSettingsContent>,
+    pub openai_compatible: Option<OpenAiCompatibleSettingsContent>,
     #[serde(rename = "zed.dev")]
     pub zed_dot_dev: Option<ZedDotDevSettingsContent>,
     pub google: Option<GoogleSettingsContent>,
     pub copilot_chat: Option<CopilotChatSettingsContent>,
+    /// The IDs of providers whose models can't be selected, e.g. `openai`.
+    pub disabled_providers: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -223,6 +230,13 @@ pub struct OpenAiSettingsContentV1 {
     pub available_models: Option<Vec<provider::open_ai::AvailableModel>>,
 }
 
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct OpenAiCompatibleSettingsContent {
+    pub api_url: Option<String>,
+    pub low_speed_timeout_in_seconds: Option<u64>,
+    pub available_models: Option<Vec<provider::openai_compatible::AvailableModel>>,
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct GoogleSettingsContent {
     pub api_url: Option<String>,
@@ -238,7 +252,27 @@ pub struct ZedDotDevSettingsContent {
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct CopilotChatSettingsContent {
+    api_url: Option<String>,
+    proxy: Option<String>,
     low_speed_timeout_in_seconds: Option<u64>,
+    completion_timeout_in_seconds: Option<u64>,
+    coalesce_chunks_in_milliseconds: Option<u64>,
+    min_first_chunk: Option<usize>,
+    first_chunk_timeout_in_milliseconds: Option<u64>,
+    max_retries: Option<usize>,
+    retry_base_delay_in_milliseconds: Option<u64>,
+    default_system_prompt: Option<String>,
+    default_model: Option<String>,
+    fallback_model: Option<String>,
+    max_concurrent_requests: Option<usize>,
+    default_max_tokens: Option<usize>,
+    truncate_prompt: Option<bool>,
+    context_warning_threshold: Option<f32>,
+    retry_on_content_filter: Option<bool>,
+    strict_validation: Option<bool>,
+    system_message_policy: Option<SystemMessagePolicy>,
+    log_requests: Option<bool>,
+    log_prompt_content: Option<bool>,
 }
 
 impl settings::Settings for AllLanguageModelSettings {
@@ -258,6 +292,11 @@ impl settings::Settings for AllLanguageModelSettings {
         let mut settings = AllLanguageModelSettings::default();
 
         for value in sources.defaults_and_customizations() {
+            merge(
+                &mut settings.disabled_providers,
+                value.disabled_providers.clone(),
+            );
+
             // Anthropic
             let (anthropic, upgraded) = match value.anthropic.clone().map(|s| s.upgrade()) {
                 Some((content, upgraded)) => (Some(content), upgraded),
@@ -329,6 +368,23 @@ impl settings::Settings for AllLanguageModelSettings {
                 openai.as_ref().and_then(|s| s.available_models.clone()),
             );
 
+            // OpenAI compatible
+            let openai_compatible = value.openai_compatible.as_ref();
+            merge(
+                &mut settings.openai_compatible.api_url,
+                openai_compatible.and_then(|s| s.api_url.clone()),
+            );
+            if let Some(low_speed_timeout_in_seconds) =
+                openai_compatible.and_then(|s| s.low_speed_timeout_in_seconds)
+            {
+                settings.openai_compatible.low_speed_timeout =
+                    Some(Duration::from_secs(low_speed_timeout_in_seconds));
+            }
+            merge(
+                &mut settings.openai_compatible.available_models,
+                openai_compatible.and_then(|s| s.available_models.clone()),
+            );
+
             merge(
                 &mut settings.zed_dot_dev.available_models,
                 value
@@ -365,14 +421,139 @@ impl settings::Settings for AllLanguageModelSettings {
                     .and_then(|s| s.available_models.clone()),
             );
 
+            if let Some(api_url) = value.copilot_chat.as_ref().and_then(|s| s.api_url.clone()) {
+                settings.copilot_chat.api_url = Some(api_url);
+            }
+            if let Some(proxy) = value.copilot_chat.as_ref().and_then(|s| s.proxy.clone()) {
+                settings.copilot_chat.proxy = Some(proxy);
+            }
             if let Some(low_speed_timeout) = value
                 .copilot_chat
                 .as_ref()
                 .and_then(|s| s.low_speed_timeout_in_seconds)
             {
+                // A timeout of 0 disables the default one.
                 settings.copilot_chat.low_speed_timeout =
-                    Some(Duration::from_secs(low_speed_timeout));
+                    (low_speed_timeout > 0).then(|| Duration::from_secs(low_speed_timeout));
+            }
+            if let Some(completion_timeout) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.completion_timeout_in_seconds)
+            {
+                settings.copilot_chat.completion_timeout =
+                    Some(Duration::from_secs(completion_timeout));
+            }
+            if let Some(coalesce_chunks) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.coalesce_chunks_in_milliseconds)
+            {
+                settings.copilot_chat.coalesce_chunks =
+                    Some(Duration::from_millis(coalesce_chunks));
             }
+            if let Some(min_first_chunk) =
+                value.copilot_chat.as_ref().and_then(|s| s.min_first_chunk)
+            {
+                settings.copilot_chat.min_first_chunk = Some(min_first_chunk);
+            }
+            if let Some(first_chunk_timeout) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.first_chunk_timeout_in_milliseconds)
+            {
+                settings.copilot_chat.first_chunk_timeout =
+                    Duration::from_millis(first_chunk_timeout);
+            }
+            merge(
+                &mut settings.copilot_chat.max_retries,
+                value.copilot_chat.as_ref().and_then(|s| s.max_retries),
+            );
+            if let Some(retry_base_delay) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.retry_base_delay_in_milliseconds)
+            {
+                settings.copilot_chat.retry_base_delay = Duration::from_millis(retry_base_delay);
+            }
+            if let Some(default_system_prompt) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.default_system_prompt.clone())
+            {
+                settings.copilot_chat.default_system_prompt = Some(default_system_prompt);
+            }
+            if let Some(default_model) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.default_model.clone())
+            {
+                settings.copilot_chat.default_model = Some(default_model);
+            }
+            if let Some(fallback_model) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.fallback_model.clone())
+            {
+                settings.copilot_chat.fallback_model = Some(fallback_model);
+            }
+            merge(
+                &mut settings.copilot_chat.max_concurrent_requests,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.max_concurrent_requests),
+            );
+            if let Some(default_max_tokens) = value
+                .copilot_chat
+                .as_ref()
+                .and_then(|s| s.default_max_tokens)
+            {
+                settings.copilot_chat.default_max_tokens = Some(default_max_tokens);
+            }
+            merge(
+                &mut settings.copilot_chat.truncate_prompt,
+                value.copilot_chat.as_ref().and_then(|s| s.truncate_prompt),
+            );
+            merge(
+                &mut settings.copilot_chat.context_warning_threshold,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.context_warning_threshold),
+            );
+            merge(
+                &mut settings.copilot_chat.retry_on_content_filter,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.retry_on_content_filter),
+            );
+            merge(
+                &mut settings.copilot_chat.strict_validation,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.strict_validation),
+            );
+            merge(
+                &mut settings.copilot_chat.system_message_policy,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.system_message_policy),
+            );
+            merge(
+                &mut settings.copilot_chat.log_requests,
+                value.copilot_chat.as_ref().and_then(|s| s.log_requests),
+            );
+            merge(
+                &mut settings.copilot_chat.log_prompt_content,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.log_prompt_content),
+            );
         }
 
         Ok(settings)
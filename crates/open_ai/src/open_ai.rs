@@ -10,6 +10,7 @@ use http_client::{AsyncBody, HttpClient, HttpRequestExt, Method, Request as Http
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     future::{self, Future},
     pin::Pin,
@@ -152,6 +153,34 @@ impl Model {
             _ => None,
         }
     }
+
+    /// The list price of a million prompt tokens in US dollars, if known.
+    pub fn input_price_per_million(&self) -> Option<f64> {
+        match self {
+            Self::ThreePointFiveTurbo => Some(0.5),
+            Self::Four => Some(30.0),
+            Self::FourTurbo => Some(10.0),
+            Self::FourOmni => Some(2.5),
+            Self::FourOmniMini => Some(0.15),
+            Self::O1Preview => Some(15.0),
+            Self::O1Mini => Some(3.0),
+            Self::Custom { .. } => None,
+        }
+    }
+
+    /// The list price of a million completion tokens in US dollars, if known.
+    pub fn output_price_per_million(&self) -> Option<f64> {
+        match self {
+            Self::ThreePointFiveTurbo => Some(1.5),
+            Self::Four => Some(60.0),
+            Self::FourTurbo => Some(30.0),
+            Self::FourOmni => Some(10.0),
+            Self::FourOmniMini => Some(0.6),
+            Self::O1Preview => Some(60.0),
+            Self::O1Mini => Some(12.0),
+            Self::Custom { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -165,6 +194,18 @@ pub struct Request {
     pub stop: Vec<String>,
     pub temperature: f32,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tools: Vec<ToolDefinition>,
@@ -478,6 +519,15 @@ pub enum OpenAiEmbeddingModel {
     TextEmbedding3Large,
 }
 
+impl OpenAiEmbeddingModel {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::TextEmbedding3Small => "text-embedding-3-small",
+            Self::TextEmbedding3Large => "text-embedding-3-large",
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct OpenAiEmbeddingRequest<'a> {
     model: OpenAiEmbeddingModel,
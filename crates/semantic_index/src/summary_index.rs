@@ -563,6 +563,18 @@ impl SummaryIndex {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            seed: None,
+            n: None,
+            max_tokens: None,
+            reasoning_effort: None,
+            session_id: None,
+            response_format: None,
+            locale: None,
+            metadata: Default::default(),
         };
 
         let code_len = code.len();
@@ -3,7 +3,7 @@
 use language::LanguageName;
 use semantic_version::SemanticVersion;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, sync::Arc, time::Duration};
+use std::{collections::HashMap, fmt::Display, sync::Arc, time::Duration};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EventRequestBody {
@@ -87,6 +87,35 @@ impl Display for AssistantPhase {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionFailureReason {
+    Auth,
+    RateLimit,
+    Timeout,
+    Network,
+    ApiError,
+    /// The completion was cancelled before it finished, e.g. by the user.
+    Cancelled,
+}
+
+impl Display for CompletionFailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Auth => "auth",
+                Self::RateLimit => "rate_limit",
+                Self::Timeout => "timeout",
+                Self::Network => "network",
+                Self::ApiError => "api_error",
+                Self::Cancelled => "cancelled",
+            }
+        )
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Event {
@@ -102,6 +131,7 @@ pub enum Event {
     Edit(EditEvent),
     Action(ActionEvent),
     Repl(ReplEvent),
+    Completion(CompletionEvent),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -153,6 +183,24 @@ pub struct AssistantEvent {
     pub language_name: Option<LanguageName>,
 }
 
+/// Performance of a single language model completion. Never includes prompt or response content.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompletionEvent {
+    /// Name of the AI model used (gpt-4o, claude-3-5-sonnet, etc)
+    pub model: String,
+    pub model_provider: String,
+    /// Time from sending the request until the first text arrived
+    pub time_to_first_token: Option<Duration>,
+    /// Time from sending the request until the completion finished or failed
+    pub duration: Duration,
+    /// Number of tokens generated, when the provider reported it
+    pub completion_tokens: Option<usize>,
+    pub failure_reason: Option<CompletionFailureReason>,
+    /// The request's metadata, e.g. the feature that requested the completion.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CpuEvent {
     pub usage_as_percentage: f32,